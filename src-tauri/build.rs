@@ -1,3 +1,47 @@
+use std::process::Command;
+
 fn main() {
+    // For get_version_info, both left unset when they can't be found
+    watch_git_head();
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=KONNYAKU_GIT_COMMIT={}", commit.trim());
+    }
+    if let Some(version) = locked_version("llama-cpp-2") {
+        println!("cargo:rustc-env=KONNYAKU_LLAMA_CPP_VERSION={}", version);
+    }
+
     tauri_build::build()
 }
+
+/// Rerun when the checked out commit changes
+///
+/// HEAD only changes with the branch, a commit moves the branch's ref
+/// instead, which lives in its own file or in packed-refs.
+fn watch_git_head() {
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    let head = std::fs::read_to_string("../.git/HEAD").unwrap_or_default();
+    if let Some(reference) = head.trim().strip_prefix("ref: ") {
+        // Watched even while missing, a packed ref gets its file back on commit
+        println!("cargo:rerun-if-changed=../.git/{}", reference);
+    }
+    if std::path::Path::new("../.git/packed-refs").exists() {
+        println!("cargo:rerun-if-changed=../.git/packed-refs");
+    }
+}
+
+/// The version of `package` in Cargo.lock
+fn locked_version(package: &str) -> Option<String> {
+    let lock = std::fs::read_to_string("Cargo.lock").ok()?;
+    let name = format!("name = \"{}\"", package);
+    let mut lines = lock.lines().skip_while(|line| line.trim() != name);
+    lines.next()?;
+    let version = lines.next()?.trim().strip_prefix("version = ")?;
+    Some(version.trim_matches('"').to_string())
+}
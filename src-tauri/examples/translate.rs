@@ -0,0 +1,36 @@
+//! Translate text from the command line using the library API directly
+//!
+//! ```sh
+//! cargo run --example translate -- en-ja "Hello, world"
+//! echo "こんにちは" | cargo run --example translate -- ja-en
+//! ```
+
+use anyhow::{bail, Context, Result};
+use konnyaku_lib::translation::{LanguagePair, TranslateOptions, TranslationService};
+use std::io::Read;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let direction = match args.next().map(|code| code.parse::<LanguagePair>()) {
+        Some(Ok(direction)) => direction,
+        _ => bail!("Usage: translate <en-ja|ja-en> [text]  (reads stdin if no text is given)"),
+    };
+
+    let mut text = args.collect::<Vec<_>>().join(" ");
+    if text.is_empty() {
+        std::io::stdin()
+            .read_to_string(&mut text)
+            .context("Failed to read stdin")?;
+    }
+
+    let service = TranslationService::new()?;
+    service.ensure_model_downloaded().await?;
+
+    let translation = service
+        .translate(&text, direction, &TranslateOptions::default())
+        .await?;
+    println!("{}", translation.text);
+
+    Ok(())
+}
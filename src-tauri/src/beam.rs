@@ -0,0 +1,238 @@
+// Beam search over the model's own context.
+//
+// Instead of committing to the most likely token at each step, the
+// `width` most likely continuations found so far are all kept going, each in
+// its own sequence of one llama context. A step proposes each beam's `width`
+// best next tokens, keeps the `width` best of them overall and decodes them
+// in a single batch, so it costs about one decode per beam. A chosen token's
+// sequence starts as a full copy of its parent's, which works for models
+// with a recurrent state too, since no beam ever has to drop tokens.
+//
+// Sequences are double buffered: the beams of one step live in seqs
+// 0..width and their children in width..2 * width, or the other way round.
+
+use crate::speculative;
+use anyhow::{Context, Result};
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use llama_cpp_2::llama_batch::LlamaBatch;
+use llama_cpp_2::model::{LlamaModel, Special};
+use llama_cpp_2::token::LlamaToken;
+use std::time::Instant;
+
+/// Widest beam search allowed, each beam is another decode per token
+pub const MAX_BEAM_WIDTH: usize = 4;
+
+/// One finished candidate
+#[derive(Debug, Clone, PartialEq)]
+pub struct Beam {
+    /// The raw output, before the prompt echo and labels are stripped
+    pub text: String,
+    /// Mean log probability of its tokens, higher is better
+    pub score: f32,
+    /// Whether it ran out of tokens or time before ending itself
+    pub truncated: bool,
+}
+
+/// What a search found, best beam first
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub beams: Vec<Beam>,
+    /// Tokens decoded across all beams
+    pub decoded_tokens: usize,
+    /// Whether the deadline cut the search short
+    pub timed_out: bool,
+}
+
+/// How a search runs
+pub struct Search<'a> {
+    pub width: usize,
+    pub max_new_tokens: usize,
+    /// Where a beam's text should be cut because it completed a stop
+    /// sequence, if it did
+    pub stop_at: &'a dyn Fn(&str) -> Option<usize>,
+    pub deadline: Option<Instant>,
+}
+
+/// Context size to ask for so each of `sequences` gets `per_sequence`
+/// tokens
+///
+/// llama.cpp splits the context between sequences, except for models with
+/// a recurrent state, which give each the full size. Those are the models
+/// that can't rewind.
+pub fn context_size(
+    model: &LlamaModel,
+    backend: &LlamaBackend,
+    per_sequence: u32,
+    sequences: u32,
+) -> Result<u32> {
+    if speculative::can_rewind(model, backend)? {
+        Ok(per_sequence * sequences)
+    } else {
+        Ok(per_sequence)
+    }
+}
+
+// A beam still being extended
+struct Live {
+    seq: i32,
+    bytes: Vec<u8>,
+    tokens: usize,
+    logprob: f32,
+    // Index of its next-token logits in the last decoded batch
+    logits: i32,
+}
+
+impl Search<'_> {
+    /// Search from a prompt of `prompt_len` tokens decoded in seq 0, whose
+    /// last token's logits are at `logits_index`
+    ///
+    /// `ctx` needs `2 * width` sequences, each with room for the prompt and
+    /// `max_new_tokens` more.
+    pub fn run(
+        &self,
+        ctx: &mut LlamaContext,
+        batch: &mut LlamaBatch,
+        prompt_len: usize,
+        logits_index: i32,
+    ) -> Result<Outcome> {
+        let width = self.width.max(1);
+        let model = ctx.model;
+        let mut live = vec![Live {
+            seq: 0,
+            bytes: Vec::new(),
+            tokens: 0,
+            logprob: 0.0,
+            logits: logits_index,
+        }];
+        let mut finished = Vec::new();
+        let mut decoded_tokens = 0;
+        let mut timed_out = false;
+        // First seq of the next step's beams
+        let mut base = width as i32;
+
+        for _ in 0..self.max_new_tokens {
+            if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                timed_out = true;
+                break;
+            }
+
+            // The best `width` continuations overall are among each beam's
+            // own best `width`
+            let mut candidates = Vec::with_capacity(live.len() * width);
+            for (parent, beam) in live.iter().enumerate() {
+                for (token, logprob) in top_logprobs(ctx.get_logits_ith(beam.logits), width) {
+                    candidates.push((parent, token, beam.logprob + logprob));
+                }
+            }
+            candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+            let mut next = Vec::with_capacity(width);
+            for (parent, token, logprob) in candidates {
+                if next.len() == width {
+                    break;
+                }
+                let beam = &live[parent];
+                if model.is_eog_token(token) {
+                    finished.push(Beam {
+                        text: String::from_utf8_lossy(&beam.bytes).into_owned(),
+                        score: mean(logprob, beam.tokens + 1),
+                        truncated: false,
+                    });
+                    continue;
+                }
+
+                let mut bytes = beam.bytes.clone();
+                bytes.extend(
+                    model
+                        .token_to_bytes(token, Special::Tokenize)
+                        .context("Failed to convert token to bytes")?,
+                );
+                let text = String::from_utf8_lossy(&bytes);
+                if let Some(end) = (self.stop_at)(&text) {
+                    finished.push(Beam {
+                        text: text[..end].to_string(),
+                        score: mean(logprob, beam.tokens + 1),
+                        truncated: false,
+                    });
+                    continue;
+                }
+                next.push((parent, token, logprob, bytes));
+            }
+            if next.is_empty() || finished.len() >= width {
+                live.clear();
+                break;
+            }
+
+            // Each chosen token continues a copy of its parent's sequence
+            for (i, (parent, ..)) in next.iter().enumerate() {
+                ctx.copy_kv_cache_seq(live[*parent].seq, base + i as i32, None, None)
+                    .context("Failed to copy beam")?;
+            }
+            for beam in &live {
+                ctx.clear_kv_cache_seq(Some(beam.seq as u32), None, None)
+                    .context("Failed to clear beam")?;
+            }
+            batch.clear();
+            for (i, (parent, token, ..)) in next.iter().enumerate() {
+                let pos = (prompt_len + live[*parent].tokens) as i32;
+                batch.add(*token, pos, &[base + i as i32], true)?;
+            }
+            ctx.decode(batch).context("Failed to decode beams")?;
+            decoded_tokens += next.len();
+
+            live = next
+                .into_iter()
+                .enumerate()
+                .map(|(i, (parent, _, logprob, bytes))| Live {
+                    seq: base + i as i32,
+                    bytes,
+                    tokens: live[parent].tokens + 1,
+                    logprob,
+                    logits: i as i32,
+                })
+                .collect();
+            base = if base == 0 { width as i32 } else { 0 };
+        }
+
+        // Whatever is still going ran out of tokens or time
+        finished.extend(live.into_iter().map(|beam| Beam {
+            text: String::from_utf8_lossy(&beam.bytes).into_owned(),
+            score: mean(beam.logprob, beam.tokens),
+            truncated: true,
+        }));
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(Outcome {
+            beams: finished,
+            decoded_tokens,
+            timed_out,
+        })
+    }
+}
+
+/// The `k` most likely tokens with their log probabilities, most likely
+/// first
+///
+/// Log probabilities are a log-softmax over all of `logits`, as
+/// `sampling::token_logprob` computes them.
+pub fn top_logprobs(logits: &[f32], k: usize) -> Vec<(LlamaToken, f32)> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = max + logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln();
+
+    let mut top: Vec<(usize, f32)> = Vec::with_capacity(k + 1);
+    for (i, &logit) in logits.iter().enumerate() {
+        if top.len() < k || top.last().is_some_and(|&(_, lowest)| logit > lowest) {
+            let at = top.partition_point(|&(_, other)| other >= logit);
+            top.insert(at, (i, logit));
+            top.truncate(k);
+        }
+    }
+    top.into_iter()
+        .map(|(i, logit)| (LlamaToken(i as i32), logit - log_sum))
+        .collect()
+}
+
+// Beams are compared per token, or longer ones would always lose
+fn mean(logprob: f32, tokens: usize) -> f32 {
+    logprob / tokens.max(1) as f32
+}
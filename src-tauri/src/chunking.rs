@@ -0,0 +1,172 @@
+// Splitting long text into pieces small enough to translate one at a time.
+//
+// Pieces are consecutive slices of the input, so joining them gives back the
+// original text exactly. Splits happen at the coarsest boundary that keeps a
+// piece under the limit: paragraphs, then lines, then sentences, and only as
+// a last resort in the middle of a sentence.
+
+const SENTENCE_ENDS: &[char] = &['。', '！', '？', '.', '!', '?'];
+
+#[derive(Clone, Copy)]
+enum Boundary {
+    Line,
+    Sentence,
+    Char,
+}
+
+/// Split `text` into pieces of at most `max_chars` characters, each holding
+/// whole paragraphs where they fit
+pub fn split(text: &str, max_chars: usize) -> Vec<&str> {
+    let max_chars = max_chars.max(1);
+    let mut pieces = Vec::new();
+    for paragraph in paragraphs(text) {
+        split_into(paragraph, max_chars, Boundary::Line, &mut pieces);
+    }
+    pieces
+}
+
+/// Paragraphs of `text`, each including the blank lines that follow it
+pub fn paragraphs(text: &str) -> Vec<&str> {
+    let mut paragraphs = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    let mut after_blank = false;
+
+    for line in text.split_inclusive('\n') {
+        let blank = line.trim().is_empty();
+        if !blank && after_blank {
+            paragraphs.push(&text[start..offset]);
+            start = offset;
+        }
+        after_blank = blank && offset > start;
+        offset += line.len();
+    }
+    if start < text.len() {
+        paragraphs.push(&text[start..]);
+    }
+
+    paragraphs
+}
+
+fn split_into<'a>(text: &'a str, max_chars: usize, boundary: Boundary, pieces: &mut Vec<&'a str>) {
+    if text.chars().count() <= max_chars {
+        pieces.push(text);
+        return;
+    }
+
+    let units: Vec<&str> = match boundary {
+        Boundary::Line => text.split_inclusive('\n').collect(),
+        Boundary::Sentence => text.split_inclusive(SENTENCE_ENDS).collect(),
+        Boundary::Char => {
+            let mut units = Vec::new();
+            let mut rest = text;
+            while !rest.is_empty() {
+                let end = rest
+                    .char_indices()
+                    .nth(max_chars)
+                    .map_or(rest.len(), |(i, _)| i);
+                units.push(&rest[..end]);
+                rest = &rest[end..];
+            }
+            units
+        }
+    };
+
+    // Group consecutive units greedily, breaking up any that is too long on
+    // its own at the next finer boundary
+    let mut start = 0;
+    let mut offset = 0;
+    let mut count = 0;
+    for unit in units {
+        let len = unit.chars().count();
+        if count + len > max_chars && count > 0 {
+            pieces.push(&text[start..offset]);
+            start = offset;
+            count = 0;
+        }
+
+        if len > max_chars {
+            let finer = match boundary {
+                Boundary::Line => Boundary::Sentence,
+                _ => Boundary::Char,
+            };
+            split_into(unit, max_chars, finer, pieces);
+            start = offset + unit.len();
+        } else {
+            count += len;
+        }
+        offset += unit.len();
+    }
+    if start < text.len() {
+        pieces.push(&text[start..]);
+    }
+}
+
+/// The last `n` sentences of `text`, trimmed
+pub fn last_sentences(text: &str, n: usize) -> &str {
+    let text = text.trim();
+    if n == 0 {
+        return "";
+    }
+
+    let sentences: Vec<&str> = text.split_inclusive(SENTENCE_ENDS).collect();
+    let keep: usize = sentences.iter().rev().take(n).map(|s| s.len()).sum();
+    text[text.len() - keep..].trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paragraphs_keep_their_blank_lines() {
+        assert_eq!(paragraphs("One.\n\nTwo.\n"), vec!["One.\n\n", "Two.\n"]);
+        assert_eq!(
+            paragraphs("One\nstill one.\n\n\nTwo.\n \nThree."),
+            vec!["One\nstill one.\n\n\n", "Two.\n \n", "Three."]
+        );
+        assert_eq!(paragraphs("\n\nOne.\n\n"), vec!["\n\n", "One.\n\n"]);
+        assert!(paragraphs("").is_empty());
+    }
+
+    #[test]
+    fn pieces_join_up_to_the_input() {
+        let text = "First paragraph. It is short.\n\n\
+                    Second paragraph, which has a rather long sentence in it! And another?\n\
+                    A line.\n\n\
+                    三つ目の段落です。とても長い文がここに続きます。";
+        for max_chars in [1, 5, 20, 40, 1000] {
+            let pieces = split(text, max_chars);
+            assert_eq!(pieces.concat(), text);
+            for piece in pieces {
+                assert!(!piece.is_empty() && piece.chars().count() <= max_chars);
+            }
+        }
+    }
+
+    #[test]
+    fn splits_happen_at_the_coarsest_boundary() {
+        let text = "Hello there.\n\nHow are you? Fine.\nThanks.";
+        assert_eq!(
+            split(text, 100),
+            vec!["Hello there.\n\n", "How are you? Fine.\nThanks."]
+        );
+        assert_eq!(
+            split(text, 20),
+            vec!["Hello there.\n\n", "How are you? Fine.\n", "Thanks."]
+        );
+        assert_eq!(
+            split(text, 14),
+            vec!["Hello there.\n\n", "How are you?", " Fine.\n", "Thanks."]
+        );
+        assert_eq!(split("日本語の文", 2), vec!["日本", "語の", "文"]);
+    }
+
+    #[test]
+    fn last_sentences_are_trimmed() {
+        assert_eq!(last_sentences("One. Two! Three? ", 2), "Two! Three?");
+        assert_eq!(last_sentences("一つ。二つ。", 1), "二つ。");
+        assert_eq!(last_sentences("Only one", 3), "Only one");
+        assert_eq!(last_sentences("One. Two.", 0), "");
+    }
+}
@@ -0,0 +1,53 @@
+use thiserror::Error;
+
+/// Failures callers may want to tell apart from generic errors
+///
+/// These travel inside `anyhow::Error` and can be recovered with
+/// `error.downcast_ref::<TranslationError>()`.
+#[derive(Debug, Error)]
+pub enum TranslationError {
+    /// Generation ran past its time budget
+    #[error("Translation timed out after {timeout_ms} ms")]
+    Timeout {
+        timeout_ms: u64,
+        /// Output generated before the deadline
+        partial: String,
+    },
+    /// The cache directory's volume can't hold the model
+    #[error("Not enough disk space to download the model: {needed} bytes needed, {available} available")]
+    InsufficientDiskSpace { needed: u64, available: u64 },
+    /// The model is gated and the server refused the (missing) token
+    #[error("Authentication required to download the model (HTTP {status}), set HF_TOKEN to a HuggingFace token with access to it")]
+    AuthenticationRequired { status: u16 },
+    /// Offline mode is on and the model isn't on disk
+    #[error("Model is not downloaded and offline mode is enabled, expected it at {path}")]
+    ModelNotDownloaded { path: String },
+    /// A direction code that isn't one of the supported pairs
+    #[error("Invalid translation direction: {direction}")]
+    InvalidDirection { direction: String },
+    /// The text is longer than `translate` accepts in one go
+    #[error("Text is too long to translate at once ({chars} characters, limit {limit}), translate it as a file instead")]
+    InputTooLong { chars: usize, limit: usize },
+    /// Too many translations are already running or queued
+    #[error("Too many translations in progress (limit {limit}), try again shortly")]
+    Busy { limit: usize },
+    /// No model cache directory could be created and written to
+    #[error("Model cache directory {path} is not writable ({reason}), set KONNYAKU_MODEL_DIR to a directory you can write to")]
+    CacheDirUnwritable { path: String, reason: String },
+}
+
+impl TranslationError {
+    /// A stable snake_case name for the variant, for frontends to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Timeout { .. } => "timeout",
+            Self::InsufficientDiskSpace { .. } => "insufficient_disk_space",
+            Self::AuthenticationRequired { .. } => "authentication_required",
+            Self::ModelNotDownloaded { .. } => "model_not_downloaded",
+            Self::InvalidDirection { .. } => "invalid_direction",
+            Self::InputTooLong { .. } => "input_too_long",
+            Self::Busy { .. } => "busy",
+            Self::CacheDirUnwritable { .. } => "cache_dir_unwritable",
+        }
+    }
+}
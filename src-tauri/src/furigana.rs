@@ -0,0 +1,190 @@
+// Furigana: kanji annotated with their readings as HTML ruby.
+
+use crate::html;
+use crate::romaji::katakana_to_hiragana;
+use std::collections::HashSet;
+
+// Readings longer than this per kanji are taken as a misaligned match
+const MAX_KANA_PER_KANJI: usize = 6;
+
+/// Whether `text` has any kanji to annotate
+pub fn has_kanji(text: &str) -> bool {
+    text.chars().any(is_kanji)
+}
+
+/// Annotate the kanji in `text` with `reading`, the whole text in kana
+///
+/// The kana and other characters around each run of kanji anchor its
+/// reading, so `今日は晴れ` with `きょうははれ` gives `今日` the reading
+/// `きょう` and `晴` `は`. Katakana and hiragana match each other and
+/// whitespace is ignored. Returns None when the reading doesn't fit the
+/// text, e.g. because it was translated rather than read. The result is
+/// HTML, with `<ruby>今日<rt>きょう</rt></ruby>` for each run.
+pub fn annotate(text: &str, reading: &str) -> Option<String> {
+    let runs = runs(text);
+    let reading: Vec<char> = normalize(reading).collect();
+    let readings = align(&runs, &reading)?;
+
+    let mut output = String::with_capacity(text.len() * 3);
+    let mut readings = readings.into_iter();
+    for run in runs {
+        if run.kanji {
+            let reading: String = readings.next()?.iter().collect();
+            output.push_str("<ruby>");
+            output.push_str(run.text);
+            output.push_str("<rt>");
+            output.push_str(&reading);
+            output.push_str("</rt></ruby>");
+        } else {
+            output.push_str(&html::escape_text(run.text));
+        }
+    }
+    Some(output)
+}
+
+struct Run<'a> {
+    text: &'a str,
+    kanji: bool,
+}
+
+/// Split `text` into alternating runs of kanji and everything else
+fn runs(text: &str) -> Vec<Run<'_>> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        let kanji = is_kanji(c);
+        let end = chars.peek().map_or(text.len(), |&(index, _)| index);
+        let next_differs = chars
+            .peek()
+            .is_none_or(|&(_, next)| is_kanji(next) != kanji);
+        if next_differs {
+            runs.push(Run {
+                text: &text[start..end],
+                kanji,
+            });
+            start = end;
+        }
+    }
+    runs
+}
+
+/// A reading for each kanji run, in order, or None if `reading` can't be
+/// split to match the runs
+fn align<'a>(runs: &[Run<'_>], reading: &'a [char]) -> Option<Vec<&'a [char]>> {
+    let mut aligner = Aligner {
+        runs,
+        reading,
+        readings: Vec::new(),
+        dead_ends: HashSet::new(),
+    };
+    aligner.align(0, 0).then_some(aligner.readings)
+}
+
+struct Aligner<'r, 'a> {
+    runs: &'r [Run<'r>],
+    reading: &'a [char],
+    // Readings of the kanji runs before the current one
+    readings: Vec<&'a [char]>,
+    // Runs and reading offsets known not to line up, so no split of the
+    // runs before them tries them twice
+    dead_ends: HashSet<(usize, usize)>,
+}
+
+impl<'a> Aligner<'_, 'a> {
+    /// Whether the runs from `run` on match the reading from `offset` on
+    fn align(&mut self, run: usize, offset: usize) -> bool {
+        let Some(current) = self.runs.get(run) else {
+            return offset == self.reading.len();
+        };
+        if self.dead_ends.contains(&(run, offset)) {
+            return false;
+        }
+
+        let reading = self.reading;
+        let rest = &reading[offset..];
+        let found = if current.kanji {
+            // Shortest reading first, the following kana decide where it ends
+            let longest = (current.text.chars().count() * MAX_KANA_PER_KANJI).min(rest.len());
+            (1..=longest)
+                .take_while(|&len| is_kana(rest[len - 1]))
+                .any(|len| {
+                    self.readings.push(&rest[..len]);
+                    let found = self.align(run + 1, offset + len);
+                    if !found {
+                        self.readings.pop();
+                    }
+                    found
+                })
+        } else {
+            let literal: Vec<char> = normalize(current.text).collect();
+            rest.starts_with(&literal) && self.align(run + 1, offset + literal.len())
+        };
+
+        if !found {
+            self.dead_ends.insert((run, offset));
+        }
+        found
+    }
+}
+
+/// Characters as compared between the text and the reading
+fn normalize(text: &str) -> impl Iterator<Item = char> + '_ {
+    text.chars()
+        .filter(|c| !c.is_whitespace())
+        .map(katakana_to_hiragana)
+}
+
+fn is_kanji(c: char) -> bool {
+    matches!(c,
+        '\u{3005}' // 々
+        | '\u{3400}'..='\u{4DBF}' // CJK extension A
+        | '\u{4E00}'..='\u{9FFF}' // CJK unified ideographs
+        | '\u{F900}'..='\u{FAFF}' // CJK compatibility ideographs
+        | '\u{20000}'..='\u{2FA1F}')
+}
+
+fn is_kana(c: char) -> bool {
+    matches!(c, '\u{3041}'..='\u{3096}' | 'ー')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kanji_get_their_readings() {
+        assert_eq!(
+            annotate("今日は晴れ", "きょうははれ").unwrap(),
+            "<ruby>今日<rt>きょう</rt></ruby>は<ruby>晴<rt>は</rt></ruby>れ"
+        );
+        assert_eq!(
+            annotate("日本語を話す", "ニホンゴヲハナス").unwrap(),
+            "<ruby>日本語<rt>にほんご</rt></ruby>を<ruby>話<rt>はな</rt></ruby>す"
+        );
+        assert_eq!(
+            annotate("東京タワー、A&B", "とうきょうたわー、 A&B").unwrap(),
+            "<ruby>東京<rt>とうきょう</rt></ruby>タワー、A&amp;B"
+        );
+        assert_eq!(annotate("ですね", "ですね").unwrap(), "ですね");
+    }
+
+    #[test]
+    fn readings_that_dont_fit_are_rejected() {
+        assert_eq!(annotate("猫", "dog"), None);
+        assert_eq!(annotate("猫が好き", "ねこがすきです"), None);
+        assert_eq!(annotate("猫", ""), None);
+        assert_eq!(annotate("猫", "ねこねこねこねこねこねこねこ"), None);
+    }
+
+    #[test]
+    fn hopeless_alignments_finish_quickly() {
+        // Every way of splitting the readings is tried, but only once per
+        // run and offset
+        let text = "漢か".repeat(40);
+        let reading = "か".repeat(160) + "ん";
+        let started = std::time::Instant::now();
+        assert_eq!(annotate(&text, &reading), None);
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,254 @@
+// Glossary term lists, read from and written to CSV or TSV files.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// A source term and the translation it should get
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GlossaryEntry {
+    pub source: String,
+    pub target: String,
+}
+
+/// A row of a glossary file that was left out
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkippedRow {
+    /// 1-based line of the file the row starts on
+    pub row: usize,
+    pub reason: String,
+}
+
+/// What importing a glossary file changed
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GlossaryImport {
+    /// Terms that weren't in the glossary yet
+    pub added: usize,
+    /// Terms already in the glossary that got the file's translation
+    pub updated: usize,
+    pub skipped: Vec<SkippedRow>,
+}
+
+/// The field separator for `path`: tabs for `.tsv` and `.tab`, commas
+/// otherwise
+pub fn delimiter(path: &Path) -> u8 {
+    let tabs = path.extension().is_some_and(|extension| {
+        extension.eq_ignore_ascii_case("tsv") || extension.eq_ignore_ascii_case("tab")
+    });
+    if tabs {
+        b'\t'
+    } else {
+        b','
+    }
+}
+
+/// Parse `source,target` rows, returning the entries and the rows skipped
+///
+/// Rows need exactly two columns and a non-empty source term. A first row of
+/// `source,target` is taken as a header and skipped silently.
+pub fn parse(contents: &str, delimiter: u8) -> (Vec<GlossaryEntry>, Vec<SkippedRow>) {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(delimiter)
+        .from_reader(contents.trim_start_matches('\u{FEFF}').as_bytes());
+
+    let mut entries = Vec::new();
+    let mut skipped = Vec::new();
+    for (index, record) in reader.records().enumerate() {
+        // Blank lines aren't records, so rows are numbered by where they start
+        let line = |position: Option<&csv::Position>| {
+            position.map_or(index + 1, |position| position.line() as usize)
+        };
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                skipped.push(SkippedRow {
+                    row: line(e.position()),
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let row = line(record.position());
+        if record.len() != 2 {
+            skipped.push(SkippedRow {
+                row,
+                reason: format!("expected 2 columns, found {}", record.len()),
+            });
+            continue;
+        }
+
+        let source = record[0].trim();
+        let target = record[1].trim();
+        if index == 0 && is_header(source, target) {
+            continue;
+        }
+        if source.is_empty() {
+            skipped.push(SkippedRow {
+                row,
+                reason: "empty source term".to_string(),
+            });
+            continue;
+        }
+        entries.push(GlossaryEntry {
+            source: source.to_string(),
+            target: target.to_string(),
+        });
+    }
+    (entries, skipped)
+}
+
+/// Merge `entries` into `glossary`, later entries replacing the translation
+/// of an existing source term
+pub fn merge(glossary: &mut Vec<GlossaryEntry>, entries: Vec<GlossaryEntry>) -> GlossaryImport {
+    let mut import = GlossaryImport::default();
+    for entry in entries {
+        match glossary
+            .iter_mut()
+            .find(|existing| existing.source == entry.source)
+        {
+            Some(existing) if existing.target == entry.target => {}
+            Some(existing) => {
+                existing.target = entry.target;
+                import.updated += 1;
+            }
+            None => {
+                glossary.push(entry);
+                import.added += 1;
+            }
+        }
+    }
+    import
+}
+
+/// Write `entries` as `source,target` rows under a header row
+pub fn write(entries: &[GlossaryEntry], delimiter: u8) -> Result<String> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(Vec::new());
+    writer
+        .write_record(["source", "target"])
+        .context("Failed to write glossary")?;
+    for entry in entries {
+        writer
+            .write_record([&entry.source, &entry.target])
+            .context("Failed to write glossary")?;
+    }
+    let output = writer
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .context("Failed to write glossary")?;
+    String::from_utf8(output).context("Glossary is not valid UTF-8")
+}
+
+/// Replace the glossary terms of `text` that `output` left untranslated
+/// with their translations
+///
+/// Only terms occurring in `text` count, and at each position the longest
+/// one wins. A term that starts or ends with an ASCII letter or digit only
+/// matches as a whole word, so `cat` is left alone inside `category`.
+pub fn apply(output: &str, text: &str, entries: &[GlossaryEntry]) -> String {
+    let mut terms: Vec<&GlossaryEntry> = entries
+        .iter()
+        .filter(|entry| entry.source != entry.target && find_term(text, &entry.source))
+        .collect();
+    if terms.is_empty() {
+        return output.to_string();
+    }
+    terms.sort_by_key(|entry| std::cmp::Reverse(entry.source.len()));
+
+    let mut result = String::with_capacity(output.len());
+    let mut at = 0;
+    while let Some(c) = output[at..].chars().next() {
+        match terms
+            .iter()
+            .find(|entry| term_at(output, at, &entry.source))
+        {
+            Some(entry) => {
+                result.push_str(&entry.target);
+                at += entry.source.len();
+            }
+            None => {
+                result.push(c);
+                at += c.len_utf8();
+            }
+        }
+    }
+    result
+}
+
+fn find_term(text: &str, term: &str) -> bool {
+    !term.is_empty()
+        && text
+            .match_indices(term)
+            .any(|(at, _)| term_at(text, at, term))
+}
+
+// Whether `term` is at byte `at` of `text`, on word boundaries where it
+// starts or ends with a word character
+fn term_at(text: &str, at: usize, term: &str) -> bool {
+    if !text[at..].starts_with(term) {
+        return false;
+    }
+    let is_word = |c: char| c.is_ascii_alphanumeric();
+    let starts_word = term.chars().next().is_some_and(is_word);
+    let ends_word = term.chars().next_back().is_some_and(is_word);
+    let joins_before = starts_word && text[..at].chars().next_back().is_some_and(is_word);
+    let joins_after = ends_word && text[at + term.len()..].chars().next().is_some_and(is_word);
+    !joins_before && !joins_after
+}
+
+fn is_header(source: &str, target: &str) -> bool {
+    source.eq_ignore_ascii_case("source") && target.eq_ignore_ascii_case("target")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(source: &str, target: &str) -> GlossaryEntry {
+        GlossaryEntry {
+            source: source.to_string(),
+            target: target.to_string(),
+        }
+    }
+
+    #[test]
+    fn apply_prefers_the_longest_term() {
+        let entries = [entry("東京", "Tokyo"), entry("東京タワー", "Tokyo Tower")];
+        let output = apply("東京タワー is in 東京.", "東京の東京タワー", &entries);
+        assert_eq!(output, "Tokyo Tower is in Tokyo.");
+    }
+
+    #[test]
+    fn apply_matches_ascii_terms_as_whole_words() {
+        let entries = [entry("cat", "猫")];
+        assert_eq!(apply("cat、category", "The cat.", &entries), "猫、category");
+        assert_eq!(apply("cat", "A category.", &entries), "cat");
+    }
+
+    #[test]
+    fn parse_skips_malformed_rows() {
+        let (entries, skipped) = parse("source,target\na,b\nc\n,d\n", b',');
+        assert_eq!(entries, vec![entry("a", "b")]);
+        let rows: Vec<usize> = skipped.iter().map(|row| row.row).collect();
+        assert_eq!(rows, vec![3, 4]);
+    }
+
+    #[test]
+    fn skipped_rows_count_blank_lines() {
+        let (entries, skipped) = parse("a,b\n\nc\n\n\n,d\n", b',');
+        assert_eq!(entries, vec![entry("a", "b")]);
+        let rows: Vec<usize> = skipped.iter().map(|row| row.row).collect();
+        assert_eq!(rows, vec![3, 6]);
+    }
+
+    #[test]
+    fn merge_updates_existing_terms() {
+        let mut glossary = vec![entry("a", "b")];
+        let import = merge(&mut glossary, vec![entry("a", "c"), entry("d", "e")]);
+        assert_eq!((import.added, import.updated), (1, 1));
+        assert_eq!(glossary, vec![entry("a", "c"), entry("d", "e")]);
+    }
+}
@@ -0,0 +1,358 @@
+use anyhow::{Context, Result};
+use directories::ProjectDirs;
+use rusqlite::{params, Connection, ErrorCode};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const HISTORY_FILE: &str = "history.sqlite3";
+// Version of the export file layout, bumped when it changes incompatibly
+const EXPORT_VERSION: u32 = 1;
+// How long to wait on a database locked by another connection before giving up
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS history (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        source_text TEXT    NOT NULL,
+        direction   TEXT    NOT NULL,
+        translation TEXT    NOT NULL,
+        created_at  INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS history_created_at ON history (created_at);
+";
+
+/// A single recorded translation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub source_text: String,
+    pub direction: String,
+    pub translation: String,
+    /// Seconds since the Unix epoch
+    pub created_at: i64,
+}
+
+/// An entry as exported, without the id, which only means something in the
+/// database it came from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportedEntry {
+    pub source_text: String,
+    pub direction: String,
+    pub translation: String,
+    pub created_at: i64,
+}
+
+// The JSON export file. Files without a version predate versioning and are
+// read as version 1.
+#[derive(Serialize, Deserialize)]
+struct HistoryExport {
+    #[serde(default = "first_version")]
+    version: u32,
+    entries: Vec<ExportedEntry>,
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+/// Translation history stored in a SQLite database in the app data dir
+///
+/// A connection is opened per operation on a blocking thread, so callers
+/// never hold the database across an await point.
+#[derive(Debug, Clone)]
+pub struct TranslationHistory {
+    db_path: PathBuf,
+}
+
+impl TranslationHistory {
+    /// Create a history store in the platform data directory
+    pub fn new() -> Result<Self> {
+        let proj_dirs = ProjectDirs::from("com", "konnyaku", "konnyaku")
+            .context("Failed to determine project directories")?;
+
+        let data_dir = proj_dirs.data_dir();
+        std::fs::create_dir_all(data_dir).context("Failed to create data directory")?;
+
+        Ok(Self {
+            db_path: data_dir.join(HISTORY_FILE),
+        })
+    }
+
+    /// Record a successful translation
+    pub async fn record(
+        &self,
+        source_text: String,
+        direction: String,
+        translation: String,
+    ) -> Result<()> {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+
+        self.with_connection(move |conn| {
+            conn.execute(
+                "INSERT INTO history (source_text, direction, translation, created_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![source_text, direction, translation, created_at],
+            )
+            .context("Failed to record translation history")?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// The most recent entries, newest first
+    pub async fn recent(&self, limit: usize) -> Result<Vec<HistoryEntry>> {
+        self.with_connection(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, source_text, direction, translation, created_at
+                 FROM history ORDER BY created_at DESC, id DESC LIMIT ?1",
+            )?;
+            let entries = stmt
+                .query_map(params![limit as i64], |row| {
+                    Ok(HistoryEntry {
+                        id: row.get(0)?,
+                        source_text: row.get(1)?,
+                        direction: row.get(2)?,
+                        translation: row.get(3)?,
+                        created_at: row.get(4)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .context("Failed to read translation history")?;
+            Ok(entries)
+        })
+        .await
+    }
+
+    /// Delete a single entry, returning whether it existed
+    pub async fn delete(&self, id: i64) -> Result<bool> {
+        self.with_connection(move |conn| {
+            let deleted = conn
+                .execute("DELETE FROM history WHERE id = ?1", params![id])
+                .context("Failed to delete history entry")?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    /// Remove every entry
+    pub async fn clear(&self) -> Result<()> {
+        self.with_connection(|conn| {
+            conn.execute("DELETE FROM history", [])
+                .context("Failed to clear translation history")?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Write every entry to `path`, oldest first, returning how many
+    ///
+    /// A `.csv` path gets CSV with a header row, anything else JSON.
+    pub async fn export(&self, path: &Path) -> Result<usize> {
+        let entries = self
+            .with_connection(|conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT source_text, direction, translation, created_at
+                     FROM history ORDER BY created_at, id",
+                )?;
+                let entries = stmt
+                    .query_map([], |row| {
+                        Ok(ExportedEntry {
+                            source_text: row.get(0)?,
+                            direction: row.get(1)?,
+                            translation: row.get(2)?,
+                            created_at: row.get(3)?,
+                        })
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+                    .context("Failed to read translation history")?;
+                Ok(entries)
+            })
+            .await?;
+        let count = entries.len();
+
+        let contents = if is_csv(path) {
+            entries_to_csv(&entries)?
+        } else {
+            let export = HistoryExport {
+                version: EXPORT_VERSION,
+                entries,
+            };
+            serde_json::to_string_pretty(&export).context("Failed to serialize history")?
+        };
+        tokio::fs::write(path, contents)
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(count)
+    }
+
+    /// Add the entries exported to `path`, returning how many were new
+    ///
+    /// Entries already in the history, with the same texts, direction and
+    /// time, are skipped, so importing a file twice changes nothing. Fields
+    /// this version doesn't know are ignored, but a file from a newer,
+    /// incompatible version is refused.
+    pub async fn import(&self, path: &Path) -> Result<usize> {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let entries = if is_csv(path) {
+            entries_from_csv(&contents)?
+        } else {
+            let export: HistoryExport =
+                serde_json::from_str(&contents).context("Not a history export file")?;
+            if export.version > EXPORT_VERSION {
+                anyhow::bail!(
+                    "History export version {} is newer than this app supports ({}), \
+                     update the app to import it",
+                    export.version,
+                    EXPORT_VERSION
+                );
+            }
+            export.entries
+        };
+
+        self.with_connection(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let mut imported = 0;
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT INTO history (source_text, direction, translation, created_at)
+                     SELECT ?1, ?2, ?3, ?4
+                     WHERE NOT EXISTS (
+                         SELECT 1 FROM history WHERE source_text = ?1 AND direction = ?2
+                             AND translation = ?3 AND created_at = ?4
+                     )",
+                )?;
+                for entry in &entries {
+                    imported += stmt
+                        .execute(params![
+                            entry.source_text,
+                            entry.direction,
+                            entry.translation,
+                            entry.created_at
+                        ])
+                        .context("Failed to import history entry")?;
+                }
+            }
+            tx.commit().context("Failed to import history")?;
+            Ok(imported)
+        })
+        .await
+    }
+
+    /// Run `f` with an open connection on the blocking thread pool
+    async fn with_connection<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let db_path = self.db_path.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = Self::open(&db_path)?;
+            f(&conn)
+        })
+        .await
+        .context("History task panicked")?
+    }
+
+    /// Open the database, creating the schema if it's missing
+    ///
+    /// A file that isn't a usable SQLite database (e.g. corrupted or
+    /// truncated) is discarded and recreated rather than failing forever.
+    fn open(db_path: &Path) -> Result<Connection> {
+        match Self::open_with_schema(db_path) {
+            Ok(conn) => Ok(conn),
+            Err(e) if Self::is_unusable_database(&e) => {
+                tracing::warn!(error = %e, "History database is unusable, recreating it");
+                std::fs::remove_file(db_path)
+                    .context("Failed to remove corrupted history database")?;
+                Self::open_with_schema(db_path).context("Failed to recreate history database")
+            }
+            Err(e) => Err(e).context("Failed to open history database"),
+        }
+    }
+
+    fn open_with_schema(db_path: &Path) -> rusqlite::Result<Connection> {
+        let conn = Connection::open(db_path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(conn)
+    }
+
+    fn is_unusable_database(error: &rusqlite::Error) -> bool {
+        matches!(
+            error.sqlite_error_code(),
+            Some(ErrorCode::NotADatabase | ErrorCode::DatabaseCorrupt)
+        )
+    }
+}
+
+fn is_csv(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"))
+}
+
+const CSV_COLUMNS: [&str; 4] = ["source_text", "direction", "translation", "created_at"];
+
+fn entries_to_csv(entries: &[ExportedEntry]) -> Result<String> {
+    let mut writer = csv::WriterBuilder::new().from_writer(Vec::new());
+    writer.write_record(CSV_COLUMNS).context("Failed to write CSV")?;
+    for entry in entries {
+        writer
+            .write_record([
+                entry.source_text.as_str(),
+                entry.direction.as_str(),
+                entry.translation.as_str(),
+                &entry.created_at.to_string(),
+            ])
+            .context("Failed to write CSV")?;
+    }
+    let output = writer
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .context("Failed to write CSV")?;
+    String::from_utf8(output).context("History CSV is not valid UTF-8")
+}
+
+/// Read CSV with the export's header row, in any column order and with any
+/// extra columns
+fn entries_from_csv(contents: &str) -> Result<Vec<ExportedEntry>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(contents.as_bytes());
+    let mut records = reader.records();
+
+    let header = records.next().context("History CSV is empty")?.context("Invalid CSV")?;
+    let mut columns = [0; 4];
+    for (index, name) in CSV_COLUMNS.iter().enumerate() {
+        columns[index] = header
+            .iter()
+            .position(|column| column.trim() == *name)
+            .with_context(|| format!("History CSV has no {} column", name))?;
+    }
+
+    let mut entries = Vec::new();
+    for (row, record) in records.enumerate() {
+        let record = record.context("Invalid CSV")?;
+        let field = |index: usize| {
+            record
+                .get(columns[index])
+                .with_context(|| format!("Row {} has no {}", row + 2, CSV_COLUMNS[index]))
+        };
+        entries.push(ExportedEntry {
+            source_text: field(0)?.to_string(),
+            direction: field(1)?.to_string(),
+            translation: field(2)?.to_string(),
+            created_at: field(3)?
+                .trim()
+                .parse()
+                .with_context(|| format!("Row {} has an invalid created_at", row + 2))?,
+        });
+    }
+    Ok(entries)
+}
@@ -0,0 +1,292 @@
+// HTML tokenizing for translation.
+//
+// Markup is split into tags and text nodes so only the text is sent to the
+// model, and the tags are put back exactly where they were. This is a
+// lightweight tokenizer rather than a parser: it doesn't build a tree or
+// check nesting, which the output doesn't need since the tags are copied
+// through in their original order.
+
+/// A piece of an HTML document
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node<'a> {
+    /// A tag, comment, doctype or the contents of `<script>` and `<style>`,
+    /// none of which are translated
+    Markup(&'a str),
+    /// Text between tags, with entities still encoded
+    Text(&'a str),
+}
+
+// Elements whose contents are code or CSS rather than text
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style"];
+
+/// Split `input` into markup and text nodes
+///
+/// Concatenating the nodes gives back `input` unchanged. A `<` that doesn't
+/// start a tag, as in `1 < 2`, stays part of the text.
+pub fn tokenize(input: &str) -> Vec<Node<'_>> {
+    let mut nodes = Vec::new();
+    let mut text_start = 0;
+    let mut i = 0;
+
+    while let Some(offset) = input[i..].find('<') {
+        let start = i + offset;
+        let Some(end) = markup_end(input, start) else {
+            i = start + 1;
+            continue;
+        };
+
+        if text_start < start {
+            nodes.push(Node::Text(&input[text_start..start]));
+        }
+
+        // Keep the body of script and style elements with their opening tag
+        let end = match raw_text_element(&input[start..end]) {
+            Some(name) => raw_text_end(input, end, name),
+            None => end,
+        };
+        nodes.push(Node::Markup(&input[start..end]));
+        text_start = end;
+        i = end;
+    }
+
+    if text_start < input.len() {
+        nodes.push(Node::Text(&input[text_start..]));
+    }
+    nodes
+}
+
+/// Decode character references like `&amp;`, `&#39;` and `&#x3042;`
+///
+/// Unknown or malformed references are left as they are.
+pub fn decode_entities(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let decoded = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| decode_entity(&rest[1..end + 1]).map(|c| (c, end + 2)));
+        match decoded {
+            Some((c, len)) => {
+                output.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                output.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    output.push_str(rest);
+    output
+}
+
+/// Escape the characters that would otherwise be read as markup
+pub fn escape_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn decode_entity(name: &str) -> Option<char> {
+    if let Some(number) = name.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+
+    let c = match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{A0}',
+        "copy" => '©',
+        "reg" => '®',
+        "hellip" => '…',
+        "mdash" => '—',
+        "ndash" => '–',
+        _ => return None,
+    };
+    Some(c)
+}
+
+/// The end of the tag, comment or declaration starting at `start`, if there
+/// is one
+fn markup_end(input: &str, start: usize) -> Option<usize> {
+    let rest = &input[start..];
+    if let Some(comment) = rest.strip_prefix("<!--") {
+        return comment.find("-->").map(|end| start + 4 + end + 3);
+    }
+
+    let first = rest[1..].chars().next()?;
+    if !(first.is_ascii_alphabetic() || matches!(first, '/' | '!' | '?')) {
+        return None;
+    }
+
+    // Find the closing '>', skipping any inside quoted attribute values
+    let mut quote = None;
+    for (offset, c) in rest.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None, '>') => return Some(start + offset + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The element name if `tag` opens a script or style element
+fn raw_text_element(tag: &str) -> Option<&'static str> {
+    let name: String = tag[1..]
+        .chars()
+        .take_while(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    RAW_TEXT_ELEMENTS
+        .iter()
+        .find(|element| **element == name)
+        .copied()
+        .filter(|_| !tag.ends_with("/>"))
+}
+
+/// Where the raw text element `name`, whose opening tag ends at `from`,
+/// closes, or the end of input if it never does
+fn raw_text_end(input: &str, from: usize, name: &str) -> usize {
+    let closing = format!("</{}", name);
+    let lower = input[from..].to_ascii_lowercase();
+    match lower.find(&closing) {
+        Some(offset) => {
+            let close_start = from + offset;
+            markup_end(input, close_start).unwrap_or(input.len())
+        }
+        None => input.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joined(nodes: &[Node]) -> String {
+        nodes
+            .iter()
+            .map(|node| match node {
+                Node::Markup(s) | Node::Text(s) => *s,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tags_and_text_are_split() {
+        let input = "<p class=\"a>b\">Hello <b>world</b></p><!-- note -->!";
+        let nodes = tokenize(input);
+        assert_eq!(
+            nodes,
+            vec![
+                Node::Markup("<p class=\"a>b\">"),
+                Node::Text("Hello "),
+                Node::Markup("<b>"),
+                Node::Text("world"),
+                Node::Markup("</b>"),
+                Node::Markup("</p>"),
+                Node::Markup("<!-- note -->"),
+                Node::Text("!"),
+            ]
+        );
+        assert_eq!(joined(&nodes), input);
+    }
+
+    #[test]
+    fn a_lone_less_than_is_text() {
+        assert_eq!(tokenize("1 < 2, 3 <4"), vec![Node::Text("1 < 2, 3 <4")]);
+
+        // So does one whose tag never closes
+        assert_eq!(tokenize("if a <b"), vec![Node::Text("if a <b")]);
+    }
+
+    #[test]
+    fn script_and_style_bodies_are_markup() {
+        let input = "<SCRIPT>if (a < b) { x = \"<p>\"; }</script>Hi<style>p{}</style>";
+        let nodes = tokenize(input);
+        assert_eq!(
+            nodes,
+            vec![
+                Node::Markup("<SCRIPT>if (a < b) { x = \"<p>\"; }</script>"),
+                Node::Text("Hi"),
+                Node::Markup("<style>p{}</style>"),
+            ]
+        );
+        assert_eq!(joined(&nodes), input);
+
+        // An unclosed script runs to the end
+        assert_eq!(tokenize("<script>a<b"), vec![Node::Markup("<script>a<b")]);
+    }
+
+    #[test]
+    fn links_keep_their_attributes() {
+        let input = "See <a href=\"https://x/?a=1&amp;b=2\">link</a>.";
+        let nodes = tokenize(input);
+        assert_eq!(
+            nodes,
+            vec![
+                Node::Text("See "),
+                Node::Markup("<a href=\"https://x/?a=1&amp;b=2\">"),
+                Node::Text("link"),
+                Node::Markup("</a>"),
+                Node::Text("."),
+            ]
+        );
+        assert_eq!(joined(&nodes), input);
+    }
+
+    #[test]
+    fn self_closing_tags_are_markup() {
+        let input = "one<br/>two<img src=\"a.png\" />three";
+        let nodes = tokenize(input);
+        assert_eq!(
+            nodes,
+            vec![
+                Node::Text("one"),
+                Node::Markup("<br/>"),
+                Node::Text("two"),
+                Node::Markup("<img src=\"a.png\" />"),
+                Node::Text("three"),
+            ]
+        );
+        assert_eq!(joined(&nodes), input);
+
+        // A self-closing script has no body, so the text after it is kept
+        let input = "<script src=\"a.js\"/>Hello</script>";
+        let nodes = tokenize(input);
+        assert_eq!(
+            nodes,
+            vec![
+                Node::Markup("<script src=\"a.js\"/>"),
+                Node::Text("Hello"),
+                Node::Markup("</script>"),
+            ]
+        );
+        assert_eq!(joined(&nodes), input);
+    }
+
+    #[test]
+    fn entities_are_decoded() {
+        assert_eq!(
+            decode_entities("Tom &amp; Jerry&#39;s &lt;b&gt; &#x3042;&nbsp;&hellip;"),
+            "Tom & Jerry's <b> あ\u{A0}…"
+        );
+        let unknown = "AT&T &bogus; &#xZZ; & done";
+        assert_eq!(decode_entities(unknown), unknown);
+        assert_eq!(decode_entities(&escape_text("a < b & c")), "a < b & c");
+    }
+}
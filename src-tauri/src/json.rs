@@ -0,0 +1,73 @@
+// Finding the translatable strings of a JSON document, e.g. app localization
+// files, without touching its structure.
+
+use serde_json::Value;
+
+/// A string value somewhere in a JSON document
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonString {
+    /// JSON pointer to the value, for `Value::pointer_mut`
+    pub pointer: String,
+    pub text: String,
+}
+
+/// Every string value in `value`, in document order
+///
+/// Object keys are never included. With `keys`, only strings under one of
+/// them are: a plain name matches an object key at any depth, a dotted path
+/// like `menu.items.0` matches from the root, with array indices as numbers.
+pub fn strings(value: &Value, keys: Option<&[String]>) -> Vec<JsonString> {
+    let mut found = Vec::new();
+    collect(value, &mut Vec::new(), keys, &mut found);
+    found
+}
+
+fn collect(
+    value: &Value,
+    path: &mut Vec<String>,
+    keys: Option<&[String]>,
+    found: &mut Vec<JsonString>,
+) {
+    match value {
+        Value::String(text) => {
+            if keys.is_none_or(|keys| keys.iter().any(|key| selects(key, path))) {
+                found.push(JsonString {
+                    pointer: pointer(path),
+                    text: text.clone(),
+                });
+            }
+        }
+        Value::Array(items) => {
+            for (index, item) in items.iter().enumerate() {
+                path.push(index.to_string());
+                collect(item, path, keys, found);
+                path.pop();
+            }
+        }
+        Value::Object(map) => {
+            for (key, item) in map {
+                path.push(key.clone());
+                collect(item, path, keys, found);
+                path.pop();
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+// Whether `key` (a name, or a dotted path from the root) covers `path`
+fn selects(key: &str, path: &[String]) -> bool {
+    if key.contains('.') {
+        let prefix: Vec<&str> = key.split('.').collect();
+        prefix.len() <= path.len() && prefix.iter().zip(path).all(|(a, b)| a == b)
+    } else {
+        path.iter().any(|segment| segment == key)
+    }
+}
+
+// RFC 6901 pointer for `path`
+fn pointer(path: &[String]) -> String {
+    path.iter()
+        .map(|segment| format!("/{}", segment.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
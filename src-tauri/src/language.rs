@@ -0,0 +1,71 @@
+// Language identification from the Unicode scripts a text is written in.
+
+/// Guess the language of `text` from its dominant script
+///
+/// Returns an ISO 639-1 code, or `"unknown"` when there is nothing to go on.
+/// Only letters are counted, so digits, punctuation and emoji don't sway the
+/// result, and mixed text gets the code of whichever script has the most
+/// letters. The languages it can tell apart are:
+///
+/// - `"ja"`: hiragana, katakana and kanji. Kanji count as Japanese, since
+///   Chinese can't be told apart by script alone.
+/// - `"ko"`: Hangul
+/// - `"en"`: Latin letters, including other Latin-script languages
+///
+/// Any other script is `"unknown"`.
+pub fn detect_language(text: &str) -> &'static str {
+    let (mut japanese, mut korean, mut latin, mut other) = (0usize, 0usize, 0usize, 0usize);
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        match script(c) {
+            Script::Japanese => japanese += 1,
+            Script::Korean => korean += 1,
+            Script::Latin => latin += 1,
+            Script::Other => other += 1,
+        }
+    }
+
+    // On a tie the earlier entry wins, so mixed kanji and Latin leans Japanese
+    let counts = [
+        ("ja", japanese),
+        ("ko", korean),
+        ("en", latin),
+        ("unknown", other),
+    ];
+    let mut dominant = ("unknown", 0);
+    for (code, count) in counts {
+        if count > dominant.1 {
+            dominant = (code, count);
+        }
+    }
+    dominant.0
+}
+
+enum Script {
+    Japanese,
+    Korean,
+    Latin,
+    Other,
+}
+
+fn script(c: char) -> Script {
+    match c {
+        '\u{3040}'..='\u{30FF}' // Hiragana, Katakana
+        | '\u{31F0}'..='\u{31FF}' // Katakana phonetic extensions
+        | '\u{FF66}'..='\u{FF9F}' // Half-width katakana
+        | '\u{3005}' // 々
+        | '\u{3400}'..='\u{4DBF}' // CJK extension A
+        | '\u{4E00}'..='\u{9FFF}' // CJK unified ideographs
+        | '\u{F900}'..='\u{FAFF}' // CJK compatibility ideographs
+        | '\u{20000}'..='\u{2FA1F}' => Script::Japanese,
+        '\u{1100}'..='\u{11FF}' | '\u{3130}'..='\u{318F}' | '\u{AC00}'..='\u{D7AF}' => {
+            Script::Korean
+        }
+        'A'..='Z'
+        | 'a'..='z'
+        | '\u{00C0}'..='\u{024F}' // Latin-1 supplement and extended
+        | '\u{1E00}'..='\u{1EFF}' // Latin extended additional
+        | '\u{FF21}'..='\u{FF3A}' // Full-width A-Z
+        | '\u{FF41}'..='\u{FF5A}' => Script::Latin,
+        _ => Script::Other,
+    }
+}
@@ -1,14 +1,57 @@
-mod translation;
+// The translation engine has no Tauri dependency and can be used on its own
+// (see examples/translate.rs). The commands below are thin wrappers over it.
+pub mod beam;
+pub mod chunking;
+pub mod error;
+pub mod furigana;
+pub mod glossary;
+pub mod history;
+pub mod html;
+pub mod json;
+pub mod language;
+pub mod logging;
+pub mod markdown;
+pub mod metadata;
+pub mod prompts;
+pub mod quality;
+pub mod quantization;
+pub mod romaji;
+pub mod sanitize;
+pub mod sampling;
+pub mod speculative;
+pub mod settings;
+pub mod subtitles;
+pub mod translation;
+pub mod translator;
 
+use error::TranslationError;
+use glossary::{GlossaryEntry, GlossaryImport};
+use history::{HistoryEntry, TranslationHistory};
+use logging::{LogLine, Logging};
+use metadata::ModelMetadata;
+use quantization::Quantization;
 use serde::{Deserialize, Serialize};
+use sampling::SamplingPreset;
+use settings::Settings;
+use std::path::Path;
 use std::sync::Arc;
-use tauri::State;
-use translation::{TranslationDirection, TranslationService};
+use tauri::{AppHandle, Emitter, State};
+use tokio::sync::Mutex;
+use translator::Translator;
+use translation::{
+    BackendInfo, Benchmark, CachedModel, Confidence, DirectionSpec, DownloadProgress, DryRun,
+    GenerationTimings, HealthReport, LanguagePair, LanguagePairInfo, MemoryEstimate, MemoryStats,
+    ModelCacheInfo, ModelPhase, ModelRepair, Readiness, RoundTrip, TranslateOptions,
+    TranslationService,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TranslateRequest {
     text: String,
-    direction: String, // "en-ja" or "ja-en"
+    /// "en-ja", "ja-en" or `{ "source": "en", "target": "ja" }`
+    direction: DirectionSpec,
+    #[serde(flatten)]
+    options: TranslateOptions,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -16,74 +59,952 @@ pub struct TranslateResponse {
     success: bool,
     translation: Option<String>,
     error: Option<String>,
+    truncated: bool,
+    alternatives: Vec<String>,
+    confidence: Option<Confidence>,
+    /// The output matches the input, the model may have skipped translating
+    possibly_untranslated: bool,
+    romaji: Option<String>,
+    furigana: Option<String>,
+    /// Tokenize, prompt and generation times, when `collect_timings` was set
+    timings: Option<GenerationTimings>,
+    /// Milliseconds until the first output token, with `timings`
+    time_to_first_token_ms: Option<f64>,
+}
+
+/// Versions and model details for bug reports
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionInfo {
+    app_version: String,
+    /// Commit the app was built from, when built from a git checkout
+    git_commit: Option<String>,
+    /// Version of the llama-cpp-2 bindings, from Cargo.lock at build time
+    llama_cpp_version: Option<String>,
+    model_repo: String,
+    model_file: String,
+    quantization: Option<Quantization>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ModelStatusResponse {
     loaded: bool,
+    /// Downloading, loading, ready and so on, finer-grained than `loaded`
+    #[serde(flatten)]
+    phase: ModelPhase,
+    /// "Metal", "GPU" or "CPU"
+    backend: String,
+    /// The GPU couldn't be used and the model was loaded on the CPU
+    cpu_fallback: bool,
+    /// Quantization of the model in use, when its file name shows one
+    quantization: Option<Quantization>,
+    /// Why the translation service couldn't be started, `phase` is failed
+    /// with the same error
+    init_error: Option<String>,
 }
 
 // Wrapper struct for TranslationService to make it manageable by Tauri
-pub struct TranslationServiceState(Arc<TranslationService>);
+//
+// Holds the error instead when the service couldn't be created, so the
+// window still opens and can show what went wrong.
+pub struct TranslationServiceState {
+    service: std::sync::RwLock<Result<Arc<TranslationService>, String>>,
+    // Held while retry_init runs, so concurrent retries don't race
+    retrying: Mutex<()>,
+}
+
+impl TranslationServiceState {
+    fn new(service: Result<Arc<TranslationService>, String>) -> Self {
+        Self {
+            service: std::sync::RwLock::new(service),
+            retrying: Mutex::new(()),
+        }
+    }
+
+    // The service, or an error saying why there is none
+    fn service(&self) -> Result<Arc<TranslationService>, String> {
+        self.service.read().unwrap().clone().map_err(unavailable)
+    }
+}
+
+// Holds the error instead when the history database couldn't be opened, so
+// translating still works without it
+pub struct HistoryState(Result<Arc<TranslationHistory>, String>);
+
+impl HistoryState {
+    fn history(&self) -> Result<Arc<TranslationHistory>, String> {
+        self.0
+            .clone()
+            .map_err(|e| format!("Translation history is unavailable: {}", e))
+    }
+}
+
+// The same service seen through the Translator trait, by the commands that
+// only need to translate
+pub struct TranslatorState(std::sync::RwLock<Result<Arc<dyn Translator>, String>>);
+
+impl TranslatorState {
+    fn translator(&self) -> Result<Arc<dyn Translator>, String> {
+        self.0.read().unwrap().clone().map_err(unavailable)
+    }
+}
+
+// What commands needing the service return when it failed to initialize
+fn unavailable(init_error: String) -> String {
+    format!("Translation service failed to initialize: {}", init_error)
+}
+
+pub struct LoggingState(Logging);
 
 #[tauri::command]
 async fn translate(
     request: TranslateRequest,
-    state: State<'_, TranslationServiceState>,
+    translator: State<'_, TranslatorState>,
+    history: State<'_, HistoryState>,
 ) -> Result<TranslateResponse, String> {
+    let response = translate_request(translator.translator()?.as_ref(), &request).await;
+
+    // Record in the background so history never slows down translation
+    let recorded = (
+        response.success,
+        &response.translation,
+        request.direction.direction(),
+        history.history(),
+    );
+    if let (true, Some(translation), Ok(direction), Ok(history)) = recorded {
+        let (source_text, direction, translation) =
+            (request.text, direction.code(), translation.clone());
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = history.record(source_text, direction, translation).await {
+                tracing::warn!(error = %e, "Failed to record translation history");
+            }
+        });
+    }
+
+    Ok(response)
+}
+
+// The body of the translate command, separate from Tauri state so it can
+// run against any Translator
+async fn translate_request(
+    translator: &dyn Translator,
+    request: &TranslateRequest,
+) -> TranslateResponse {
     // Parse translation direction
-    let direction = match request.direction.as_str() {
-        "en-ja" => TranslationDirection::EnglishToJapanese,
-        "ja-en" => TranslationDirection::JapaneseToEnglish,
-        _ => {
-            return Ok(TranslateResponse {
+    let direction = match request.direction.direction() {
+        Ok(direction) => direction,
+        Err(e) => {
+            return TranslateResponse {
                 success: false,
                 translation: None,
-                error: Some(format!("Invalid translation direction: {}", request.direction)),
-            });
+                error: Some(e.to_string()),
+                truncated: false,
+                alternatives: Vec::new(),
+                confidence: None,
+                possibly_untranslated: false,
+                romaji: None,
+                furigana: None,
+                timings: None,
+                time_to_first_token_ms: None,
+            };
         }
     };
     
     // Perform translation
-    match state.0.translate(&request.text, direction).await {
-        Ok(translated_text) => Ok(TranslateResponse {
+    match translator
+        .translate(&request.text, direction, &request.options)
+        .await
+    {
+        Ok(translated) => TranslateResponse {
             success: true,
-            translation: Some(translated_text),
+            possibly_untranslated: quality::is_untranslated(&request.text, &translated.text),
+            translation: Some(translated.text),
             error: None,
-        }),
-        Err(e) => Ok(TranslateResponse {
-            success: false,
-            translation: None,
-            error: Some(format!("Translation failed: {}", e)),
-        }),
+            truncated: translated.truncated,
+            alternatives: translated.alternatives,
+            confidence: translated.confidence,
+            romaji: translated.romaji,
+            furigana: translated.furigana,
+            time_to_first_token_ms: translated
+                .timings
+                .as_ref()
+                .map(|timings| timings.time_to_first_token_ms),
+            timings: translated.timings,
+        },
+        Err(e) => {
+            // A timeout still hands back whatever was generated in time
+            let partial = match e.downcast_ref::<TranslationError>() {
+                Some(TranslationError::Timeout { partial, .. }) => Some(partial.clone()),
+                _ => None,
+            };
+
+            TranslateResponse {
+                success: false,
+                truncated: partial.is_some(),
+                translation: partial,
+                error: Some(format!("Translation failed: {}", e)),
+                alternatives: Vec::new(),
+                confidence: None,
+                possibly_untranslated: false,
+                romaji: None,
+                furigana: None,
+                timings: None,
+                time_to_first_token_ms: None,
+            }
+        }
     }
 }
 
 #[tauri::command]
-async fn get_model_status(state: State<'_, TranslationServiceState>) -> Result<ModelStatusResponse, String> {
-    let loaded = state.0.is_model_loaded().await;
-    Ok(ModelStatusResponse { loaded })
+async fn translate_dry_run(
+    text: String,
+    direction: DirectionSpec,
+    options: Option<TranslateOptions>,
+    state: State<'_, TranslationServiceState>,
+) -> Result<DryRun, String> {
+    let direction = direction.direction().map_err(|e| e.to_string())?;
+    state
+        .service()?
+        .translate_dry_run(&text, direction, &options.unwrap_or_default())
+        .await
+        .map_err(|e| format!("Failed to plan translation: {}", e))
+}
+
+#[tauri::command]
+async fn count_tokens(
+    text: String,
+    direction: DirectionSpec,
+    translator: State<'_, TranslatorState>,
+) -> Result<usize, String> {
+    let direction = direction.direction().map_err(|e| e.to_string())?;
+    translator
+        .translator()?
+        .count_tokens(&text, direction)
+        .await
+        .map_err(|e| format!("Failed to count tokens: {}", e))
+}
+
+#[tauri::command]
+async fn translate_file(
+    input_path: String,
+    output_path: Option<String>,
+    direction: DirectionSpec,
+    options: Option<TranslateOptions>,
+    state: State<'_, TranslationServiceState>,
+) -> Result<Option<String>, String> {
+    let direction = direction.direction().map_err(|e| e.to_string())?;
+    state
+        .service()?
+        .translate_file(
+            Path::new(&input_path),
+            output_path.as_deref().map(Path::new),
+            direction,
+            &options.unwrap_or_default(),
+        )
+        .await
+        .map_err(|e| format!("Failed to translate file: {}", e))
+}
+
+#[tauri::command]
+async fn translate_srt(
+    content: String,
+    direction: DirectionSpec,
+    options: Option<TranslateOptions>,
+    state: State<'_, TranslationServiceState>,
+) -> Result<String, String> {
+    let direction = direction.direction().map_err(|e| e.to_string())?;
+    state
+        .service()?
+        .translate_srt(&content, direction, &options.unwrap_or_default())
+        .await
+        .map_err(|e| format!("Failed to translate subtitles: {}", e))
+}
+
+#[tauri::command]
+async fn translate_json(
+    json: String,
+    direction: DirectionSpec,
+    keys: Option<Vec<String>>,
+    options: Option<TranslateOptions>,
+    state: State<'_, TranslationServiceState>,
+) -> Result<String, String> {
+    let direction = direction.direction().map_err(|e| e.to_string())?;
+    state
+        .service()?
+        .translate_json(&json, direction, keys.as_deref(), &options.unwrap_or_default())
+        .await
+        .map_err(|e| format!("Failed to translate JSON: {}", e))
+}
+
+#[tauri::command]
+async fn load_model_from_path(
+    path: String,
+    state: State<'_, TranslationServiceState>,
+) -> Result<(), String> {
+    state
+        .service()?
+        .load_model_from_path(Path::new(&path))
+        .await
+        .map_err(|e| format!("Failed to load model from path: {}", e))
+}
+
+#[tauri::command]
+async fn set_quantization(
+    quantization: String,
+    state: State<'_, TranslationServiceState>,
+) -> Result<(), String> {
+    let quantization = quantization.parse::<Quantization>().map_err(|e| e.to_string())?;
+    state
+        .service()?
+        .set_quantization(quantization)
+        .await
+        .map_err(|e| format!("Failed to switch quantization: {}", e))
+}
+
+#[tauri::command]
+async fn estimate_model_memory(
+    model_file: String,
+    state: State<'_, TranslationServiceState>,
+) -> Result<MemoryEstimate, String> {
+    state
+        .service()?
+        .estimate_model_memory(&model_file)
+        .await
+        .map_err(|e| format!("Failed to estimate model memory: {}", e))
+}
+
+#[tauri::command]
+async fn translate_csv(
+    content: String,
+    columns: Vec<usize>,
+    direction: DirectionSpec,
+    has_header: bool,
+    options: Option<TranslateOptions>,
+    state: State<'_, TranslationServiceState>,
+) -> Result<String, String> {
+    let direction = direction.direction().map_err(|e| e.to_string())?;
+    let options = options.unwrap_or_default();
+    state
+        .service()?
+        .translate_csv(&content, &columns, direction, has_header, &options)
+        .await
+        .map_err(|e| format!("Failed to translate CSV: {}", e))
+}
+
+#[tauri::command]
+fn create_session(state: State<'_, TranslationServiceState>) -> Result<u64, String> {
+    state
+        .service()?
+        .create_session()
+        .map_err(|e| format!("Failed to create session: {}", e))
+}
+
+#[tauri::command]
+async fn translate_in_session(
+    session_id: u64,
+    text: String,
+    direction: DirectionSpec,
+    options: Option<TranslateOptions>,
+    state: State<'_, TranslationServiceState>,
+) -> Result<String, String> {
+    let direction = direction.direction().map_err(|e| e.to_string())?;
+    state
+        .service()?
+        .translate_in_session(session_id, &text, direction, &options.unwrap_or_default())
+        .await
+        .map(|translation| translation.text)
+        .map_err(|e| format!("Translation failed: {}", e))
+}
+
+#[tauri::command]
+fn close_session(
+    session_id: u64,
+    state: State<'_, TranslationServiceState>,
+) -> Result<bool, String> {
+    Ok(state.service()?.close_session(session_id))
+}
+
+#[tauri::command]
+async fn round_trip(
+    text: String,
+    direction: DirectionSpec,
+    options: Option<TranslateOptions>,
+    state: State<'_, TranslationServiceState>,
+) -> Result<RoundTrip, String> {
+    let direction = direction.direction().map_err(|e| e.to_string())?;
+    state
+        .service()?
+        .round_trip(&text, direction, &options.unwrap_or_default())
+        .await
+        .map_err(|e| format!("Failed to round-trip translation: {}", e))
+}
+
+#[tauri::command]
+async fn get_model_status(translator: State<'_, TranslatorState>) -> Result<ModelStatusResponse, String> {
+    let translator = match translator.0.read().unwrap().clone() {
+        Ok(translator) => translator,
+        Err(error) => {
+            return Ok(ModelStatusResponse {
+                loaded: false,
+                phase: ModelPhase::Failed { error: error.clone() },
+                backend: "none".to_string(),
+                cpu_fallback: false,
+                quantization: None,
+                init_error: Some(error),
+            })
+        }
+    };
+    let loaded = translator.is_model_loaded().await;
+    let backend = translator.backend_info().await;
+    Ok(ModelStatusResponse {
+        loaded,
+        phase: translator.model_phase(),
+        backend: backend.backend,
+        cpu_fallback: backend.cpu_fallback,
+        quantization: translator.quantization(),
+        init_error: None,
+    })
+}
+
+#[tauri::command]
+fn get_version_info(state: State<'_, TranslationServiceState>) -> Result<VersionInfo, String> {
+    let service = state.service()?;
+    Ok(VersionInfo {
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        git_commit: option_env!("KONNYAKU_GIT_COMMIT").map(str::to_string),
+        llama_cpp_version: option_env!("KONNYAKU_LLAMA_CPP_VERSION").map(str::to_string),
+        model_repo: service.model_repo().to_string(),
+        model_file: service.model_file(),
+        quantization: service.quantization(),
+    })
+}
+
+#[tauri::command]
+fn get_readiness(state: State<'_, TranslationServiceState>) -> Readiness {
+    match state.service.read().unwrap().as_ref() {
+        Ok(service) => service.readiness(),
+        Err(error) => Readiness {
+            ready: false,
+            reason: Some(format!("init failed: {}", error)),
+        },
+    }
 }
 
 #[tauri::command]
-async fn ensure_model_downloaded(state: State<'_, TranslationServiceState>) -> Result<bool, String> {
-    match state.0.ensure_model_downloaded().await {
+async fn ensure_model_downloaded(translator: State<'_, TranslatorState>) -> Result<bool, String> {
+    match translator.translator()?.ensure_model_downloaded().await {
         Ok(_) => Ok(true),
         Err(e) => Err(format!("Failed to download model: {}", e)),
     }
 }
 
 #[tauri::command]
-async fn initialize_model(state: State<'_, TranslationServiceState>) -> Result<bool, String> {
-    match state.0.ensure_model_loaded().await {
+async fn initialize_model(translator: State<'_, TranslatorState>) -> Result<bool, String> {
+    match translator.translator()?.ensure_model_loaded().await {
         Ok(_) => Ok(true),
         Err(e) => Err(format!("Failed to initialize model: {}", e)),
     }
 }
 
+/// Why `prepare_model` failed
+#[derive(Debug, Serialize)]
+struct PrepareModelError {
+    /// `TranslationError::code` of the failure, "init_failed" when the
+    /// service never started, or "other"
+    code: &'static str,
+    message: String,
+}
+
+/// Download and load the model, returning once it can translate
+///
+/// Progress is reported through "model-phase-changed" events. Calling it
+/// again, or while another call is still running, doesn't download twice.
+#[tauri::command]
+async fn prepare_model(translator: State<'_, TranslatorState>) -> Result<(), PrepareModelError> {
+    let translator = translator.translator().map_err(|message| PrepareModelError {
+        code: "init_failed",
+        message,
+    })?;
+    translator.ensure_model_loaded().await.map_err(|e| PrepareModelError {
+        code: e.downcast_ref::<TranslationError>().map_or("other", TranslationError::code),
+        message: format!("Failed to prepare model: {}", e),
+    })
+}
+
+#[tauri::command]
+fn clear_translation_cache(state: State<'_, TranslationServiceState>) -> Result<(), String> {
+    state.service()?.clear_cache();
+    Ok(())
+}
+
+#[tauri::command]
+fn set_translation_cache_size(
+    size: usize,
+    state: State<'_, TranslationServiceState>,
+) -> Result<(), String> {
+    state.service()?.set_cache_size(size);
+    Ok(())
+}
+
+#[tauri::command]
+fn get_settings(state: State<'_, TranslationServiceState>) -> Result<Settings, String> {
+    Ok(state.service()?.settings())
+}
+
+#[tauri::command]
+fn reset_settings(state: State<'_, TranslationServiceState>) -> Result<Settings, String> {
+    state
+        .service()?
+        .reset_settings()
+        .map_err(|e| format!("Failed to reset settings: {}", e))
+}
+
+#[tauri::command]
+async fn get_history(
+    limit: Option<usize>,
+    history: State<'_, HistoryState>,
+) -> Result<Vec<HistoryEntry>, String> {
+    history
+        .history()?
+        .recent(limit.unwrap_or(100))
+        .await
+        .map_err(|e| format!("Failed to load history: {}", e))
+}
+
+#[tauri::command]
+async fn clear_history(history: State<'_, HistoryState>) -> Result<(), String> {
+    history
+        .history()?
+        .clear()
+        .await
+        .map_err(|e| format!("Failed to clear history: {}", e))
+}
+
+#[tauri::command]
+async fn export_history(path: String, history: State<'_, HistoryState>) -> Result<usize, String> {
+    history
+        .history()?
+        .export(Path::new(&path))
+        .await
+        .map_err(|e| format!("Failed to export history: {}", e))
+}
+
+#[tauri::command]
+async fn import_history(path: String, history: State<'_, HistoryState>) -> Result<usize, String> {
+    history
+        .history()?
+        .import(Path::new(&path))
+        .await
+        .map_err(|e| format!("Failed to import history: {}", e))
+}
+
+#[tauri::command]
+fn set_log_level(level: String, logging: State<'_, LoggingState>) -> Result<(), String> {
+    logging
+        .0
+        .set_level(&level)
+        .map_err(|e| format!("Failed to set log level: {}", e))
+}
+
+#[tauri::command]
+fn get_recent_logs(limit: Option<usize>, logging: State<'_, LoggingState>) -> Vec<LogLine> {
+    logging.0.recent(limit.unwrap_or(logging::RECENT_LOG_LINES))
+}
+
+#[tauri::command]
+async fn delete_history_entry(id: i64, history: State<'_, HistoryState>) -> Result<bool, String> {
+    history
+        .history()?
+        .delete(id)
+        .await
+        .map_err(|e| format!("Failed to delete history entry: {}", e))
+}
+
+#[tauri::command]
+async fn set_context_size(
+    context_size: u32,
+    state: State<'_, TranslationServiceState>,
+) -> Result<(), String> {
+    state
+        .service()?
+        .set_context_size(context_size)
+        .await
+        .map_err(|e| format!("Failed to set context size: {}", e))
+}
+
+#[tauri::command]
+async fn unload_model(state: State<'_, TranslationServiceState>) -> Result<(), String> {
+    state.service()?.unload_model().await;
+    Ok(())
+}
+
+#[tauri::command]
+fn set_idle_timeout(
+    idle_timeout_secs: u64,
+    state: State<'_, TranslationServiceState>,
+) -> Result<(), String> {
+    state.service()?.set_idle_timeout(idle_timeout_secs);
+    Ok(())
+}
+
+#[tauri::command]
+fn set_batch_size(n_batch: u32, state: State<'_, TranslationServiceState>) -> Result<(), String> {
+    state
+        .service()?
+        .set_batch_size(n_batch)
+        .map_err(|e| format!("Failed to set batch size: {}", e))
+}
+
+#[tauri::command]
+fn set_download_mirrors(
+    mirrors: Vec<String>,
+    state: State<'_, TranslationServiceState>,
+) -> Result<(), String> {
+    state
+        .service()?
+        .set_download_mirrors(mirrors)
+        .map_err(|e| format!("Failed to set download mirrors: {}", e))
+}
+
+#[tauri::command]
+fn set_output_prefixes(
+    prefixes: Vec<String>,
+    state: State<'_, TranslationServiceState>,
+) -> Result<(), String> {
+    state.service()?.set_output_prefixes(prefixes);
+    Ok(())
+}
+
+#[tauri::command]
+fn get_glossary(state: State<'_, TranslationServiceState>) -> Result<Vec<GlossaryEntry>, String> {
+    Ok(state.service()?.glossary())
+}
+
+#[tauri::command]
+async fn import_glossary(
+    path: String,
+    state: State<'_, TranslationServiceState>,
+) -> Result<GlossaryImport, String> {
+    state
+        .service()?
+        .import_glossary(Path::new(&path))
+        .await
+        .map_err(|e| format!("Failed to import glossary: {}", e))
+}
+
+#[tauri::command]
+async fn export_glossary(
+    path: String,
+    state: State<'_, TranslationServiceState>,
+) -> Result<usize, String> {
+    state
+        .service()?
+        .export_glossary(Path::new(&path))
+        .await
+        .map_err(|e| format!("Failed to export glossary: {}", e))
+}
+
+#[tauri::command]
+fn set_download_timeouts(
+    connect_secs: u64,
+    total_secs: u64,
+    state: State<'_, TranslationServiceState>,
+) -> Result<(), String> {
+    state
+        .service()?
+        .set_download_timeouts(connect_secs, total_secs)
+        .map_err(|e| format!("Failed to set download timeouts: {}", e))
+}
+
+#[tauri::command]
+fn set_max_input_chars(
+    max_input_chars: usize,
+    state: State<'_, TranslationServiceState>,
+) -> Result<(), String> {
+    state
+        .service()?
+        .set_max_input_chars(max_input_chars)
+        .map_err(|e| format!("Failed to set input length limit: {}", e))
+}
+
+#[tauri::command]
+fn set_flash_attention(
+    enabled: bool,
+    state: State<'_, TranslationServiceState>,
+) -> Result<(), String> {
+    state.service()?.set_flash_attention(enabled);
+    Ok(())
+}
+
+#[tauri::command]
+fn set_offline(offline: bool, state: State<'_, TranslationServiceState>) -> Result<(), String> {
+    state.service()?.set_offline(offline);
+    Ok(())
+}
+
+#[tauri::command]
+fn set_default_system_prompt(
+    direction: DirectionSpec,
+    prompt: Option<String>,
+    state: State<'_, TranslationServiceState>,
+) -> Result<(), String> {
+    let direction = direction.direction().map_err(|e| e.to_string())?;
+    state
+        .service()?
+        .set_default_system_prompt(direction, prompt)
+        .map_err(|e| format!("Failed to set system prompt: {}", e))
+}
+
+#[tauri::command]
+fn set_default_preset(
+    direction: DirectionSpec,
+    preset: Option<String>,
+    state: State<'_, TranslationServiceState>,
+) -> Result<(), String> {
+    let direction = direction.direction().map_err(|e| e.to_string())?;
+    let preset = preset
+        .map(|name| name.parse::<SamplingPreset>())
+        .transpose()
+        .map_err(|e| e.to_string())?;
+    state.service()?.set_default_preset(direction, preset);
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_backend_info(state: State<'_, TranslationServiceState>) -> Result<BackendInfo, String> {
+    Ok(state.service()?.backend_info().await)
+}
+
+#[tauri::command]
+async fn benchmark(
+    iterations: usize,
+    state: State<'_, TranslationServiceState>,
+) -> Result<Benchmark, String> {
+    state
+        .service()?
+        .benchmark(iterations)
+        .await
+        .map_err(|e| format!("Benchmark failed: {}", e))
+}
+
+#[tauri::command]
+async fn get_memory_stats(state: State<'_, TranslationServiceState>) -> Result<MemoryStats, String> {
+    Ok(state.service()?.memory_stats().await)
+}
+
+#[tauri::command]
+async fn get_model_metadata(
+    state: State<'_, TranslationServiceState>,
+) -> Result<ModelMetadata, String> {
+    state
+        .service()?
+        .model_metadata()
+        .await
+        .map_err(|e| format!("Failed to read model metadata: {}", e))
+}
+
+#[tauri::command]
+async fn apply_lora(
+    path_or_repo: String,
+    scale: f32,
+    state: State<'_, TranslationServiceState>,
+) -> Result<(), String> {
+    state
+        .service()?
+        .apply_lora(&path_or_repo, scale)
+        .await
+        .map_err(|e| format!("Failed to apply LoRA adapter: {}", e))
+}
+
+#[tauri::command]
+async fn clear_lora(state: State<'_, TranslationServiceState>) -> Result<bool, String> {
+    Ok(state.service()?.clear_lora().await)
+}
+
+#[tauri::command]
+async fn set_draft_model(
+    repo: String,
+    file: String,
+    state: State<'_, TranslationServiceState>,
+) -> Result<(), String> {
+    state
+        .service()?
+        .set_draft_model(&repo, &file)
+        .await
+        .map_err(|e| format!("Failed to set draft model: {}", e))
+}
+
+#[tauri::command]
+async fn clear_draft_model(state: State<'_, TranslationServiceState>) -> Result<bool, String> {
+    Ok(state.service()?.clear_draft_model().await)
+}
+
+#[tauri::command]
+async fn health_check(state: State<'_, TranslationServiceState>) -> Result<HealthReport, String> {
+    let service = match state.service.read().unwrap().clone() {
+        Ok(service) => service,
+        Err(error) => {
+            return Ok(HealthReport {
+                model_downloaded: false,
+                model_loaded: false,
+                cache_dir: String::new(),
+                cache_dir_writable: false,
+                free_disk_bytes: None,
+                backend: "none".to_string(),
+                num_threads: 0,
+                test_translation: None,
+                errors: vec![format!("init: {}", error)],
+            });
+        }
+    };
+    Ok(service.health_check().await)
+}
+
+#[tauri::command]
+async fn get_max_context_length(state: State<'_, TranslationServiceState>) -> Result<u32, String> {
+    state
+        .service()?
+        .max_context_length()
+        .await
+        .map_err(|e| format!("Failed to get max context length: {}", e))
+}
+
+#[tauri::command]
+async fn get_model_cache_info(
+    state: State<'_, TranslationServiceState>,
+) -> Result<ModelCacheInfo, String> {
+    Ok(state.service()?.model_cache_info().await)
+}
+
+/// Payload of the "model-download-finished" event
+#[derive(Debug, Clone, Serialize)]
+struct DownloadFinished {
+    success: bool,
+    error: Option<String>,
+}
+
+/// Download the model in the background, returning false if it is already
+/// on disk or being downloaded
+///
+/// A "model-download-finished" event reports how it ended. Translations
+/// started meanwhile wait for this download instead of starting another.
+#[tauri::command]
+fn start_background_download(
+    app: AppHandle,
+    state: State<'_, TranslationServiceState>,
+) -> Result<bool, String> {
+    let service = state.service()?;
+    if service.is_model_downloaded() {
+        return Ok(false);
+    }
+    // Claimed before spawning, so a second call can't start another
+    let Some(claim) = service.claim_download() else {
+        return Ok(false);
+    };
+
+    tauri::async_runtime::spawn(async move {
+        let error = service.ensure_model_downloaded().await.err().map(|e| e.to_string());
+        drop(claim);
+        let finished = DownloadFinished {
+            success: error.is_none(),
+            error,
+        };
+        if let Err(e) = app.emit("model-download-finished", finished) {
+            tracing::warn!(error = %e, "Failed to emit download completion");
+        }
+    });
+    Ok(true)
+}
+
+#[tauri::command]
+fn get_download_progress(
+    state: State<'_, TranslationServiceState>,
+) -> Result<DownloadProgress, String> {
+    Ok(state.service()?.download_progress())
+}
+
+#[tauri::command]
+fn cancel_download(state: State<'_, TranslationServiceState>) -> Result<bool, String> {
+    Ok(state.service()?.cancel_download())
+}
+
+#[tauri::command]
+async fn list_cached_models(
+    state: State<'_, TranslationServiceState>,
+) -> Result<Vec<CachedModel>, String> {
+    state
+        .service()?
+        .list_cached_models()
+        .await
+        .map_err(|e| format!("Failed to list cached models: {}", e))
+}
+
+#[tauri::command]
+async fn delete_cached_model(state: State<'_, TranslationServiceState>) -> Result<(), String> {
+    state
+        .service()?
+        .delete_cached_model()
+        .await
+        .map_err(|e| format!("Failed to delete cached model: {}", e))
+}
+
+#[tauri::command]
+async fn repair_model(state: State<'_, TranslationServiceState>) -> Result<ModelRepair, String> {
+    state
+        .service()?
+        .repair_model()
+        .await
+        .map_err(|e| format!("Failed to repair model: {}", e))
+}
+
+/// Create the translation service again after it failed to initialize, e.g.
+/// on a transient GPU driver problem
+///
+/// Does nothing once the service is running. Fails with the new error when
+/// initializing fails again, and while another retry is still running.
+#[tauri::command]
+async fn retry_init(
+    app: AppHandle,
+    state: State<'_, TranslationServiceState>,
+    translator: State<'_, TranslatorState>,
+) -> Result<(), String> {
+    let Ok(_retrying) = state.retrying.try_lock() else {
+        return Err("Initialization is already being retried".to_string());
+    };
+    if state.service().is_ok() {
+        return Ok(());
+    }
+
+    // Backend and settings setup block, keep them off the async workers
+    let service = tokio::task::spawn_blocking(init_service)
+        .await
+        .map_err(|e| format!("Failed to initialize translation service: {}", e))?;
+    publish_service(&state, &translator, service.clone());
+    let service =
+        service.map_err(|e| format!("Failed to initialize translation service: {}", e))?;
+    start_service(&app, &service);
+    tracing::info!("Translation service initialized on retry");
+    Ok(())
+}
+
+#[tauri::command]
+fn detect_language(text: String) -> String {
+    language::detect_language(&text).to_string()
+}
+
 #[tauri::command]
 fn get_supported_languages() -> Vec<String> {
-    vec!["en-ja".to_string(), "ja-en".to_string()]
+    translation::supported_pairs()
+        .iter()
+        .map(LanguagePair::code)
+        .collect()
+}
+
+#[tauri::command]
+fn get_language_pairs() -> Vec<LanguagePairInfo> {
+    translation::supported_pairs()
+        .iter()
+        .map(LanguagePair::info)
+        .collect()
 }
 
 // Legacy greet command (can be removed later)
@@ -92,28 +1013,269 @@ fn greet(name: &str) -> String {
     format!("Hello, {}! You've been greeted from Rust!", name)
 }
 
+// Create the translation service, logging why it failed if it did
+fn init_service() -> Result<Arc<TranslationService>, String> {
+    TranslationService::new().map(Arc::new).map_err(|e| {
+        tracing::error!(error = %e, "Failed to initialize translation service");
+        format!("{:#}", e)
+    })
+}
+
+// Hand the outcome of initializing to both states, so commands going
+// through either get the same service or report the same error
+fn publish_service(
+    state: &TranslationServiceState,
+    translator: &TranslatorState,
+    service: Result<Arc<TranslationService>, String>,
+) {
+    *translator.0.write().unwrap() = service
+        .clone()
+        .map(|service| service as Arc<dyn Translator>);
+    *state.service.write().unwrap() = service;
+}
+
+// Start the work a new service does in the background
+fn start_service(app: &AppHandle, service: &Arc<TranslationService>) {
+    #[cfg(feature = "bundled-model")]
+    register_bundled_model(app, service);
+    emit_model_phase_changes(app.clone(), service);
+
+    // Catch a damaged model before the first translation trips over it
+    tauri::async_runtime::spawn({
+        let service = Arc::clone(service);
+        async move { service.check_cached_model().await }
+    });
+
+    // Free the model's memory when the app sits idle
+    tauri::async_runtime::spawn(Arc::clone(service).unload_when_idle());
+}
+
+// Point the service at a model shipped in the app's resources, if there is one
+#[cfg(feature = "bundled-model")]
+fn register_bundled_model(app: &AppHandle, service: &TranslationService) {
+    use tauri::path::BaseDirectory;
+    use tauri::Manager;
+
+    let resource = format!("models/{}", service.model_file());
+    match app.path().resolve(resource, BaseDirectory::Resource) {
+        Ok(path) if path.is_file() => service.set_bundled_model(path),
+        Ok(path) => tracing::warn!(path = ?path, "Bundled model not found, it will be downloaded"),
+        Err(e) => tracing::warn!(error = %e, "Failed to resolve bundled model path"),
+    }
+}
+
+// Forward every model phase change to the frontend as a
+// `model-phase-changed` event
+fn emit_model_phase_changes(app: AppHandle, service: &TranslationService) {
+    let mut phases = service.subscribe_model_phase();
+    tauri::async_runtime::spawn(async move {
+        while phases.changed().await.is_ok() {
+            let phase = phases.borrow_and_update().clone();
+            if let Err(e) = app.emit("model-phase-changed", phase) {
+                tracing::warn!(error = %e, "Failed to emit model phase change");
+            }
+        }
+    });
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    // Initialize the translation service
-    let translation_service = match TranslationService::new() {
-        Ok(service) => Arc::new(service),
-        Err(e) => {
-            eprintln!("Failed to initialize translation service: {}", e);
-            panic!("Cannot start application without translation service");
-        }
+    // Log level is controlled by RUST_LOG, e.g. RUST_LOG=konnyaku_lib=debug,
+    // and later by set_log_level
+    let logging = match Logging::init() {
+        Ok(logging) => logging,
+        Err(e) => panic!("Cannot start application without logging: {:#}", e),
     };
-    
+
+    // Initialize the translation service. Without one the app still starts,
+    // so the window can report the error and offer retry_init.
+    let translation_service = init_service();
+
+    // Without history translations just aren't recorded, and the history
+    // commands report why
+    let history = TranslationHistory::new().map(Arc::new).map_err(|e| {
+        tracing::error!(error = %e, "Failed to initialize translation history");
+        format!("{:#}", e)
+    });
+
+    let translator = translation_service
+        .clone()
+        .map(|service| service as Arc<dyn Translator>);
+
+    let setup_service = translation_service.clone();
+
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        .manage(TranslationServiceState(translation_service))
+        .setup(move |app| {
+            if let Ok(service) = &setup_service {
+                start_service(app.handle(), service);
+            }
+            Ok(())
+        })
+        .manage(TranslatorState(std::sync::RwLock::new(translator)))
+        .manage(TranslationServiceState::new(translation_service))
+        .manage(HistoryState(history))
+        .manage(LoggingState(logging))
         .invoke_handler(tauri::generate_handler![
             greet,
             translate,
+            count_tokens,
+            translate_dry_run,
+            translate_file,
+            translate_srt,
+            translate_json,
+            translate_csv,
+            estimate_model_memory,
+            set_quantization,
+            load_model_from_path,
+            create_session,
+            translate_in_session,
+            close_session,
+            round_trip,
             get_model_status,
+            get_readiness,
+            get_version_info,
             ensure_model_downloaded,
             initialize_model,
+            prepare_model,
+            unload_model,
+            set_idle_timeout,
+            set_context_size,
+            set_batch_size,
+            set_flash_attention,
+            set_max_input_chars,
+            set_download_timeouts,
+            set_output_prefixes,
+            get_glossary,
+            import_glossary,
+            export_glossary,
+            set_download_mirrors,
+            set_default_system_prompt,
+            set_default_preset,
+            set_offline,
+            get_model_cache_info,
+            list_cached_models,
+            start_background_download,
+            get_download_progress,
+            cancel_download,
+            get_backend_info,
+            get_memory_stats,
+            health_check,
+            benchmark,
+            get_model_metadata,
+            get_max_context_length,
+            apply_lora,
+            clear_lora,
+            set_draft_model,
+            clear_draft_model,
+            retry_init,
+            delete_cached_model,
+            repair_model,
             get_supported_languages,
+            get_language_pairs,
+            detect_language,
+            clear_translation_cache,
+            set_translation_cache_size,
+            get_settings,
+            reset_settings,
+            get_history,
+            clear_history,
+            set_log_level,
+            get_recent_logs,
+            export_history,
+            import_history,
+            delete_history_entry,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use translator::MockTranslator;
+
+    fn request(text: &str, direction: &str) -> TranslateRequest {
+        TranslateRequest {
+            text: text.to_string(),
+            direction: DirectionSpec::Code(direction.to_string()),
+            options: TranslateOptions::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn translations_are_returned() {
+        let translator = MockTranslator {
+            respond: |_| Ok(MockTranslator::translation("こんにちは")),
+        };
+        let response = translate_request(&translator, &request("Hello", "en-ja")).await;
+        assert!(response.success);
+        assert_eq!(response.translation.as_deref(), Some("こんにちは"));
+        assert_eq!(response.error, None);
+        assert!(!response.truncated);
+        assert!(!response.possibly_untranslated);
+
+        let echo = MockTranslator {
+            respond: |text| Ok(MockTranslator::translation(text)),
+        };
+        let response = translate_request(&echo, &request("Hello there", "en-ja")).await;
+        assert!(response.possibly_untranslated);
+    }
+
+    #[tokio::test]
+    async fn invalid_directions_are_reported() {
+        let translator = MockTranslator {
+            respond: |_| panic!("nothing should be translated"),
+        };
+        let response = translate_request(&translator, &request("Hello", "en-fr")).await;
+        assert!(!response.success);
+        assert_eq!(response.translation, None);
+        assert!(response.error.unwrap().contains("en-fr"));
+    }
+
+    #[tokio::test]
+    async fn timeouts_keep_the_partial_translation() {
+        let translator = MockTranslator {
+            respond: |_| {
+                Err(TranslationError::Timeout {
+                    timeout_ms: 100,
+                    partial: "こんに".to_string(),
+                }
+                .into())
+            },
+        };
+        let response = translate_request(&translator, &request("Hello", "en-ja")).await;
+        assert!(!response.success);
+        assert!(response.truncated);
+        assert_eq!(response.translation.as_deref(), Some("こんに"));
+        assert!(response.error.unwrap().contains("timed out"));
+
+        let failing = MockTranslator {
+            respond: |_| Err(anyhow::anyhow!("Model not loaded")),
+        };
+        let response = translate_request(&failing, &request("Hello", "en-ja")).await;
+        assert!(!response.success);
+        assert!(!response.truncated);
+        assert_eq!(response.translation, None);
+    }
+
+    #[test]
+    fn failed_retries_reach_both_states() {
+        let state = TranslationServiceState::new(Err("no GPU".to_string()));
+        let translator = TranslatorState(std::sync::RwLock::new(Err("no GPU".to_string())));
+        publish_service(&state, &translator, Err("driver crashed".to_string()));
+
+        let expected = unavailable("driver crashed".to_string());
+        assert_eq!(state.service().err(), Some(expected.clone()));
+        assert_eq!(translator.translator().err(), Some(expected));
+    }
+
+    #[test]
+    fn history_commands_report_a_failed_history() {
+        let history = HistoryState(Err("readonly database".to_string()));
+        assert_eq!(
+            history.history().err().as_deref(),
+            Some("Translation history is unavailable: readonly database")
+        );
+    }
+}
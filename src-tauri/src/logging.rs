@@ -0,0 +1,146 @@
+// Logging setup: a filter that can be changed at runtime and a buffer of
+// recent lines for bug reports.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context as LayerContext, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+/// Lines kept for `recent`
+pub const RECENT_LOG_LINES: usize = 1000;
+
+/// Levels `set_level` accepts, most to least severe
+pub const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+/// One logged event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogLine {
+    /// Milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub level: String,
+    /// The module that logged it, e.g. "konnyaku_lib::translation"
+    pub target: String,
+    /// The message followed by the event's other fields as `key=value`
+    pub message: String,
+}
+
+/// Handle to the installed logger
+pub struct Logging {
+    filter: reload::Handle<EnvFilter, Registry>,
+    recent: Arc<Mutex<VecDeque<LogLine>>>,
+}
+
+impl Logging {
+    /// Install the global logger
+    ///
+    /// The level starts from RUST_LOG, e.g. RUST_LOG=konnyaku_lib=debug, and
+    /// is info without it.
+    pub fn init() -> Result<Self> {
+        let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+        let (filter, handle) = reload::Layer::new(filter);
+        let recent = Arc::new(Mutex::new(VecDeque::with_capacity(RECENT_LOG_LINES)));
+
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(fmt::layer())
+            .with(RecentLines(Arc::clone(&recent)))
+            .try_init()
+            .context("Failed to install logger")?;
+
+        Ok(Self {
+            filter: handle,
+            recent,
+        })
+    }
+
+    /// Log at `level` and above from now on, one of `LOG_LEVELS`
+    ///
+    /// This replaces any per-module filter from RUST_LOG.
+    pub fn set_level(&self, level: &str) -> Result<()> {
+        let level = level.trim().to_ascii_lowercase();
+        if !LOG_LEVELS.contains(&level.as_str()) {
+            anyhow::bail!(
+                "Unknown log level: {}, expected one of {}",
+                level,
+                LOG_LEVELS.join(", ")
+            );
+        }
+        self.filter
+            .reload(EnvFilter::new(&level))
+            .context("Failed to change log level")?;
+        tracing::info!(level = %level, "Log level changed");
+        Ok(())
+    }
+
+    /// The filter currently applied, e.g. "debug"
+    pub fn level(&self) -> String {
+        self.filter
+            .with_current(|filter| filter.to_string())
+            .unwrap_or_default()
+    }
+
+    /// The last `limit` lines logged, oldest first
+    pub fn recent(&self, limit: usize) -> Vec<LogLine> {
+        let recent = self.recent.lock().unwrap();
+        let skip = recent.len().saturating_sub(limit);
+        recent.iter().skip(skip).cloned().collect()
+    }
+}
+
+// Keeps the last RECENT_LOG_LINES events that passed the filter
+struct RecentLines(Arc<Mutex<VecDeque<LogLine>>>);
+
+impl<S: Subscriber> Layer<S> for RecentLines {
+    fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0);
+        let metadata = event.metadata();
+        let line = LogLine {
+            timestamp_ms,
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message: visitor.message + &visitor.fields,
+        };
+
+        let mut recent = self.0.lock().unwrap();
+        if recent.len() == RECENT_LOG_LINES {
+            recent.pop_front();
+        }
+        recent.push_back(line);
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
@@ -0,0 +1,373 @@
+// Markdown protection for translation.
+//
+// Code (inline spans and fenced blocks) and link destinations are swapped out
+// for opaque placeholders before the text reaches the model, so only the prose
+// gets translated. The placeholders are plain ASCII markers (`@@0@@`) that the
+// model copies through verbatim rather than translating or reordering.
+
+/// Text with its untranslatable fragments replaced by placeholders
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtectedMarkdown {
+    /// The prose to send to the model, containing placeholders
+    pub text: String,
+    /// Original fragments, indexed by placeholder number
+    pub fragments: Vec<String>,
+}
+
+impl ProtectedMarkdown {
+    /// Returns true if there is nothing left to translate once the
+    /// placeholders are ignored (e.g. the input was a single code block)
+    pub fn is_only_placeholders(&self) -> bool {
+        !has_prose(&self.text)
+    }
+}
+
+/// Whether `text` has anything to translate besides placeholders
+pub fn has_prose(text: &str) -> bool {
+    let mut rest = text;
+    let mut stripped = String::new();
+    while let Some((start, end, _)) = find_placeholder(rest) {
+        stripped.push_str(&rest[..start]);
+        rest = &rest[end..];
+    }
+    stripped.push_str(rest);
+    stripped.chars().any(char::is_alphanumeric)
+}
+
+/// Replace code spans, fenced code blocks and link URLs with placeholders
+pub fn protect(input: &str) -> ProtectedMarkdown {
+    let mut fragments = Vec::new();
+    let mut text = String::with_capacity(input.len());
+    let mut prose = String::new();
+    let mut lines = input.split_inclusive('\n');
+
+    while let Some(line) = lines.next() {
+        let Some(fence) = opening_fence(line) else {
+            prose.push_str(line);
+            continue;
+        };
+
+        // Flush the prose collected so far before starting the block
+        protect_inline(&prose, &mut text, &mut fragments);
+        prose.clear();
+
+        let mut block = line.to_string();
+        for next in lines.by_ref() {
+            block.push_str(next);
+            if is_closing_fence(next, fence) {
+                break;
+            }
+        }
+
+        // Keep the trailing newline outside the placeholder so the
+        // surrounding line structure survives translation
+        let trailing_newline = block.ends_with('\n');
+        if trailing_newline {
+            block.pop();
+        }
+        text.push_str(&placeholder(fragments.len()));
+        fragments.push(block);
+        if trailing_newline {
+            text.push('\n');
+        }
+    }
+    protect_inline(&prose, &mut text, &mut fragments);
+
+    ProtectedMarkdown { text, fragments }
+}
+
+/// Put the original fragments back in place of their placeholders
+///
+/// Placeholders the model dropped are appended at the end so that no code or
+/// URL is ever lost, even if it ends up out of position.
+pub fn restore(translated: &str, fragments: &[String]) -> String {
+    let mut output = String::with_capacity(translated.len());
+    let mut used = vec![false; fragments.len()];
+    let mut rest = translated;
+
+    while let Some((start, end, index)) = find_placeholder(rest) {
+        output.push_str(&rest[..start]);
+        match fragments.get(index) {
+            Some(fragment) => {
+                output.push_str(fragment);
+                used[index] = true;
+            }
+            // Not one of ours, leave it untouched
+            None => output.push_str(&rest[start..end]),
+        }
+        rest = &rest[end..];
+    }
+    output.push_str(rest);
+
+    for (fragment, _) in fragments.iter().zip(&used).filter(|(_, used)| !**used) {
+        let separator = if fragment.contains('\n') { "\n" } else { " " };
+        if !output.is_empty() && !output.ends_with(char::is_whitespace) {
+            output.push_str(separator);
+        }
+        output.push_str(fragment);
+    }
+
+    output
+}
+
+fn placeholder(index: usize) -> String {
+    format!("@@{}@@", index)
+}
+
+/// Find the first placeholder in `text`, returning its byte range and index
+///
+/// Matching is lenient about the full-width forms (`＠＠０＠＠`) and stray
+/// spaces the model sometimes introduces when writing Japanese.
+fn find_placeholder(text: &str) -> Option<(usize, usize, usize)> {
+    let is_at = |c: char| c == '@' || c == '＠';
+    let mut search_from = 0;
+
+    while let Some(offset) = text[search_from..].find(is_at) {
+        let start = search_from + offset;
+        if let Some((end, index)) = parse_placeholder(&text[start..]) {
+            return Some((start, start + end, index));
+        }
+        search_from = start + text[start..].chars().next().map_or(1, char::len_utf8);
+    }
+
+    None
+}
+
+fn parse_placeholder(text: &str) -> Option<(usize, usize)> {
+    let is_at = |c: char| c == '@' || c == '＠';
+    let mut chars = text.char_indices().peekable();
+
+    for _ in 0..2 {
+        match chars.next() {
+            Some((_, c)) if is_at(c) => {}
+            _ => return None,
+        }
+    }
+    while chars.next_if(|(_, c)| *c == ' ').is_some() {}
+
+    let mut index = 0usize;
+    let mut digits = 0;
+    while let Some((_, c)) = chars.next_if(|(_, c)| placeholder_digit(*c).is_some()) {
+        let digit = placeholder_digit(c)?;
+        index = index.checked_mul(10)?.checked_add(digit as usize)?;
+        digits += 1;
+    }
+    if digits == 0 {
+        return None;
+    }
+    while chars.next_if(|(_, c)| *c == ' ').is_some() {}
+
+    for _ in 0..2 {
+        match chars.next() {
+            Some((_, c)) if is_at(c) => {}
+            _ => return None,
+        }
+    }
+
+    let end = chars.peek().map_or(text.len(), |(i, _)| *i);
+    Some((end, index))
+}
+
+fn placeholder_digit(c: char) -> Option<u32> {
+    match c {
+        '0'..='9' => c.to_digit(10),
+        '０'..='９' => Some(c as u32 - '０' as u32),
+        _ => None,
+    }
+}
+
+/// The fence character and length if `line` opens a fenced code block
+fn opening_fence(line: &str) -> Option<(char, usize)> {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+
+    let fence_char = trimmed.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = trimmed.chars().take_while(|c| *c == fence_char).count();
+    if len < 3 {
+        return None;
+    }
+
+    // Backtick fences can't have backticks in their info string
+    if fence_char == '`' && trimmed[len..].contains('`') {
+        return None;
+    }
+
+    Some((fence_char, len))
+}
+
+fn is_closing_fence(line: &str, (fence_char, fence_len): (char, usize)) -> bool {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return false;
+    }
+
+    let len = trimmed.chars().take_while(|c| *c == fence_char).count();
+    len >= fence_len && trimmed[len..].trim().is_empty()
+}
+
+/// Protect inline code spans and link destinations within a run of prose
+fn protect_inline(input: &str, output: &mut String, fragments: &mut Vec<String>) {
+    let mut i = 0;
+
+    while i < input.len() {
+        let rest = &input[i..];
+
+        if rest.starts_with('`') {
+            if let Some(len) = code_span_len(rest) {
+                output.push_str(&placeholder(fragments.len()));
+                fragments.push(rest[..len].to_string());
+                i += len;
+                continue;
+            }
+
+            // An unmatched run of backticks is literal text
+            let run = rest.chars().take_while(|c| *c == '`').count();
+            output.push_str(&rest[..run]);
+            i += run;
+            continue;
+        }
+
+        if rest.starts_with('[') {
+            if let Some((text_end, dest_end)) = link_bounds(rest) {
+                // Translate the link text, keep the destination verbatim
+                output.push('[');
+                protect_inline(&rest[1..text_end], output, fragments);
+                output.push(']');
+                output.push_str(&placeholder(fragments.len()));
+                fragments.push(rest[text_end + 1..dest_end].to_string());
+                i += dest_end;
+                continue;
+            }
+        }
+
+        let c = rest.chars().next().unwrap_or_default();
+        output.push(c);
+        i += c.len_utf8();
+    }
+}
+
+/// Length in bytes of the code span starting at the beginning of `text`
+fn code_span_len(text: &str) -> Option<usize> {
+    let run = text.chars().take_while(|c| *c == '`').count();
+    let mut search_from = run;
+
+    while let Some(offset) = text[search_from..].find('`') {
+        let start = search_from + offset;
+        let close = text[start..].chars().take_while(|c| *c == '`').count();
+        if close == run {
+            return Some(start + close);
+        }
+        search_from = start + close;
+    }
+
+    None
+}
+
+/// For `[text](url)` at the start of `text`, the byte offsets of the closing
+/// `]` and of the end of the `(url)` part
+fn link_bounds(text: &str) -> Option<(usize, usize)> {
+    let text_end = matching_close(text, '[', ']')?;
+    let after = &text[text_end + 1..];
+    if !after.starts_with('(') {
+        return None;
+    }
+
+    let dest_len = matching_close(after, '(', ')')?;
+    Some((text_end, text_end + 1 + dest_len + 1))
+}
+
+/// Byte offset of the bracket closing the one at the start of `text`
+fn matching_close(text: &str, open: char, close: char) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            '\n' if open == '(' => return None,
+            c if c == open => depth += 1,
+            c if c == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fenced_blocks_become_one_placeholder() {
+        let input = "Intro text\n```rust\nfn main() {}\n```\nOutro text\n";
+        let protected = protect(input);
+        assert_eq!(protected.text, "Intro text\n@@0@@\nOutro text\n");
+        assert_eq!(protected.fragments, vec!["```rust\nfn main() {}\n```"]);
+        assert_eq!(restore(&protected.text, &protected.fragments), input);
+
+        // An unclosed fence runs to the end of the text
+        let unclosed = protect("~~~\ncode\nmore");
+        assert_eq!(unclosed.text, "@@0@@");
+        assert!(unclosed.is_only_placeholders());
+    }
+
+    #[test]
+    fn code_spans_are_protected() {
+        let protected = protect("Run `cargo build` then ``a ` b``.");
+        assert_eq!(protected.text, "Run @@0@@ then @@1@@.");
+        assert_eq!(protected.fragments, vec!["`cargo build`", "``a ` b``"]);
+        assert_eq!(
+            restore("@@0@@を実行してから@@1@@。", &protected.fragments),
+            "`cargo build`を実行してから``a ` b``。"
+        );
+
+        // An unmatched backtick is just text
+        assert_eq!(protect("a `b").text, "a `b");
+    }
+
+    #[test]
+    fn link_urls_are_protected_but_not_their_text() {
+        let protected = protect("See [the `docs`](https://example.com/a_(b)) now.");
+        assert_eq!(protected.text, "See [the @@0@@]@@1@@ now.");
+        assert_eq!(
+            protected.fragments,
+            vec!["`docs`", "(https://example.com/a_(b))"]
+        );
+        assert!(!protected.is_only_placeholders());
+
+        // Brackets without a destination aren't links
+        assert_eq!(protect("[not a link] (x)").text, "[not a link] (x)");
+    }
+
+    #[test]
+    fn dropped_placeholders_are_appended() {
+        let protected = protect("Use `x` at [home](https://example.com).");
+        assert_eq!(
+            restore("[ホーム]@@1@@で使う。", &protected.fragments),
+            "[ホーム](https://example.com)で使う。 `x`"
+        );
+
+        let block = vec!["```\ncode\n```".to_string()];
+        assert_eq!(restore("説明", &block), "説明\n```\ncode\n```");
+    }
+
+    #[test]
+    fn full_width_and_spaced_placeholders_are_restored() {
+        let fragments = vec!["`a`".to_string(), "`b`".to_string()];
+        assert_eq!(restore("＠＠０＠＠と@@ 1 @@", &fragments), "`a`と`b`");
+        // Placeholders that aren't ours are left alone
+        assert_eq!(restore("@@7@@ @@", &fragments), "@@7@@ @@ `a` `b`");
+    }
+}
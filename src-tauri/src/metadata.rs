@@ -0,0 +1,108 @@
+use llama_cpp_2::model::{LlamaChatMessage, LlamaModel};
+use serde::{Deserialize, Serialize};
+
+/// Identifying details from the loaded model's GGUF header
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelMetadata {
+    /// `general.name`, if the file sets it
+    pub name: Option<String>,
+    /// `general.architecture`, e.g. "lfm2"
+    pub architecture: Option<String>,
+    pub parameter_count: u64,
+    /// Quantization from `general.file_type`, e.g. "Q4_K_M"
+    pub quantization: Option<String>,
+    /// Context length the model was trained with, in tokens
+    pub trained_context_length: u32,
+    /// How prompts are laid out for this model
+    pub prompt_format: PromptFormat,
+}
+
+/// The chat format prompts are built with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptFormat {
+    /// The chat template stored in the GGUF file
+    ModelTemplate,
+    /// ChatML, for models without a template or with one llama.cpp can't
+    /// apply
+    Chatml,
+}
+
+impl PromptFormat {
+    /// The format used for `model`: its own template when llama.cpp can
+    /// apply it
+    pub fn of(model: &LlamaModel) -> Self {
+        let Ok(template) = model.chat_template(None) else {
+            return Self::Chatml;
+        };
+        let applies = LlamaChatMessage::new("user".to_string(), "test".to_string())
+            .is_ok_and(|probe| model.apply_chat_template(&template, &[probe], true).is_ok());
+        if applies {
+            Self::ModelTemplate
+        } else {
+            Self::Chatml
+        }
+    }
+}
+
+impl ModelMetadata {
+    /// Read the metadata of a loaded model
+    pub fn read(model: &LlamaModel) -> Self {
+        let meta = |key: &str| model.meta_val_str(key).ok().filter(|v| !v.is_empty());
+
+        Self {
+            name: meta("general.name"),
+            architecture: meta("general.architecture"),
+            parameter_count: model.n_params(),
+            quantization: meta("general.file_type").map(|file_type| {
+                file_type
+                    .parse()
+                    .ok()
+                    .and_then(file_type_name)
+                    .map_or(file_type, str::to_string)
+            }),
+            trained_context_length: model.n_ctx_train(),
+            prompt_format: PromptFormat::of(model),
+        }
+    }
+}
+
+/// Name of a llama.cpp `llama_ftype` value
+fn file_type_name(file_type: u32) -> Option<&'static str> {
+    let name = match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        36 => "TQ1_0",
+        37 => "TQ2_0",
+        _ => return None,
+    };
+    Some(name)
+}
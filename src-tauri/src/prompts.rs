@@ -0,0 +1,45 @@
+use crate::translation::LanguagePair;
+use anyhow::{Context, Result};
+use directories::ProjectDirs;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+// Where versions before the overrides moved into the settings kept them
+const LEGACY_PROMPTS_FILE: &str = "system_prompts.json";
+
+/// Reject prompts that would leave the model without instructions
+pub fn validate(prompt: &str) -> Result<()> {
+    if prompt.trim().is_empty() {
+        anyhow::bail!("System prompt must not be empty");
+    }
+    Ok(())
+}
+
+/// System prompt overrides left in their old file, if there is one
+///
+/// Unreadable or empty prompts are skipped, the settings only take valid
+/// ones.
+pub fn legacy_overrides() -> Option<HashMap<LanguagePair, String>> {
+    let path = legacy_path().ok()?;
+    let contents = std::fs::read_to_string(path).ok()?;
+    let overrides: HashMap<LanguagePair, String> = serde_json::from_str(&contents)
+        .map_err(|e| tracing::warn!(error = %e, "Ignoring unreadable system prompt overrides"))
+        .ok()?;
+    Some(
+        overrides
+            .into_iter()
+            .filter(|(_, prompt)| validate(prompt).is_ok())
+            .collect(),
+    )
+}
+
+/// Delete the old overrides file once its prompts are in the settings
+pub fn remove_legacy_overrides() -> Result<()> {
+    std::fs::remove_file(legacy_path()?).context("Failed to remove old system prompt file")
+}
+
+fn legacy_path() -> Result<PathBuf> {
+    let proj_dirs = ProjectDirs::from("com", "konnyaku", "konnyaku")
+        .context("Failed to determine project directories")?;
+    Ok(proj_dirs.config_dir().join(LEGACY_PROMPTS_FILE))
+}
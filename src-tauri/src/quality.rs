@@ -0,0 +1,78 @@
+// Heuristics for judging translation quality without a reference translation.
+
+/// Similarity of two texts in [0, 1], where 1 means identical
+///
+/// This is the normalized Levenshtein similarity over characters:
+/// `1 - edit_distance / max_len`. Case and runs of whitespace are ignored,
+/// so it measures wording rather than formatting. Two empty texts count as
+/// identical.
+pub fn similarity(a: &str, b: &str) -> f32 {
+    let a = normalize(a);
+    let b = normalize(b);
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+
+    1.0 - levenshtein(&a, &b) as f32 / longest as f32
+}
+
+/// Number of single-character insertions, deletions and substitutions
+/// needed to turn `a` into `b`
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    // Only the previous row of the distance matrix is needed
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[b.len()]
+}
+
+fn normalize(text: &str) -> Vec<char> {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+        .chars()
+        .collect()
+}
+
+/// Whether `output` looks like `input` handed back without translation
+///
+/// Compares the texts after folding full-width ASCII to half-width and
+/// collapsing whitespace, so `Ｔｏｋｙｏ` and `Tokyo` count as the same.
+/// Input without any letters (numbers, punctuation) never counts, since
+/// there is nothing to translate.
+pub fn is_untranslated(input: &str, output: &str) -> bool {
+    if !input.chars().any(char::is_alphabetic) {
+        return false;
+    }
+    fold(input) == fold(output)
+}
+
+/// Map full-width ASCII variants (U+FF01-U+FF5E) and the ideographic space
+/// to their half-width forms
+pub fn normalize_width(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+            '\u{3000}' => ' ',
+            _ => c,
+        })
+        .collect()
+}
+
+fn fold(text: &str) -> String {
+    normalize_width(text)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
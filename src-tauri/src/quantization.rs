@@ -0,0 +1,111 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Quantizations the default model is published in, smallest first
+///
+/// Larger ones translate a little more accurately at the cost of download
+/// size, memory and speed.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Quantization {
+    /// The default, about 230MB
+    Q4_K_M,
+    Q5_K_M,
+    Q8_0,
+    /// Unquantized, about 700MB
+    F16,
+}
+
+impl Quantization {
+    pub const ALL: [Quantization; 4] = [Self::Q4_K_M, Self::Q5_K_M, Self::Q8_0, Self::F16];
+
+    /// The name used in GGUF file names, e.g. "Q4_K_M"
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Q4_K_M => "Q4_K_M",
+            Self::Q5_K_M => "Q5_K_M",
+            Self::Q8_0 => "Q8_0",
+            Self::F16 => "F16",
+        }
+    }
+
+    /// The file of this quantization for a model published as
+    /// `<stem>-<quantization>.gguf`
+    pub fn file_name(self, stem: &str) -> String {
+        format!("{}-{}.gguf", stem, self.as_str())
+    }
+
+    /// The quantization a `<stem>-<quantization>.gguf` file name ends in
+    pub fn from_file_name(file: &str) -> Option<Self> {
+        Self::split_file_name(file).map(|(_, quantization)| quantization)
+    }
+
+    /// The stem and quantization of a `<stem>-<quantization>.gguf` file name
+    pub fn split_file_name(file: &str) -> Option<(&str, Self)> {
+        let (name, extension) = file.rsplit_once('.')?;
+        if !extension.eq_ignore_ascii_case("gguf") {
+            return None;
+        }
+        let (stem, suffix) = name.rsplit_once('-')?;
+        let quantization = Self::ALL
+            .into_iter()
+            .find(|quantization| suffix.eq_ignore_ascii_case(quantization.as_str()))?;
+        Some((stem, quantization))
+    }
+}
+
+impl fmt::Display for Quantization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Quantization {
+    type Err = anyhow::Error;
+
+    /// Parse a quantization name, ignoring case
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        Self::ALL
+            .into_iter()
+            .find(|quantization| s.eq_ignore_ascii_case(quantization.as_str()))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unknown quantization: {}, expected Q4_K_M, Q5_K_M, Q8_0 or F16",
+                    s
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_split_into_stem_and_quantization() {
+        assert_eq!(
+            Quantization::split_file_name("LFM2-350M-ENJP-MT-Q4_K_M.gguf"),
+            Some(("LFM2-350M-ENJP-MT", Quantization::Q4_K_M))
+        );
+        assert_eq!(
+            Quantization::split_file_name("my-model-f16.GGUF"),
+            Some(("my-model", Quantization::F16))
+        );
+        for unrecognized in [
+            "model.gguf",
+            "model-Q3_K_S.gguf",
+            "model-Q8_0.bin",
+            "model-Q8_0-00001-of-00002.gguf",
+        ] {
+            assert_eq!(Quantization::split_file_name(unrecognized), None);
+        }
+
+        let file = Quantization::Q8_0.file_name("my-model");
+        assert_eq!(file, "my-model-Q8_0.gguf");
+        let quantization = Quantization::from_file_name(&file);
+        assert_eq!(quantization, Some(Quantization::Q8_0));
+    }
+}
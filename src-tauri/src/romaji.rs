@@ -0,0 +1,171 @@
+// Kana to romaji transliteration (modified Hepburn).
+
+/// Transliterate the kana in `text` to romaji
+///
+/// Hiragana and katakana become Hepburn romaji, with small-kana combinations
+/// (`きょ` → `kyo`), doubled consonants (`っ`), long vowels (`ー`) and
+/// syllabic `n` handled. Japanese punctuation becomes its ASCII equivalent.
+/// Anything else, kanji included, passes through unchanged, so give it a
+/// kana reading of kanji text to get romaji for all of it.
+pub fn to_romaji(text: &str) -> String {
+    let chars: Vec<char> = text.chars().map(katakana_to_hiragana).collect();
+    let mut output = String::with_capacity(text.len() * 2);
+    let mut double_next = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        // Two-kana combinations like きゃ come before single kana
+        let pair = chars.get(i + 1).and_then(|&next| combination(c, next));
+        let (syllable, consumed) = match pair {
+            Some(syllable) => (Some(syllable), 2),
+            None => (kana(c), 1),
+        };
+
+        match (c, syllable) {
+            ('っ', _) => double_next = true,
+            ('ー', _) => {
+                if let Some(vowel) = output.chars().last().filter(|v| "aeiou".contains(*v)) {
+                    output.push(vowel);
+                }
+            }
+            ('ん', _) => {
+                output.push('n');
+                // Keep ん + vowel or y apart from a syllable, e.g. kin'en
+                let next = chars.get(i + 1).and_then(|&next| kana(next));
+                if next.is_some_and(|s| s.starts_with(['a', 'e', 'i', 'o', 'u', 'y'])) {
+                    output.push('\'');
+                }
+            }
+            (_, Some(syllable)) => {
+                if std::mem::take(&mut double_next) {
+                    // っち is written tch in Hepburn
+                    let first = if syllable.starts_with("ch") {
+                        't'
+                    } else {
+                        syllable.chars().next().unwrap_or_default()
+                    };
+                    output.push(first);
+                }
+                output.push_str(syllable);
+            }
+            (_, None) => {
+                double_next = false;
+                output.push(punctuation(c).unwrap_or(c));
+            }
+        }
+
+        i += consumed;
+    }
+
+    output
+}
+
+pub(crate) fn katakana_to_hiragana(c: char) -> char {
+    match c {
+        // ァ-ヴ share their layout with ぁ-ゔ, 0x60 code points earlier
+        '\u{30A1}'..='\u{30F4}' => char::from_u32(c as u32 - 0x60).unwrap_or(c),
+        _ => c,
+    }
+}
+
+#[rustfmt::skip]
+fn kana(c: char) -> Option<&'static str> {
+    let romaji = match c {
+        'あ' => "a", 'い' => "i", 'う' => "u", 'え' => "e", 'お' => "o",
+        'か' => "ka", 'き' => "ki", 'く' => "ku", 'け' => "ke", 'こ' => "ko",
+        'が' => "ga", 'ぎ' => "gi", 'ぐ' => "gu", 'げ' => "ge", 'ご' => "go",
+        'さ' => "sa", 'し' => "shi", 'す' => "su", 'せ' => "se", 'そ' => "so",
+        'ざ' => "za", 'じ' => "ji", 'ず' => "zu", 'ぜ' => "ze", 'ぞ' => "zo",
+        'た' => "ta", 'ち' => "chi", 'つ' => "tsu", 'て' => "te", 'と' => "to",
+        'だ' => "da", 'ぢ' => "ji", 'づ' => "zu", 'で' => "de", 'ど' => "do",
+        'な' => "na", 'に' => "ni", 'ぬ' => "nu", 'ね' => "ne", 'の' => "no",
+        'は' => "ha", 'ひ' => "hi", 'ふ' => "fu", 'へ' => "he", 'ほ' => "ho",
+        'ば' => "ba", 'び' => "bi", 'ぶ' => "bu", 'べ' => "be", 'ぼ' => "bo",
+        'ぱ' => "pa", 'ぴ' => "pi", 'ぷ' => "pu", 'ぺ' => "pe", 'ぽ' => "po",
+        'ま' => "ma", 'み' => "mi", 'む' => "mu", 'め' => "me", 'も' => "mo",
+        'や' => "ya", 'ゆ' => "yu", 'よ' => "yo",
+        'ら' => "ra", 'り' => "ri", 'る' => "ru", 'れ' => "re", 'ろ' => "ro",
+        'わ' => "wa", 'ゐ' => "i", 'ゑ' => "e", 'を' => "o",
+        'ゔ' => "vu",
+        'ぁ' => "a", 'ぃ' => "i", 'ぅ' => "u", 'ぇ' => "e", 'ぉ' => "o",
+        'ゃ' => "ya", 'ゅ' => "yu", 'ょ' => "yo", 'ゎ' => "wa",
+        _ => return None,
+    };
+    Some(romaji)
+}
+
+#[rustfmt::skip]
+fn combination(first: char, second: char) -> Option<&'static str> {
+    let romaji = match (first, second) {
+        ('き', 'ゃ') => "kya", ('き', 'ゅ') => "kyu", ('き', 'ょ') => "kyo",
+        ('ぎ', 'ゃ') => "gya", ('ぎ', 'ゅ') => "gyu", ('ぎ', 'ょ') => "gyo",
+        ('し', 'ゃ') => "sha", ('し', 'ゅ') => "shu", ('し', 'ょ') => "sho", ('し', 'ぇ') => "she",
+        ('じ', 'ゃ') => "ja", ('じ', 'ゅ') => "ju", ('じ', 'ょ') => "jo", ('じ', 'ぇ') => "je",
+        ('ち', 'ゃ') => "cha", ('ち', 'ゅ') => "chu", ('ち', 'ょ') => "cho", ('ち', 'ぇ') => "che",
+        ('に', 'ゃ') => "nya", ('に', 'ゅ') => "nyu", ('に', 'ょ') => "nyo",
+        ('ひ', 'ゃ') => "hya", ('ひ', 'ゅ') => "hyu", ('ひ', 'ょ') => "hyo",
+        ('び', 'ゃ') => "bya", ('び', 'ゅ') => "byu", ('び', 'ょ') => "byo",
+        ('ぴ', 'ゃ') => "pya", ('ぴ', 'ゅ') => "pyu", ('ぴ', 'ょ') => "pyo",
+        ('み', 'ゃ') => "mya", ('み', 'ゅ') => "myu", ('み', 'ょ') => "myo",
+        ('り', 'ゃ') => "rya", ('り', 'ゅ') => "ryu", ('り', 'ょ') => "ryo",
+        // Katakana loanword sounds
+        ('て', 'ぃ') => "ti", ('で', 'ぃ') => "di", ('と', 'ぅ') => "tu", ('ど', 'ぅ') => "du",
+        ('ふ', 'ぁ') => "fa", ('ふ', 'ぃ') => "fi", ('ふ', 'ぇ') => "fe", ('ふ', 'ぉ') => "fo",
+        ('う', 'ぃ') => "wi", ('う', 'ぇ') => "we", ('う', 'ぉ') => "wo",
+        ('ゔ', 'ぁ') => "va", ('ゔ', 'ぃ') => "vi", ('ゔ', 'ぇ') => "ve", ('ゔ', 'ぉ') => "vo",
+        ('つ', 'ぁ') => "tsa", ('つ', 'ぃ') => "tsi", ('つ', 'ぇ') => "tse", ('つ', 'ぉ') => "tso",
+        _ => return None,
+    };
+    Some(romaji)
+}
+
+fn punctuation(c: char) -> Option<char> {
+    let ascii = match c {
+        '。' => '.',
+        '、' => ',',
+        '・' => ' ',
+        '「' | '」' | '『' | '』' => '"',
+        '（' => '(',
+        '）' => ')',
+        '！' => '!',
+        '？' => '?',
+        '：' => ':',
+        '　' => ' ',
+        '〜' | '～' => '~',
+        _ => return None,
+    };
+    Some(ascii)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubled_consonants() {
+        assert_eq!(to_romaji("きって"), "kitte");
+        assert_eq!(to_romaji("まっちゃ"), "matcha");
+    }
+
+    #[test]
+    fn syllabic_n_before_vowels() {
+        assert_eq!(to_romaji("きんえん"), "kin'en");
+        assert_eq!(to_romaji("ほんや"), "hon'ya");
+        assert_eq!(to_romaji("さんぽ"), "sanpo");
+    }
+
+    #[test]
+    fn long_vowels_and_combinations() {
+        assert_eq!(to_romaji("コーヒー"), "koohii");
+        assert_eq!(to_romaji("きょうと"), "kyouto");
+        assert_eq!(to_romaji("ティーシャツ"), "tiishatsu");
+    }
+
+    #[test]
+    fn punctuation_and_kanji() {
+        assert_eq!(to_romaji("はい、そうです。"), "hai,soudesu.");
+        assert_eq!(to_romaji("毎日"), "毎日");
+    }
+}
@@ -0,0 +1,281 @@
+use anyhow::Result;
+use llama_cpp_2::model::LlamaModel;
+use llama_cpp_2::sampling::LlamaSampler;
+use llama_cpp_2::token::LlamaToken;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Used for alternatives when the request itself asks for greedy decoding
+const ALTERNATIVE_TEMPERATURE: f32 = 0.7;
+// How many recent tokens the penalties look at when `repeat_last_n` is unset
+const DEFAULT_REPEAT_LAST_N: i32 = 64;
+// llama.cpp's mirostat defaults
+const DEFAULT_MIROSTAT_TAU: f32 = 5.0;
+const DEFAULT_MIROSTAT_ETA: f32 = 0.1;
+
+/// Token sampling settings for generation
+///
+/// The default is greedy decoding, which is deterministic and usually the
+/// best choice for translation. Setting a temperature above zero switches to
+/// random sampling through the penalty and top-k/top-p filters.
+///
+/// The penalties only apply to random sampling and are ignored in greedy
+/// mode. They only see generated tokens, not the prompt, so copying names
+/// from the source text isn't penalized.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SamplingConfig {
+    /// Softmax temperature, 0 (the default) means greedy decoding
+    pub temperature: f32,
+    /// Keep only the k most likely tokens
+    pub top_k: Option<i32>,
+    /// Keep the smallest set of tokens whose probability sums to p
+    pub top_p: Option<f32>,
+    /// Drop tokens less likely than this fraction of the most likely one.
+    /// Adapts to how confident the model is, 0.05-0.1 works well.
+    pub min_p: Option<f32>,
+    /// Keep the tokens whose surprise is closest to the expected one, up
+    /// to probability p. 1.0 disables it, 0.9-0.95 is a reasonable start.
+    pub typical_p: Option<f32>,
+    /// Seed for random sampling, a fresh one is picked per call when unset
+    pub seed: Option<u32>,
+    /// Divide the logits of recently generated tokens by this, 1.0 disables
+    /// it and around 1.1 is a reasonable start against loops
+    pub repeat_penalty: Option<f32>,
+    /// How many recent tokens the penalties consider, 64 by default and -1
+    /// for the whole context
+    pub repeat_last_n: Option<i32>,
+    /// Subtract this times a token's recent count from its logit, 0.0
+    /// disables it, small values like 0.1-0.5 are typical
+    pub frequency_penalty: Option<f32>,
+    /// Subtract this from the logit of any recently seen token, 0.0 disables
+    /// it, small values like 0.1-0.5 are typical
+    pub presence_penalty: Option<f32>,
+    /// Pick tokens with mirostat v2 instead of top-k/top-p, which are then
+    /// ignored. Enables random sampling even at temperature 0.
+    pub mirostat: Option<MirostatConfig>,
+}
+
+/// Mirostat v2 settings
+///
+/// Rather than cutting the distribution at a fixed k or p, mirostat aims for
+/// a target surprise (perplexity) and adjusts its cutoff token by token to
+/// keep the output near it, so quality stays even without hand-tuning.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MirostatConfig {
+    /// Target surprise, lower stays closer to the most likely wording.
+    /// 5.0 by default.
+    pub tau: f32,
+    /// How quickly the cutoff adapts, 0.1 by default
+    pub eta: f32,
+}
+
+impl Default for MirostatConfig {
+    fn default() -> Self {
+        Self {
+            tau: DEFAULT_MIROSTAT_TAU,
+            eta: DEFAULT_MIROSTAT_ETA,
+        }
+    }
+}
+
+impl SamplingConfig {
+    /// Whether generation always picks the most likely token
+    pub fn is_greedy(&self) -> bool {
+        self.temperature <= 0.0 && self.mirostat.is_none()
+    }
+
+    /// Whether the same input always produces the same output
+    pub fn is_deterministic(&self) -> bool {
+        self.is_greedy() || self.seed.is_some()
+    }
+
+    /// Check that the probability cutoffs are within 0.0..=1.0
+    pub fn validate(&self) -> Result<()> {
+        let cutoffs = [
+            ("top_p", self.top_p),
+            ("min_p", self.min_p),
+            ("typical_p", self.typical_p),
+        ];
+        for (name, value) in cutoffs {
+            if let Some(value) = value.filter(|value| !(0.0..=1.0).contains(value)) {
+                anyhow::bail!("{} must be between 0.0 and 1.0, got {}", name, value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Build the sampler chain for these settings
+    ///
+    /// The filters run in llama.cpp's usual order, top-k, typical-p, top-p
+    /// then min-p, before temperature scales what is left.
+    pub fn build_sampler(&self) -> LlamaSampler {
+        if self.is_greedy() {
+            return LlamaSampler::greedy();
+        }
+
+        let mut samplers = Vec::new();
+        if self.has_penalties() {
+            samplers.push(LlamaSampler::penalties(
+                self.repeat_last_n.unwrap_or(DEFAULT_REPEAT_LAST_N),
+                self.repeat_penalty.unwrap_or(1.0),
+                self.frequency_penalty.unwrap_or(0.0),
+                self.presence_penalty.unwrap_or(0.0),
+            ));
+        }
+        let seed = self.seed.unwrap_or_else(random_seed);
+        // As in llama.cpp, mirostat replaces the truncating samplers and
+        // makes the final pick itself
+        if let Some(mirostat) = &self.mirostat {
+            if self.temperature > 0.0 {
+                samplers.push(LlamaSampler::temp(self.temperature));
+            }
+            samplers.push(LlamaSampler::mirostat_v2(seed, mirostat.tau, mirostat.eta));
+            return LlamaSampler::chain_simple(samplers);
+        }
+
+        if let Some(k) = self.top_k {
+            samplers.push(LlamaSampler::top_k(k));
+        }
+        if let Some(p) = self.typical_p {
+            samplers.push(LlamaSampler::typical(p, 1));
+        }
+        if let Some(p) = self.top_p {
+            samplers.push(LlamaSampler::top_p(p, 1));
+        }
+        if let Some(p) = self.min_p {
+            samplers.push(LlamaSampler::min_p(p, 1));
+        }
+        samplers.push(LlamaSampler::temp(self.temperature));
+        samplers.push(LlamaSampler::dist(seed));
+
+        LlamaSampler::chain_simple(samplers)
+    }
+
+    fn has_penalties(&self) -> bool {
+        self.repeat_penalty.is_some()
+            || self.frequency_penalty.is_some()
+            || self.presence_penalty.is_some()
+    }
+
+    /// Settings for the `index`th alternative translation
+    ///
+    /// Greedy decoding would produce the same output every time, so it is
+    /// swapped for a moderate temperature. A fixed seed is offset by `index`
+    /// so each alternative differs while the set stays reproducible.
+    pub fn alternative(&self, index: u32) -> SamplingConfig {
+        SamplingConfig {
+            temperature: if self.is_greedy() {
+                ALTERNATIVE_TEMPERATURE
+            } else {
+                self.temperature
+            },
+            seed: self.seed.map(|seed| seed.wrapping_add(index)),
+            ..self.clone()
+        }
+    }
+}
+
+/// Named sampling settings, for picking a style without tuning each value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplingPreset {
+    /// Greedy decoding: deterministic and closest to the source
+    Literal,
+    /// Temperature 0.3, top-k 40, top-p 0.9 and repeat penalty 1.05: a
+    /// little freedom in word choice while staying close to the source
+    Balanced,
+    /// Temperature 0.7, top-k 50, top-p 0.95 and repeat penalty 1.1: freer,
+    /// more idiomatic phrasing that may stray from the source's structure
+    Natural,
+}
+
+impl SamplingPreset {
+    /// The sampling settings this preset stands for
+    pub fn config(self) -> SamplingConfig {
+        match self {
+            Self::Literal => SamplingConfig::default(),
+            Self::Balanced => SamplingConfig {
+                temperature: 0.3,
+                top_k: Some(40),
+                top_p: Some(0.9),
+                repeat_penalty: Some(1.05),
+                ..SamplingConfig::default()
+            },
+            Self::Natural => SamplingConfig {
+                temperature: 0.7,
+                top_k: Some(50),
+                top_p: Some(0.95),
+                repeat_penalty: Some(1.1),
+                ..SamplingConfig::default()
+            },
+        }
+    }
+}
+
+impl FromStr for SamplingPreset {
+    type Err = anyhow::Error;
+
+    /// Parse a preset name, ignoring case
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "literal" => Ok(Self::Literal),
+            "balanced" => Ok(Self::Balanced),
+            "natural" => Ok(Self::Natural),
+            _ => anyhow::bail!(
+                "Unknown sampling preset: {}, expected literal, balanced or natural",
+                s
+            ),
+        }
+    }
+}
+
+/// Natural-log probability of `token` under the model's raw distribution
+///
+/// Computed as a log-softmax over the logits, before temperature or top-k/p
+/// filtering, so it reflects the model's own confidence rather than the
+/// sampler's. Values are at most 0, where 0 means the model was certain.
+pub fn token_logprob(logits: &[f32], token: LlamaToken) -> Option<f32> {
+    let logit = *logits.get(usize::try_from(token.0).ok()?)?;
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|l| (l - max).exp()).sum();
+    Some(logit - max - sum.ln())
+}
+
+/// A sampler that only allows output matching the GBNF `grammar`, which
+/// must define a `root` rule
+pub fn grammar_sampler(model: &LlamaModel, grammar: &str) -> Result<LlamaSampler> {
+    validate_grammar(grammar)?;
+    // llama.cpp only logs why parsing failed, so the reason can't be passed on
+    LlamaSampler::grammar(model, grammar, "root").ok_or_else(|| {
+        anyhow::anyhow!("Invalid GBNF grammar, check the rule syntax (details are in the log)")
+    })
+}
+
+/// Catch the grammar mistakes that can be spotted without parsing it
+pub fn validate_grammar(grammar: &str) -> Result<()> {
+    if grammar.trim().is_empty() {
+        anyhow::bail!("Grammar is empty");
+    }
+    if grammar.contains('\0') {
+        anyhow::bail!("Grammar must not contain NUL characters");
+    }
+
+    let defines_root = grammar.lines().any(|line| {
+        line.split_once("::=")
+            .is_some_and(|(name, _)| name.trim() == "root")
+    });
+    if !defines_root {
+        anyhow::bail!("Grammar must define a `root` rule, e.g. `root ::= ...`");
+    }
+    Ok(())
+}
+
+fn random_seed() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() ^ d.as_secs() as u32)
+        .unwrap_or_default()
+}
@@ -0,0 +1,111 @@
+use crate::translation::{ServiceConfig, DEFAULT_CACHE_SIZE};
+use anyhow::{Context, Result};
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+const SETTINGS_FILE: &str = "settings.json";
+
+/// User settings that survive restarts
+///
+/// The runtime settings are saved whenever they change. The rest only take
+/// effect at startup and are left for users to edit in the file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    #[serde(flatten)]
+    pub service: ServiceConfig,
+    /// Number of translations kept in memory, 0 disables the cache
+    pub cache_size: usize,
+    /// Inference threads, the built-in default when unset
+    pub num_threads: Option<i32>,
+    /// Layers offloaded to the GPU, the built-in default when unset
+    pub gpu_layers: Option<u32>,
+    pub force_cpu: bool,
+    /// HuggingFace repository and GGUF file to use instead of the default
+    /// model
+    pub model_repo: Option<String>,
+    pub model_file: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            service: ServiceConfig::default(),
+            cache_size: DEFAULT_CACHE_SIZE,
+            num_threads: None,
+            gpu_layers: None,
+            force_cpu: false,
+            model_repo: None,
+            model_file: None,
+        }
+    }
+}
+
+/// Settings backed by a JSON file in the platform config directory
+#[derive(Debug)]
+pub struct SettingsStore {
+    path: PathBuf,
+    settings: RwLock<Settings>,
+}
+
+impl SettingsStore {
+    /// Load the saved settings
+    ///
+    /// A missing or unreadable file is replaced with the defaults.
+    pub fn load() -> Result<Self> {
+        let proj_dirs = ProjectDirs::from("com", "konnyaku", "konnyaku")
+            .context("Failed to determine project directories")?;
+
+        let config_dir = proj_dirs.config_dir();
+        std::fs::create_dir_all(config_dir).context("Failed to create config directory")?;
+        Self::load_from(config_dir.join(SETTINGS_FILE))
+    }
+
+    /// Load the settings saved in the file at `path`, like `load`
+    pub fn load_from(path: PathBuf) -> Result<Self> {
+        let loaded = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(
+                    |e| tracing::warn!(error = %e, "Replacing unreadable settings with defaults"),
+                )
+                .ok(),
+            Err(_) => None,
+        };
+
+        let store = Self {
+            path,
+            settings: RwLock::new(loaded.clone().unwrap_or_default()),
+        };
+        if loaded.is_none() {
+            store.save(&store.get())?;
+        }
+        Ok(store)
+    }
+
+    /// A snapshot of the current settings
+    pub fn get(&self) -> Settings {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Change the settings with `change` and save them
+    pub fn update(&self, change: impl FnOnce(&mut Settings)) -> Result<()> {
+        let mut settings = self.settings.write().unwrap();
+        change(&mut settings);
+        self.save(&settings)
+    }
+
+    /// Go back to the defaults and save them
+    pub fn reset(&self) -> Result<Settings> {
+        let defaults = Settings::default();
+        self.update(|settings| *settings = defaults.clone())?;
+        Ok(defaults)
+    }
+
+    fn save(&self, settings: &Settings) -> Result<()> {
+        let contents =
+            serde_json::to_string_pretty(settings).context("Failed to serialize settings")?;
+        std::fs::write(&self.path, contents).context("Failed to save settings")
+    }
+}
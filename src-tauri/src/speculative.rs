@@ -0,0 +1,232 @@
+// Speculative decoding with a draft model.
+//
+// llama-cpp-2 doesn't bind llama.cpp's speculative API, so this does the
+// greedy form of it by hand: a small model that shares the main model's
+// vocabulary guesses the next few tokens one at a time, and the main model
+// decodes all of them in a single batch. Guesses are kept up to the first
+// one the main model disagrees with, plus the main model's own token at that
+// point, so the output is exactly what greedy decoding alone would produce.
+// Each accepted guess saves one full decode of the main model.
+//
+// Rejected guesses have to be dropped from the context again, which models
+// with a recurrent state, including hybrids like LFM2, can't do. Both models
+// are probed for it when the draft is loaded.
+
+use anyhow::{Context, Result};
+use llama_cpp_2::context::params::LlamaContextParams;
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use llama_cpp_2::llama_batch::LlamaBatch;
+use llama_cpp_2::model::{LlamaModel, Special};
+use llama_cpp_2::token::LlamaToken;
+use std::num::NonZeroU32;
+
+/// Most tokens the draft model guesses ahead per step
+pub const DRAFT_TOKENS: usize = 5;
+
+// Tokens compared between the two vocabularies, spread over their range
+const VOCAB_SAMPLES: usize = 1000;
+
+// Context size for `can_rewind`, the smallest llama.cpp allocates anyway
+const PROBE_CONTEXT: u32 = 256;
+
+/// Check `draft` tokenizes like `model`, which speculation relies on
+///
+/// The vocabularies must be the same size with the same special tokens, and
+/// a sample of tokens spread over them must have the same text.
+pub fn check_compatible(model: &LlamaModel, draft: &LlamaModel) -> Result<()> {
+    if model.n_vocab() != draft.n_vocab() {
+        anyhow::bail!(
+            "Draft model has a vocabulary of {} tokens, the model has {}",
+            draft.n_vocab(),
+            model.n_vocab()
+        );
+    }
+    if model.token_bos() != draft.token_bos() || model.token_eos() != draft.token_eos() {
+        anyhow::bail!("Draft model uses different special tokens than the model");
+    }
+
+    let n_vocab = model.n_vocab().max(0) as usize;
+    let step = (n_vocab / VOCAB_SAMPLES).max(1);
+    for id in (0..n_vocab).step_by(step) {
+        let token = LlamaToken(id as i32);
+        let expected = model.token_to_bytes(token, Special::Tokenize).ok();
+        if draft.token_to_bytes(token, Special::Tokenize).ok() != expected {
+            anyhow::bail!("Draft model's token {} differs from the model's", id);
+        }
+    }
+    Ok(())
+}
+
+/// Whether contexts of `model` can drop their latest tokens, which
+/// rejecting guesses needs
+///
+/// Decodes two tokens in a small context and tries to remove the second.
+/// llama.cpp refuses without changing anything when the model keeps a
+/// recurrent state.
+pub fn can_rewind(model: &LlamaModel, backend: &LlamaBackend) -> Result<bool> {
+    let params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(PROBE_CONTEXT))
+        .with_n_batch(PROBE_CONTEXT);
+    let mut ctx = model
+        .new_context(backend, params)
+        .context("Failed to create context")?;
+    let mut batch = LlamaBatch::new(2, 1);
+    batch.add(model.token_bos(), 0, &[0], false)?;
+    batch.add(model.token_bos(), 1, &[0], true)?;
+    ctx.decode(&mut batch)
+        .context("Failed to decode probe tokens")?;
+    ctx.clear_kv_cache_seq(Some(0), Some(1), None)
+        .context("Failed to remove probe token")
+}
+
+/// A draft model context following along with a generation
+pub struct Speculator<'a> {
+    ctx: LlamaContext<'a>,
+    batch: LlamaBatch,
+    draft_tokens: usize,
+    // Positions the draft context holds the accepted tokens for
+    n_past: i32,
+    // The last accepted token, when the draft guessed it but hasn't
+    // decoded it yet
+    pending: Option<LlamaToken>,
+    drafted: usize,
+    accepted: usize,
+}
+
+impl<'a> Speculator<'a> {
+    /// A draft context that has read `prompt`, guessing at most
+    /// `draft_tokens` tokens ahead
+    pub fn new(
+        draft: &'a LlamaModel,
+        backend: &LlamaBackend,
+        params: LlamaContextParams,
+        prompt: &[LlamaToken],
+        n_batch: usize,
+        draft_tokens: usize,
+    ) -> Result<Self> {
+        let mut ctx = draft
+            .new_context(backend, params)
+            .context("Failed to create draft model context")?;
+        let mut batch = LlamaBatch::new(n_batch.max(2), 1);
+        for (index, chunk) in prompt.chunks(n_batch.max(1)).enumerate() {
+            batch.clear();
+            let offset = index * n_batch.max(1);
+            for (i, &token) in chunk.iter().enumerate() {
+                batch.add(token, (offset + i) as i32, &[0], false)?;
+            }
+            ctx.decode(&mut batch)
+                .context("Failed to decode prompt with the draft model")?;
+        }
+
+        Ok(Self {
+            ctx,
+            batch,
+            draft_tokens,
+            n_past: prompt.len() as i32,
+            pending: None,
+            drafted: 0,
+            accepted: 0,
+        })
+    }
+
+    /// Decode `token` at `pos` in `ctx` together with up to `limit` guesses
+    /// of the tokens after it
+    ///
+    /// Returns the guesses `ctx`'s model agrees with, which are decoded
+    /// already, and the index in `batch` of the logits for the token after
+    /// the last of them.
+    pub fn step(
+        &mut self,
+        ctx: &mut LlamaContext,
+        batch: &mut LlamaBatch,
+        token: LlamaToken,
+        pos: i32,
+        limit: usize,
+    ) -> Result<(Vec<LlamaToken>, i32)> {
+        let guesses = self.guess(token, limit.min(self.draft_tokens))?;
+
+        batch.clear();
+        batch.add(token, pos, &[0], true)?;
+        for (i, &guess) in guesses.iter().enumerate() {
+            batch.add(guess, pos + 1 + i as i32, &[0], true)?;
+        }
+        ctx.decode(batch)
+            .context("Failed to decode draft tokens")?;
+
+        let agreed = guesses
+            .iter()
+            .enumerate()
+            .take_while(|(i, &guess)| argmax(ctx.get_logits_ith(*i as i32)) == guess)
+            .count();
+        self.drafted += guesses.len();
+        self.accepted += agreed;
+
+        // Forget the rejected guesses in both contexts
+        let keep = pos + 1 + agreed as i32;
+        let mut rewound = ctx
+            .clear_kv_cache_seq(Some(0), Some(keep as u32), None)
+            .context("Failed to discard rejected draft tokens")?;
+        if self.n_past > keep {
+            rewound &= self
+                .ctx
+                .clear_kv_cache_seq(Some(0), Some(keep as u32), None)
+                .context("Failed to discard rejected draft tokens")?;
+            self.n_past = keep;
+        }
+        if !rewound {
+            anyhow::bail!("The model can't discard rejected draft tokens");
+        }
+        // The draft decodes each guess only to make the next one, so the
+        // last, when accepted, is still missing from its context
+        let accepted = guesses[..agreed].to_vec();
+        self.pending = (self.n_past < keep).then(|| accepted.last().copied().unwrap_or(token));
+        Ok((accepted, agreed as i32))
+    }
+
+    /// Guesses made and how many of them the main model accepted
+    pub fn acceptance(&self) -> (usize, usize) {
+        (self.drafted, self.accepted)
+    }
+
+    // Greedily guess up to `limit` tokens after `token`, stopping early at
+    // the end of generation
+    fn guess(&mut self, token: LlamaToken, limit: usize) -> Result<Vec<LlamaToken>> {
+        let mut guesses = Vec::with_capacity(limit);
+        let mut next = token;
+        while guesses.len() < limit {
+            self.batch.clear();
+            if let Some(pending) = self.pending.take() {
+                self.batch.add(pending, self.n_past, &[0], false)?;
+                self.n_past += 1;
+            }
+            self.batch.add(next, self.n_past, &[0], true)?;
+            self.ctx
+                .decode(&mut self.batch)
+                .context("Failed to decode with the draft model")?;
+            self.n_past += 1;
+
+            next = argmax(self.ctx.get_logits_ith(self.batch.n_tokens() - 1));
+            if self.ctx.model.is_eog_token(next) {
+                break;
+            }
+            guesses.push(next);
+        }
+        Ok(guesses)
+    }
+}
+
+// The most likely token, as the greedy sampler would pick it
+fn argmax(logits: &[f32]) -> LlamaToken {
+    let best = logits
+        .iter()
+        .enumerate()
+        .fold((0, f32::NEG_INFINITY), |best, (i, &logit)| {
+            if logit > best.1 {
+                (i, logit)
+            } else {
+                best
+            }
+        });
+    LlamaToken(best.0 as i32)
+}
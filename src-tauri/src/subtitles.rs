@@ -0,0 +1,160 @@
+// SubRip (.srt) subtitle parsing, so only the cue text gets translated.
+
+use anyhow::Result;
+
+/// A single subtitle cue
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    /// The cue number line, kept verbatim
+    pub index: String,
+    /// The `00:00:01,000 --> 00:00:02,500` line, kept verbatim
+    pub timing: String,
+    /// The cue's lines joined with `\n`, tags like `<i>` included
+    pub text: String,
+}
+
+/// Parse SRT content into cues
+///
+/// Any block without a numeric index and a valid timing line is an error,
+/// so nothing is silently dropped.
+pub fn parse(content: &str) -> Result<Vec<Cue>> {
+    let content = content.trim_start_matches('\u{FEFF}').replace("\r\n", "\n");
+    let mut cues = Vec::new();
+
+    for (number, block) in blocks(&content).into_iter().enumerate() {
+        let mut lines = block.iter();
+        let index = lines.next().map(|line| line.trim()).unwrap_or_default();
+        if index.is_empty() || !index.chars().all(|c| c.is_ascii_digit()) {
+            anyhow::bail!(
+                "Malformed SRT block {}: expected a cue number, found {:?}",
+                number + 1,
+                index
+            );
+        }
+
+        let timing = lines.next().map(|line| line.trim()).unwrap_or_default();
+        if !is_timing_line(timing) {
+            anyhow::bail!(
+                "Malformed SRT block {} (cue {}): expected a timing line, found {:?}",
+                number + 1,
+                index,
+                timing
+            );
+        }
+
+        cues.push(Cue {
+            index: index.to_string(),
+            timing: timing.to_string(),
+            text: lines.copied().collect::<Vec<_>>().join("\n"),
+        });
+    }
+
+    Ok(cues)
+}
+
+/// Render cues back into SRT
+pub fn render(cues: &[Cue]) -> String {
+    let mut output = String::new();
+    for cue in cues {
+        output.push_str(&cue.index);
+        output.push('\n');
+        output.push_str(&cue.timing);
+        output.push('\n');
+        if !cue.text.is_empty() {
+            output.push_str(&cue.text);
+            output.push('\n');
+        }
+        output.push('\n');
+    }
+    output
+}
+
+/// Groups of consecutive non-blank lines
+fn blocks(content: &str) -> Vec<Vec<&str>> {
+    let mut blocks = Vec::new();
+    let mut current = Vec::new();
+    for line in content.lines() {
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                blocks.push(std::mem::take(&mut current));
+            }
+        } else {
+            current.push(line);
+        }
+    }
+    if !current.is_empty() {
+        blocks.push(current);
+    }
+    blocks
+}
+
+/// `HH:MM:SS,mmm --> HH:MM:SS,mmm`, optionally followed by position settings
+fn is_timing_line(line: &str) -> bool {
+    let Some((start, rest)) = line.split_once("-->") else {
+        return false;
+    };
+    let end = rest.split_whitespace().next().unwrap_or_default();
+    is_timestamp(start.trim()) && is_timestamp(end)
+}
+
+fn is_timestamp(text: &str) -> bool {
+    // Some files use '.' instead of ',' before the milliseconds
+    let Some((clock, millis)) = text.split_once([',', '.']) else {
+        return false;
+    };
+    let parts: Vec<&str> = clock.split(':').collect();
+    let numeric = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+
+    parts.len() == 3 && parts.iter().all(|p| numeric(p)) && numeric(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_line_and_tagged_cues_round_trip() {
+        let content = "1\n00:00:01,000 --> 00:00:02,500\n<i>Hello,</i>\nworld\n\n\
+                       2\n00:00:03,000 --> 00:00:04,000 X1:10 X2:20\nBye\n\n";
+        let cues = parse(content).unwrap();
+        assert_eq!(cues.len(), 2);
+        assert_eq!(cues[0].text, "<i>Hello,</i>\nworld");
+        assert_eq!(cues[1].timing, "00:00:03,000 --> 00:00:04,000 X1:10 X2:20");
+        assert_eq!(render(&cues), content);
+    }
+
+    #[test]
+    fn bom_crlf_and_dot_separators_are_accepted() {
+        let content = "\u{FEFF}1\r\n00:00:01.000 --> 00:00:02.500\r\nこんにちは\r\n\r\n\
+                       2\r\n00:00:03,000 --> 00:00:04,000\r\n\r\n";
+        let cues = parse(content).unwrap();
+        assert_eq!(
+            cues,
+            vec![
+                Cue {
+                    index: "1".to_string(),
+                    timing: "00:00:01.000 --> 00:00:02.500".to_string(),
+                    text: "こんにちは".to_string(),
+                },
+                Cue {
+                    index: "2".to_string(),
+                    timing: "00:00:03,000 --> 00:00:04,000".to_string(),
+                    text: String::new(),
+                },
+            ]
+        );
+        assert_eq!(render(&cues[1..]), "2\n00:00:03,000 --> 00:00:04,000\n\n");
+    }
+
+    #[test]
+    fn malformed_blocks_are_errors() {
+        assert!(parse("Hello\n00:00:01,000 --> 00:00:02,000\nHi\n").is_err());
+        assert!(parse("1\nHi\n").is_err());
+        assert!(parse("1\n00:00:01 --> 00:00:02,000\nHi\n").is_err());
+        assert!(parse("1\n00:01,000 --> 00:00:02,000\nHi\n").is_err());
+
+        // A bad block after good ones isn't dropped either
+        let error = parse("1\n00:00:01,000 --> 00:00:02,000\nHi\n\n2\nBye\n").unwrap_err();
+        assert!(error.to_string().contains("block 2"));
+    }
+}
@@ -1,17 +1,50 @@
+use crate::beam::{self, MAX_BEAM_WIDTH};
+use crate::chunking;
+use crate::error::TranslationError;
+use crate::furigana;
+use crate::glossary::{self, GlossaryEntry, GlossaryImport};
+use crate::html;
+use crate::json;
+use crate::markdown;
+use crate::metadata::{ModelMetadata, PromptFormat};
+use crate::quantization::Quantization;
+use crate::prompts;
+use crate::quality;
+use crate::romaji;
+use crate::sanitize;
+use crate::sampling::{self, SamplingConfig, SamplingPreset};
+use crate::settings::{Settings, SettingsStore};
+use crate::speculative::{self, Speculator};
+use crate::subtitles;
 use anyhow::{Context, Result};
 use directories::ProjectDirs;
-use hf_hub::api::tokio::Api;
+use futures_util::Stream;
+use hf_hub::api::tokio::{Api, ApiBuilder};
 use llama_cpp_2::context::params::LlamaContextParams;
 use llama_cpp_2::llama_backend::LlamaBackend;
 use llama_cpp_2::llama_batch::LlamaBatch;
 use llama_cpp_2::model::params::LlamaModelParams;
-use llama_cpp_2::model::{AddBos, LlamaModel, Special, LlamaChatMessage, LlamaChatTemplate};
+use llama_cpp_2::model::{
+    AddBos, LlamaChatMessage, LlamaChatTemplate, LlamaLoraAdapter, LlamaModel, Special,
+};
 use llama_cpp_2::sampling::LlamaSampler;
+use llama_cpp_2::token::LlamaToken;
+use llama_cpp_2::LlamaModelLoadError;
+use lru::LruCache;
 use serde::{Deserialize, Serialize};
-use std::num::NonZeroU32;
-use std::path::PathBuf;
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch, Mutex, Notify, OnceCell, Semaphore};
+use unicode_normalization::{is_nfkc_quick, IsNormalized, UnicodeNormalization};
 
 // Model configuration constants
 const MODEL_REPO: &str = "LiquidAI/LFM2-350M-ENJP-MT-GGUF";
@@ -19,377 +52,5438 @@ const MODEL_FILE: &str = "LFM2-350M-ENJP-MT-Q4_K_M.gguf";
 const SYSTEM_PROMPT_EN_TO_JA: &str = "Translate to Japanese.";
 const SYSTEM_PROMPT_JA_TO_EN: &str = "Translate to English.";
 const MAX_TOKENS: i32 = 512;
-const CONTEXT_SIZE: u32 = 4096;  // Sufficient for translation tasks, model supports up to 128000
+const CONTEXT_SIZE: u32 = 4096;  // Default, sufficient for translation tasks, model supports up to 128000
+const MIN_CONTEXT_SIZE: u32 = 512; // Smallest context created for a short translation
+const BATCH_SIZE: u32 = 512; // Prompt tokens processed per decode call
+const MAX_INPUT_CHARS: usize = 50_000; // Longest text translated in one call
+const MODEL_DIR_ENV: &str = "KONNYAKU_MODEL_DIR"; // Overrides the platform cache dir
+// Checked in order for a HuggingFace token, for gated models
+const HF_TOKEN_ENVS: &[&str] = &["HF_TOKEN", "HUGGING_FACE_HUB_TOKEN"];
+const NUM_THREADS: i32 = 4; // CPU threads used for inference
+// Translations running or waiting for the model at once, beyond this
+// requests are turned away rather than piling up
+const MAX_CONCURRENT_TRANSLATIONS: usize = 4;
+const GPU_LAYERS: u32 = 0; // Layers offloaded to the GPU, the model is small enough for the CPU
+// Chat-template role markers the model sometimes emits after the real translation
+const DEFAULT_STOP_SEQUENCES: &[&str] = &["<|im_end|>", "<|im_start|>", "<|endoftext|>"];
+// Labels the model sometimes puts before its output
+const DEFAULT_OUTPUT_PREFIXES: &[&str] = &[
+    "Translation:",
+    "Translated text:",
+    "English:",
+    "Japanese:",
+    "翻訳：",
+    "翻訳:",
+    "訳：",
+    "訳:",
+    "英語：",
+    "日本語：",
+];
+pub(crate) const DEFAULT_CACHE_SIZE: usize = 128; // Number of translations kept in memory
+// Sent with direct downloads. hf-hub 0.3 builds its own client with a fixed
+// User-Agent, so API fallback requests can't carry it.
+const USER_AGENT: &str = concat!("konnyaku/", env!("CARGO_PKG_VERSION"));
+const HF_BASE_URL: &str = "https://huggingface.co";
+const DOWNLOAD_CONNECT_TIMEOUT_SECS: u64 = 30; // Time to establish a connection to the model host
+const DOWNLOAD_TIMEOUT_SECS: u64 = 3600; // Whole transfer of one file, per attempt
+const PROGRESS_REPORT_INTERVAL: u64 = 10 * 1_048_576; // Log download progress every 10MB
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 600; // Unload the model after 10 minutes without use
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const DISK_SPACE_MARGIN: u64 = 64 * 1_048_576; // Free space to leave on top of the model itself
+const GGUF_MIN_SIZE: u64 = 24; // Magic, version and tensor and metadata counts
+const MAX_CHUNK_CHARS: usize = 400; // Long text is translated in pieces of about this size, to fit MAX_TOKENS
+const FILE_BUFFER_BYTES: usize = 64 * 1024; // Most of a file held in memory before translating it
+const MAX_ALTERNATIVES: usize = 5; // Each alternative is a full generation, keep latency bounded
+const MAX_BENCHMARK_ITERATIONS: usize = 100;
+const MAX_SESSIONS: usize = 32; // Translation sessions open at once
+const MAX_SESSION_TURNS: usize = 8; // Earlier exchanges shown to the model in a session
+const TOKEN_CHANNEL_CAPACITY: usize = 32; // Output pieces buffered ahead of a slow stream consumer
+const COMPUTE_BUFFER_BYTES: u64 = 256 * 1_048_576; // Inference scratch memory beyond weights and KV
+const FURIGANA_PROMPT: &str = "Rewrite the Japanese text in hiragana.";
+const HEALTH_CHECK_TEXT: &str = "Hello";
+const HEALTH_CHECK_TIMEOUT_MS: u64 = 30_000;
+// Translated by `benchmark`, long enough that per-token costs dominate
+const BENCHMARK_TEXT: &str = "The committee met on Tuesday morning to review the budget for next year. \
+After a long discussion, they agreed to increase funding for the public library \
+and to repair the roads near the station before winter.";
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum TranslationDirection {
-    EnglishToJapanese,
-    JapaneseToEnglish,
+/// The language translated from and the language translated into
+///
+/// Serialized as its code, e.g. `"en-ja"`. Supporting another pair means
+/// adding it to `SUPPORTED_PAIRS`, and any new language to `Language`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LanguagePair {
+    pub source: Language,
+    pub target: Language,
 }
 
-// Model state holding the loaded model and context
-pub struct ModelState {
-    backend: LlamaBackend,
-    model: Option<LlamaModel>,
-    is_loaded: bool,
-}
+impl LanguagePair {
+    pub const EN_JA: LanguagePair = LanguagePair {
+        source: Language::English,
+        target: Language::Japanese,
+    };
+    pub const JA_EN: LanguagePair = LanguagePair {
+        source: Language::Japanese,
+        target: Language::English,
+    };
 
-pub struct TranslationService {
-    model_state: Arc<Mutex<ModelState>>,
-    model_path: PathBuf,
+    /// The pair translating `source` into `target`, if it is supported
+    pub fn new(source: Language, target: Language) -> std::result::Result<Self, TranslationError> {
+        let pair = LanguagePair { source, target };
+        if SUPPORTED_PAIRS.contains(&pair) {
+            Ok(pair)
+        } else {
+            Err(TranslationError::InvalidDirection {
+                direction: pair.code(),
+            })
+        }
+    }
+
+    /// The code the frontend uses for this pair, e.g. `"en-ja"`
+    pub fn code(&self) -> String {
+        format!("{}-{}", self.source.code(), self.target.code())
+    }
+
+    /// Display details for this pair's languages
+    pub fn info(&self) -> LanguagePairInfo {
+        LanguagePairInfo {
+            code: self.code(),
+            source_code: self.source.code(),
+            source_name: self.source.name(),
+            source_flag: self.source.flag(),
+            target_code: self.target.code(),
+            target_name: self.target.name(),
+            target_flag: self.target.flag(),
+        }
+    }
+
+    /// The pair translating back to the source language
+    pub fn reverse(&self) -> LanguagePair {
+        LanguagePair {
+            source: self.target,
+            target: self.source,
+        }
+    }
+
+    /// The built-in system prompt for this pair
+    fn default_system_prompt(&self) -> &'static str {
+        self.target.system_prompt()
+    }
 }
 
-impl TranslationService {
-    /// Create a new TranslationService instance
-    pub fn new() -> Result<Self> {
-        // Get the cache directory for storing the model
-        let cache_dir = Self::get_cache_dir()?;
-        let model_path = cache_dir.join(MODEL_FILE);
-        
-        // Initialize the LlamaBackend
-        let backend = LlamaBackend::init()
-            .context("Failed to initialize LlamaBackend")?;
-        
-        let model_state = ModelState {
-            backend,
-            model: None,
-            is_loaded: false,
+// The pairs the model translates
+const SUPPORTED_PAIRS: [LanguagePair; 2] = [LanguagePair::EN_JA, LanguagePair::JA_EN];
+
+impl FromStr for LanguagePair {
+    type Err = TranslationError;
+
+    /// Parse a pair code like `"en-ja"`, ignoring case and surrounding
+    /// whitespace
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || TranslationError::InvalidDirection {
+            direction: s.to_string(),
         };
-        
-        Ok(Self {
-            model_state: Arc::new(Mutex::new(model_state)),
-            model_path,
-        })
+        let (source, target) = s.split_once('-').ok_or_else(invalid)?;
+        let source = Language::from_code(source).ok_or_else(invalid)?;
+        let target = Language::from_code(target).ok_or_else(invalid)?;
+        LanguagePair::new(source, target).map_err(|_| invalid())
     }
-    
-    /// Get the cache directory for storing models
-    fn get_cache_dir() -> Result<PathBuf> {
-        let proj_dirs = ProjectDirs::from("com", "konnyaku", "konnyaku")
-            .context("Failed to determine project directories")?;
-        
-        let cache_dir = proj_dirs.cache_dir().join("models");
-        std::fs::create_dir_all(&cache_dir)
-            .context("Failed to create cache directory")?;
-        
-        Ok(cache_dir)
+}
+
+impl fmt::Display for LanguagePair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.code())
     }
-    
-    /// Download the model from HuggingFace if not cached
-    pub async fn ensure_model_downloaded(&self) -> Result<()> {
-        if self.model_path.exists() {
-            println!("Model already cached at: {:?}", self.model_path);
-            return Ok(());
+}
+
+impl Serialize for LanguagePair {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for LanguagePair {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let code = String::deserialize(deserializer)?;
+        // Settings saved before pairs were keyed by their code
+        match code.as_str() {
+            "EnglishToJapanese" => Ok(LanguagePair::EN_JA),
+            "JapaneseToEnglish" => Ok(LanguagePair::JA_EN),
+            _ => code.parse().map_err(serde::de::Error::custom),
         }
-        
-        println!("Downloading model from HuggingFace...");
-        println!("Model: {}/{}", MODEL_REPO, MODEL_FILE);
-        
-        // Ensure the parent directory exists
-        if let Some(parent) = self.model_path.parent() {
-            tokio::fs::create_dir_all(parent)
-                .await
-                .context("Failed to create model directory")?;
+    }
+}
+
+/// Every supported pair, the list frontends should offer
+pub fn supported_pairs() -> &'static [LanguagePair] {
+    &SUPPORTED_PAIRS
+}
+
+/// A language the model translates from or into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Language {
+    English,
+    Japanese,
+}
+
+impl Language {
+    pub const ALL: [Language; 2] = [Language::English, Language::Japanese];
+
+    /// The ISO 639-1 code, e.g. `"ja"`
+    pub fn code(self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::Japanese => "ja",
         }
-        
-        // Try direct download first as it's often faster
-        let direct_url = format!(
-            "https://huggingface.co/{}/resolve/main/{}",
-            MODEL_REPO, MODEL_FILE
-        );
-        
-        println!("Attempting direct download from: {}", direct_url);
-        
-        match self.download_file_direct(&direct_url).await {
-            Ok(()) => {
-                println!("Model downloaded successfully via direct download");
-                return Ok(());
-            }
-            Err(e) => {
-                eprintln!("Direct download failed: {}, trying HuggingFace API...", e);
-            }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::English => "English",
+            Language::Japanese => "Japanese",
         }
-        
-        // Fallback to HuggingFace API
-        let download_timeout = std::time::Duration::from_secs(300);
-        
-        let api = Api::new()
-            .context("Failed to create HuggingFace API")?;
-        let repo = api.model(MODEL_REPO.to_string());
-        
-        let download_future = async {
-            println!("Starting HuggingFace API download...");
-            let model_file = repo.get(MODEL_FILE).await
-                .context("Failed to download model from HuggingFace")?;
-            
-            println!("Download complete, copying to cache...");
-            
-            // Copy to cache location
-            tokio::fs::copy(&model_file, &self.model_path)
-                .await
-                .context("Failed to copy model to cache")?;
-            
-            Ok::<(), anyhow::Error>(())
-        };
-        
-        match tokio::time::timeout(download_timeout, download_future).await {
-            Ok(Ok(())) => {
-                println!("Model downloaded successfully to: {:?}", self.model_path);
-                Ok(())
-            }
-            Ok(Err(e)) => {
-                eprintln!("HuggingFace API download failed: {}", e);
-                eprintln!("\nPlease try downloading the model manually:");
-                eprintln!("1. Download from: {}", direct_url);
-                eprintln!("2. Save to: {:?}", self.model_path);
-                Err(e)
-            }
-            Err(_) => {
-                let err = anyhow::anyhow!("Model download timed out after 5 minutes");
-                eprintln!("{}", err);
-                eprintln!("\nPlease try downloading the model manually:");
-                eprintln!("1. Download from: {}", direct_url);
-                eprintln!("2. Save to: {:?}", self.model_path);
-                Err(err)
-            }
+    }
+
+    pub fn flag(self) -> &'static str {
+        match self {
+            Language::English => "🇺🇸",
+            Language::Japanese => "🇯🇵",
         }
     }
-    
-    /// Direct download using reqwest (simpler than HuggingFace API)
-    async fn download_file_direct(&self, url: &str) -> Result<()> {
-        use tokio::io::AsyncWriteExt;
-        
-        let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(300))
-            .build()?;
-        
-        let response = client
-            .get(url)
-            .send()
-            .await
-            .context("Failed to start download")?;
-        
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!("HTTP error: {}", response.status()));
+
+    /// The language with this ISO 639-1 code or English name, ignoring case
+    pub fn from_code(code: &str) -> Option<Self> {
+        let code = code.trim();
+        Language::ALL.into_iter().find(|language| {
+            code.eq_ignore_ascii_case(language.code()) || code.eq_ignore_ascii_case(language.name())
+        })
+    }
+
+    /// The built-in system prompt for translating into this language
+    fn system_prompt(self) -> &'static str {
+        match self {
+            Language::English => SYSTEM_PROMPT_JA_TO_EN,
+            Language::Japanese => SYSTEM_PROMPT_EN_TO_JA,
         }
-        
-        let total_size = response
-            .content_length()
-            .unwrap_or(0);
-        
-        println!("Download size: {} MB", total_size / 1_048_576);
-        
-        let mut file = tokio::fs::File::create(&self.model_path)
-            .await
-            .context("Failed to create file")?;
-        
-        let mut downloaded = 0u64;
-        let mut stream = response.bytes_stream();
-        
-        use futures_util::StreamExt;
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk.context("Error while downloading chunk")?;
-            file.write_all(&chunk)
-                .await
-                .context("Failed to write to file")?;
-            
-            downloaded += chunk.len() as u64;
-            
-            // Print progress every 10MB
-            if downloaded % (10 * 1_048_576) == 0 || downloaded == total_size {
-                let progress = if total_size > 0 {
-                    (downloaded as f64 / total_size as f64 * 100.0) as u32
-                } else {
-                    0
+    }
+}
+
+/// A direction as frontends send it: a code like `"en-ja"`, or its two
+/// languages as `{ "source": "en", "target": "ja" }`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DirectionSpec {
+    Code(String),
+    Pair { source: String, target: String },
+}
+
+impl DirectionSpec {
+    /// The supported direction this names
+    pub fn direction(&self) -> std::result::Result<LanguagePair, TranslationError> {
+        match self {
+            DirectionSpec::Code(code) => code.parse(),
+            DirectionSpec::Pair { source, target } => {
+                let invalid = || TranslationError::InvalidDirection {
+                    direction: format!("{}-{}", source, target),
                 };
-                println!("Download progress: {} MB / {} MB ({}%)",
-                         downloaded / 1_048_576,
-                         total_size / 1_048_576,
-                         progress);
+                let source = Language::from_code(source).ok_or_else(invalid)?;
+                let target = Language::from_code(target).ok_or_else(invalid)?;
+                LanguagePair::new(source, target)
             }
         }
-        
-        file.flush().await?;
-        println!("Download complete!");
-        
-        Ok(())
     }
-    
-    /// Initialize the model if not already loaded
-    pub async fn ensure_model_loaded(&self) -> Result<()> {
-        let mut state = self.model_state.lock().await;
-        
-        if state.is_loaded {
-            return Ok(());
+}
+
+/// A supported language pair, with what the UI needs to show it
+#[derive(Debug, Clone, Serialize)]
+pub struct LanguagePairInfo {
+    /// The pair code passed to commands, e.g. `"en-ja"`
+    pub code: String,
+    pub source_code: &'static str,
+    pub source_name: &'static str,
+    pub source_flag: &'static str,
+    pub target_code: &'static str,
+    pub target_name: &'static str,
+    pub target_flag: &'static str,
+}
+
+/// Per-request options controlling how text is translated
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TranslateOptions {
+    /// Keep code spans, fenced code blocks and link URLs out of the model's
+    /// hands so Markdown formatting survives translation
+    pub preserve_markdown: bool,
+    /// Treat the input as HTML and translate only its text, keeping every
+    /// tag, comment and script as it was. Takes precedence over
+    /// `preserve_markdown`.
+    pub preserve_html: bool,
+    /// Translate each blank-line separated paragraph on its own and join
+    /// them with the original separators, as the model tends to merge
+    /// paragraphs otherwise. Ignored with `preserve_html`, whose text nodes
+    /// are already translated one by one.
+    pub preserve_paragraphs: bool,
+    /// Trim whitespace from the output, on by default. Off, the output is
+    /// given the leading and trailing whitespace of the input instead, for
+    /// translating pieces of a larger document; the model's own is dropped
+    /// either way, as it is usually just a newline before the end token.
+    /// Paragraphs translated separately by `preserve_paragraphs` always keep
+    /// the whitespace around them, so this only matters for text translated
+    /// in one piece.
+    pub trim_output: bool,
+    /// Cap on generated tokens, defaults to `MAX_TOKENS`
+    pub max_tokens: Option<i32>,
+    /// Strings that end generation when produced, and are stripped from the
+    /// output. Defaults to the chat-template role markers, plus a blank line
+    /// when the input itself is a single paragraph.
+    pub stop_sequences: Option<Vec<String>>,
+    /// How tokens are picked during generation. Left at the default
+    /// (greedy), `preset` or the direction's default preset decide instead.
+    pub sampling: SamplingConfig,
+    /// A `SamplingPreset` by name ("literal", "balanced" or "natural"),
+    /// used when `sampling` isn't set
+    pub preset: Option<String>,
+    /// Give up on generation after this many milliseconds, no limit by default
+    pub timeout_ms: Option<u64>,
+    /// Generate up to this many distinct candidates (at most
+    /// `MAX_ALTERNATIVES`) using sampling, 0 or 1 for a single result.
+    /// With `beam_width` the candidates are the best beams instead, at most
+    /// `beam_width` of them and without further generations, for text that
+    /// is translated in one piece.
+    pub n_alternatives: usize,
+    /// Keep this many candidate outputs going at each token (at most
+    /// `MAX_BEAM_WIDTH`) and return the one whose tokens are most likely on
+    /// average, 0 or 1 for plain greedy decoding (the default). Each beam
+    /// is about one more decode per token and one more copy of the KV
+    /// cache, so a width of 4 makes generation roughly 3-4x slower. Only
+    /// works with greedy sampling, and without `grammar` or
+    /// `include_logprobs`.
+    pub beam_width: usize,
+    /// Record the log probability of each generated token. Off by default,
+    /// as it costs a softmax over the whole vocabulary per token.
+    pub include_logprobs: bool,
+    /// Replaces the system prompt for this call, e.g. to ask for a formal
+    /// tone or give a domain hint
+    pub system_prompt: Option<String>,
+    /// Apply Unicode NFKC normalization to the input before it reaches the
+    /// model, on by default. This folds full-width ASCII (`ＡＢＣ１２３`) and
+    /// half-width katakana (`ｶﾀｶﾅ`) into their usual forms, which tokenize
+    /// better. It also rewrites compatibility characters that may be
+    /// intentional in creative text, such as `①` to `1` or `㈱` to `(株)`,
+    /// so turn it off when those must reach the model untouched. Code and
+    /// URLs protected by `preserve_markdown` are never normalized.
+    pub normalize_input: bool,
+    /// Remove chat-template control markers such as `<|im_start|>` from the
+    /// input, off by default. The input already goes in its own user turn,
+    /// but it is tokenized with special tokens enabled, so without this a
+    /// marker in it can end the turn and pose as the system prompt. Turn it
+    /// on for input from untrusted sources. Instruction-like phrases are
+    /// still translated and only logged.
+    pub sanitize_input: bool,
+    /// Also transliterate Japanese output to romaji, off by default. Kanji
+    /// are read by asking the model to write each line in kana, as for
+    /// `with_furigana`, so the romaji is best-effort: a line whose reading
+    /// doesn't fit the text keeps its kanji. Both options share one reading.
+    pub with_romaji: bool,
+    /// Also return Japanese output with its kanji annotated with readings,
+    /// as HTML ruby, off by default. The readings come from asking the
+    /// model to write each line in kana, which costs a second generation
+    /// and is best-effort: lines whose answer doesn't fit the text are left
+    /// unannotated.
+    pub with_furigana: bool,
+    /// When translating long text in pieces, show the model this many
+    /// sentences from the end of the previous piece, with their
+    /// translation, so the pieces read as one text. 0 (the default) sends
+    /// each piece on its own.
+    pub overlap_sentences: usize,
+    /// A GBNF grammar (with a `root` rule) the output must match, e.g. to
+    /// get a translation back in a fixed JSON shape. This constrains every
+    /// token and can make translations less fluent, so keep it loose.
+    pub grammar: Option<String>,
+    /// Time tokenization, prompt processing and generation separately and
+    /// return the figures with the translation. Such requests always run
+    /// inference, the cache is bypassed so the timings are real.
+    pub collect_timings: bool,
+    /// Source texts and translations shown to the model as earlier turns,
+    /// set by `translate_long` for overlap and by sessions. They are only
+    /// context and never part of the output.
+    #[serde(skip_deserializing, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) context: Vec<(String, String)>,
+    /// The session whose last prompt this one may continue, set by
+    /// `translate_in_session`. The output is the same either way.
+    #[serde(skip)]
+    pub(crate) session: Option<u64>,
+}
+
+impl Default for TranslateOptions {
+    fn default() -> Self {
+        Self {
+            preserve_markdown: false,
+            preserve_html: false,
+            preserve_paragraphs: false,
+            trim_output: true,
+            max_tokens: None,
+            stop_sequences: None,
+            sampling: SamplingConfig::default(),
+            preset: None,
+            timeout_ms: None,
+            n_alternatives: 0,
+            beam_width: 0,
+            include_logprobs: false,
+            system_prompt: None,
+            normalize_input: true,
+            sanitize_input: false,
+            with_romaji: false,
+            with_furigana: false,
+            overlap_sentences: 0,
+            grammar: None,
+            collect_timings: false,
+            context: Vec::new(),
+            session: None,
         }
-        
-        // Ensure model is downloaded
-        drop(state); // Release lock temporarily
-        self.ensure_model_downloaded().await?;
-        state = self.model_state.lock().await; // Re-acquire lock
-        
-        println!("Loading model from: {:?}", self.model_path);
-        
-        let model_params = LlamaModelParams::default()
-            .with_n_gpu_layers(0); // Offload no layers to GPU.
-            
-        
-        // Load the model
-        let model = LlamaModel::load_from_file(
-            &state.backend,
-            &self.model_path,
-            &model_params,
-        )
-        .context("Failed to load model")?;
-        
-        state.model = Some(model);
-        state.is_loaded = true;
-        
-        println!("Model loaded successfully");
-        Ok(())
     }
-    
-    /// Translate text based on the specified direction
-    pub async fn translate(
-        &self,
-        text: &str,
-        direction: TranslationDirection,
-    ) -> Result<String> {
-        // Ensure model is loaded
-        self.ensure_model_loaded().await?;
-        
-        let state = self.model_state.lock().await;
-        let model = state.model.as_ref()
-            .context("Model not loaded")?;
-        
-        // Get the appropriate system prompt
-        let system_prompt = match direction {
-            TranslationDirection::EnglishToJapanese => SYSTEM_PROMPT_EN_TO_JA,
-            TranslationDirection::JapaneseToEnglish => SYSTEM_PROMPT_JA_TO_EN,
+}
+
+impl TranslateOptions {
+    /// `text` as it should be shown to the model
+    fn prepare_input<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let text = if self.normalize_input && is_nfkc_quick(text.chars()) != IsNormalized::Yes {
+            Cow::Owned(text.nfkc().collect())
+        } else {
+            Cow::Borrowed(text)
         };
-        
-        // Get the chat template from the model
-        let chat_template = model
-            .chat_template(None)  // None = use default template
-            .unwrap_or_else(|_| {
-                // Fallback to chatml if no template in model
-                LlamaChatTemplate::new("chatml")
-                    .expect("Failed to create chatml template")
-            });
-        
-        // Create chat messages
-        let chat = vec![
-            LlamaChatMessage::new("system".to_string(), system_prompt.to_string())
-                .context("Failed to create system message")?,
-            LlamaChatMessage::new("user".to_string(), text.to_string())
-                .context("Failed to create user message")?,
-        ];
-        
-        // Apply the chat template
-        let full_prompt = model
-            .apply_chat_template(&chat_template, &chat, true)
-            .context("Failed to apply chat template")?;
-        
-        
-        // Create context parameters
-        let ctx_params = LlamaContextParams::default()
-            .with_n_ctx(Some(NonZeroU32::new(CONTEXT_SIZE).unwrap()))
-            .with_n_threads(4); // Use 4 threads for CPU inference
-        
-        // Create a new context for this translation
-        let mut ctx = model
-            .new_context(&state.backend, ctx_params)
-            .context("Failed to create context")?;
-        
-        // Tokenize the prompt - AddBos depends on model's expectation
-        // Try with AddBos::Always first as many models expect it
-        let tokens_list = model
-            .str_to_token(&full_prompt, AddBos::Always)
-            .context("Failed to tokenize prompt")?;
-        
-        // Create a batch for processing
-        let mut batch = LlamaBatch::new(512, 1);
-        
-        // Add all prompt tokens to the batch
-        // Only request logits for the last token
-        let last_index = (tokens_list.len() - 1) as i32;
-        for (i, token) in (0_i32..).zip(tokens_list.iter()) {
-            let is_last = i == last_index;
-            batch.add(*token, i, &[0], is_last)?;
+        if !self.sanitize_input {
+            return text;
         }
-        
-        // Process the prompt
-        ctx.decode(&mut batch)
-            .context("Failed to decode prompt")?;
-        
-        // Initialize generation position
-        let mut n_cur = batch.n_tokens();
-        
-        // Initialize the decoder for UTF-8 output
-        let mut decoder = encoding_rs::UTF_8.new_decoder();
-        
-        // Create a sampler for token generation
-        // Using greedy sampling for deterministic output (best for translation)
-        let mut sampler = LlamaSampler::greedy();
-        
-        // Generate the translation
-        let mut translation = String::new();
-        let n_len = n_cur + MAX_TOKENS;
-        
-        while n_cur <= n_len {
-            // Sample the next token
-            // Key: Use batch.n_tokens() - 1, not n_cur - 1!
-            let token = sampler.sample(&ctx, batch.n_tokens() - 1);
-            sampler.accept(token);
-            
-            // Check for end of stream
-            if token == model.token_eos() {
-                break;
-            }
-            
-            // Convert token to text
-            let output_bytes = model
-                .token_to_bytes(token, Special::Tokenize)
-                .context("Failed to convert token to bytes")?;
-            
-            // Decode bytes to string
-            let mut output_string = String::with_capacity(32);
-            let _decode_result = decoder.decode_to_string(&output_bytes, &mut output_string, false);
-            
-            // Add to translation
-            translation.push_str(&output_string);
-            
-            // Prepare for next iteration
-            batch.clear();
-            batch.add(token, n_cur, &[0], true)?;
-            
-            n_cur += 1;
-            
-            // Process the new token
-            ctx.decode(&mut batch)
-                .context("Failed to decode next token")?;
+
+        if let Some(phrase) = sanitize::find_override(&text) {
+            tracing::warn!(phrase, "Input looks like an attempt to override the prompt");
+        }
+        match text {
+            Cow::Borrowed(text) => sanitize::sanitize(text),
+            Cow::Owned(text) => Cow::Owned(sanitize::sanitize(&text).into_owned()),
         }
-        
-        // Clean up the translation (remove any extra whitespace)
-        let translation = translation.trim().to_string();
-        
-        Ok(translation)
     }
-    
-    /// Check if the model is currently loaded
-    pub async fn is_model_loaded(&self) -> bool {
-        self.model_state.lock().await.is_loaded
+
+    /// Whether the same input always produces the same result
+    fn is_deterministic(&self) -> bool {
+        let alternatives_reproducible =
+            self.n_alternatives <= 1 || self.sampling.seed.is_some() || self.beam_search();
+        self.sampling.is_deterministic() && alternatives_reproducible
     }
-}
 
-// Make TranslationService thread-safe and Send
-unsafe impl Send for TranslationService {}
-unsafe impl Sync for TranslationService {}
+    /// Whether generation keeps several beams
+    fn beam_search(&self) -> bool {
+        self.beam_width > 1
+    }
 
-// Implementation notes:
-// 1. Using LlamaBackend::init() to initialize the backend once
-// 2. Loading model with LlamaModel::load_from_file()
-// 3. Creating a new context for each translation to ensure clean state
-// 4. Using greedy sampling for deterministic translations
+    /// Reject beam search settings it can't honor
+    fn validate_beam_search(&self) -> Result<()> {
+        if !self.beam_search() {
+            return Ok(());
+        }
+        if self.beam_width > MAX_BEAM_WIDTH {
+            anyhow::bail!(
+                "beam_width must be at most {}, got {}",
+                MAX_BEAM_WIDTH,
+                self.beam_width
+            );
+        }
+        if !self.sampling.is_greedy() {
+            anyhow::bail!("Beam search can't be combined with sampling");
+        }
+        if self.grammar.is_some() {
+            anyhow::bail!("Beam search can't be combined with a grammar");
+        }
+        if self.include_logprobs {
+            anyhow::bail!("Beam search can't be combined with include_logprobs");
+        }
+        Ok(())
+    }
+
+    /// The stop sequences to use when translating `text`
+    fn effective_stop_sequences(&self, text: &str) -> Vec<String> {
+        if let Some(stops) = &self.stop_sequences {
+            return stops.iter().filter(|s| !s.is_empty()).cloned().collect();
+        }
+
+        let mut stops: Vec<String> = DEFAULT_STOP_SEQUENCES.iter().map(|s| s.to_string()).collect();
+        // A blank line after single-paragraph input is the start of an extra turn
+        if !text.contains("\n\n") {
+            stops.push("\n\n".to_string());
+        }
+        stops
+    }
+}
+
+/// The result of a translation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Translation {
+    pub text: String,
+    /// Generation hit the token cap before the model finished
+    pub truncated: bool,
+    /// Distinct candidates, starting with `text`, when alternatives were
+    /// requested
+    pub alternatives: Vec<String>,
+    /// Per-token log probabilities, when requested
+    pub confidence: Option<Confidence>,
+    /// Romaji transliteration of `text`, when requested for Japanese output
+    pub romaji: Option<String>,
+    /// `text` as HTML with ruby readings over the kanji, when requested for
+    /// Japanese output
+    pub furigana: Option<String>,
+    /// Where the time went, when `collect_timings` was set. Summed over the
+    /// pieces when the text was translated in several generations.
+    pub timings: Option<GenerationTimings>,
+}
+
+impl Translation {
+    // What translating nothing gives
+    fn empty() -> Self {
+        Self {
+            text: String::new(),
+            truncated: false,
+            alternatives: Vec::new(),
+            confidence: None,
+            romaji: None,
+            furigana: None,
+            timings: None,
+        }
+    }
+}
+
+/// How sure the model was of its output
+///
+/// Log probabilities are natural logs in (-inf, 0]: 0 means certain, -0.7 is
+/// roughly a coin flip and anything below -2.3 had under a 10% chance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Confidence {
+    /// Generated tokens in order, as the model produced them (before
+    /// Markdown restoration and trimming)
+    pub tokens: Vec<TokenLogprob>,
+    /// Mean log probability over `tokens`, 0 when nothing was generated
+    pub mean_logprob: f32,
+}
+
+impl Confidence {
+    fn new(tokens: Vec<TokenLogprob>) -> Self {
+        let mean_logprob = if tokens.is_empty() {
+            0.0
+        } else {
+            tokens.iter().map(|t| t.logprob).sum::<f32>() / tokens.len() as f32
+        };
+        Self {
+            tokens,
+            mean_logprob,
+        }
+    }
+}
+
+/// A generated token and its log probability
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenLogprob {
+    pub text: String,
+    pub logprob: f32,
+}
+
+/// A translation and its back-translation into the source language
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoundTrip {
+    pub translation: String,
+    pub round_tripped: String,
+    /// `quality::similarity` between the input and `round_tripped`
+    pub similarity: f32,
+}
+
+/// What inference is actually running on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackendInfo {
+    /// "Metal", "GPU" or "CPU"
+    pub backend: String,
+    /// Whether this build of llama.cpp can offload to a GPU at all
+    pub gpu_support: bool,
+    /// Layers offloaded to the GPU, known once the model is loaded
+    pub gpu_layers: Option<u32>,
+    /// Loading on the GPU failed and the model runs on the CPU instead
+    pub cpu_fallback: bool,
+    pub num_threads: i32,
+    pub context_size: u32,
+}
+
+/// Approximate memory use, for diagnostics
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryStats {
+    /// Resident memory of the whole app process, if the platform reports it
+    pub resident_bytes: Option<u64>,
+    /// Size of the model file, a proxy for the memory it takes once loaded
+    pub model_bytes: u64,
+    pub model_loaded: bool,
+    /// Whether model layers are offloaded to the GPU
+    pub gpu_in_use: bool,
+}
+
+/// Whether a cached model is likely to fit, see
+/// `TranslationService::estimate_model_memory`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryEstimate {
+    /// Weights, KV cache at the configured context size and compute buffers
+    pub estimated_bytes: u64,
+    /// Memory the system reports as available right now
+    pub available_bytes: u64,
+    pub fits: bool,
+}
+
+/// The results of `TranslationService::health_check`, for bug reports
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub model_downloaded: bool,
+    pub model_loaded: bool,
+    pub cache_dir: String,
+    pub cache_dir_writable: bool,
+    /// Free space on the cache directory's volume, if it could be read
+    pub free_disk_bytes: Option<u64>,
+    /// "Metal", "GPU" or "CPU"
+    pub backend: String,
+    pub num_threads: i32,
+    /// Translation of a short English phrase, when the model is downloaded
+    pub test_translation: Option<String>,
+    /// What went wrong in each check that failed, as "check: error"
+    pub errors: Vec<String>,
+}
+
+/// How long one generation took, split into its phases
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerationTimings {
+    pub prompt_tokens: usize,
+    pub generated_tokens: usize,
+    /// Time to turn the prompt into tokens
+    pub tokenize_ms: f64,
+    /// Tokens in the context created for the generation, which is sized to
+    /// fit the prompt and `max_tokens`
+    pub context_size: u32,
+    /// Time to create the context
+    pub context_ms: f64,
+    /// Time to process the prompt before the first token
+    pub prompt_eval_ms: f64,
+    /// Time from the start of generation, after the model is loaded and its
+    /// lock acquired, to the first output token, prompt included. Waiting for
+    /// a turn or a model load isn't counted. Beam search only settles on its
+    /// output at the end, so there it is the whole generation.
+    pub time_to_first_token_ms: f64,
+    /// Time spent producing the output tokens
+    pub generation_ms: f64,
+    /// Generated tokens per second of generation time
+    pub tokens_per_sec: f64,
+    /// Tokens guessed by the draft model, zero when decoding without one
+    #[serde(default)]
+    pub draft_tokens: usize,
+    /// Guesses the model agreed with and kept
+    #[serde(default)]
+    pub accepted_draft_tokens: usize,
+}
+
+impl GenerationTimings {
+    /// Add `other` to these timings, as if both ran as one generation
+    fn add(&mut self, other: &GenerationTimings) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.generated_tokens += other.generated_tokens;
+        self.tokenize_ms += other.tokenize_ms;
+        self.context_size = self.context_size.max(other.context_size);
+        self.context_ms += other.context_ms;
+        self.prompt_eval_ms += other.prompt_eval_ms;
+        self.generation_ms += other.generation_ms;
+        self.draft_tokens += other.draft_tokens;
+        self.accepted_draft_tokens += other.accepted_draft_tokens;
+        // time_to_first_token_ms stays this generation's, its token came first
+        self.tokens_per_sec =
+            self.generated_tokens as f64 / (self.generation_ms / 1000.0).max(f64::EPSILON);
+    }
+}
+
+/// What translating a text would send to the model, worked out without
+/// generating anything
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DryRun {
+    /// The prompt exactly as tokenized, chat template applied
+    pub prompt: String,
+    pub prompt_tokens: usize,
+    pub prompt_format: PromptFormat,
+    /// Pieces `translate_long` would split the text into
+    pub chunks: usize,
+    /// Sampling after presets and the direction's default are applied
+    pub sampling: SamplingConfig,
+    pub stop_sequences: Vec<String>,
+    /// Most tokens generation could produce
+    pub max_new_tokens: i32,
+    /// Tokens in the context that would be created
+    pub context_size: u32,
+}
+
+/// Throughput over repeated runs of a fixed translation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Benchmark {
+    /// One entry per timed run, to show the variance between them
+    pub runs: Vec<GenerationTimings>,
+    pub mean_tokens_per_sec: f64,
+    pub mean_context_ms: f64,
+    pub mean_prompt_eval_ms: f64,
+    pub mean_time_to_first_token_ms: f64,
+    pub mean_generation_ms: f64,
+    /// With a draft model set, the mean of as many runs again decoded
+    /// without it, to show what speculation gains
+    pub mean_tokens_per_sec_without_draft: Option<f64>,
+    /// Share of the draft's guesses the model kept over the timed runs
+    pub draft_acceptance: Option<f64>,
+}
+
+/// Where the model is in getting ready to translate
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "phase", rename_all = "snake_case")]
+pub enum ModelPhase {
+    /// Not loaded, and nothing is in progress
+    Idle,
+    Downloading,
+    Loading,
+    /// Loaded and ready to translate
+    Ready,
+    /// The last download or load failed
+    Failed { error: String },
+}
+
+/// Whether translations can run right now, and if not, why
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Readiness {
+    pub ready: bool,
+    /// "not downloaded", "not loaded", "downloading", "loading",
+    /// "download failed: ...", "load failed: ..." or, when the service
+    /// couldn't be created, "init failed: ...", None when ready
+    pub reason: Option<String>,
+}
+
+impl Readiness {
+    fn new(phase: ModelPhase, downloaded: bool) -> Self {
+        let reason = match phase {
+            ModelPhase::Ready => None,
+            ModelPhase::Idle if downloaded => Some("not loaded".to_string()),
+            ModelPhase::Idle => Some("not downloaded".to_string()),
+            ModelPhase::Downloading => Some("downloading".to_string()),
+            ModelPhase::Loading => Some("loading".to_string()),
+            // A failed download leaves nothing on disk
+            ModelPhase::Failed { error } if downloaded => Some(format!("load failed: {}", error)),
+            ModelPhase::Failed { error } => Some(format!("download failed: {}", error)),
+        };
+        Self {
+            ready: reason.is_none(),
+            reason,
+        }
+    }
+}
+
+/// How far the model download has got
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadProgress {
+    pub in_progress: bool,
+    pub downloaded_bytes: u64,
+    /// None when the server didn't send the size, or the download went
+    /// through the HuggingFace API, which doesn't report progress
+    pub total_bytes: Option<u64>,
+    /// Share of `total_bytes` downloaded, 0 to 100
+    pub percent: Option<f64>,
+    #[serde(flatten)]
+    pub phase: ModelPhase,
+}
+
+/// Where the model lives on disk and how much space it takes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelCacheInfo {
+    pub path: String,
+    pub size_bytes: u64,
+    pub exists: bool,
+}
+
+/// What `repair_model` checked and downloaded again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelRepair {
+    /// Every file of the model
+    pub checked: Vec<String>,
+    /// Files that were missing, the wrong size or failed their checksum
+    pub repaired: Vec<String>,
+}
+
+/// Size and checksum the model host publishes for a file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RemoteFile {
+    pub size: Option<u64>,
+    /// Lowercase hex SHA-256, published for files stored with Git LFS
+    pub sha256: Option<String>,
+}
+
+impl RemoteFile {
+    /// Read HuggingFace's response headers for a `resolve` URL
+    ///
+    /// LFS files answer with a redirect whose `x-linked-size` and
+    /// `x-linked-etag` headers describe the file. Others report their own
+    /// length, and an ETag that is a git hash rather than a SHA-256.
+    pub fn from_headers(headers: &reqwest::header::HeaderMap) -> Self {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let size = header("x-linked-size")
+            .or_else(|| header(reqwest::header::CONTENT_LENGTH.as_str()))
+            .and_then(|size| size.trim().parse().ok());
+        let sha256 = header("x-linked-etag")
+            .map(|etag| etag.trim().trim_start_matches("W/").trim_matches('"').to_lowercase())
+            .filter(|etag| etag.len() == 64 && etag.chars().all(|c| c.is_ascii_hexdigit()));
+        Self { size, sha256 }
+    }
+}
+
+/// A GGUF file found in the model cache directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedModel {
+    pub file_name: String,
+    pub size_bytes: u64,
+    /// The file is (a shard of) the model this service uses
+    pub active: bool,
+    /// Active and currently loaded
+    pub loaded: bool,
+}
+
+/// Counts a download as in progress until dropped, however it ends
+pub(crate) struct DownloadGuard(Arc<AtomicUsize>);
+
+impl DownloadGuard {
+    fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        Self(Arc::clone(count))
+    }
+}
+
+impl Drop for DownloadGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Service settings that can be changed at runtime
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServiceConfig {
+    /// Largest context window in tokens, shared between the prompt and the
+    /// output. Each translation gets a window just big enough for its prompt
+    /// and `max_tokens`, so this caps the memory a long input can take.
+    pub context_size: u32,
+    /// Never touch the network, the model must already be on disk
+    pub offline: bool,
+    /// Free the model's memory after this many seconds without a
+    /// translation, 0 keeps it loaded
+    pub idle_timeout_secs: u64,
+    /// Prompt tokens processed per decode call. Larger batches speed up long
+    /// prompts at the cost of more compute memory.
+    pub n_batch: u32,
+    /// Use flash attention, which cuts attention memory and can speed up
+    /// long prompts on backends that support it
+    pub flash_attention: bool,
+    /// Longest text `translate` accepts, in characters. Longer documents go
+    /// through `translate_long` or `translate_file`.
+    pub max_input_chars: usize,
+    /// Seconds to wait for a connection to the model host before giving
+    /// up, 30 by default
+    pub connect_timeout_secs: u64,
+    /// Seconds one download attempt may take in total, an hour by default.
+    /// The direct download and the HuggingFace API fallback each get the
+    /// full time.
+    pub download_timeout_secs: u64,
+    /// Base URLs serving HuggingFace-style `<repo>/resolve/main/<file>`
+    /// paths, tried in order before the HuggingFace API. huggingface.co by
+    /// default.
+    pub download_mirrors: Vec<String>,
+    /// Labels stripped from the start of the output, compared
+    /// case-insensitively. Kept when the input starts with one of them.
+    pub output_prefixes: Vec<String>,
+    /// Sampling preset for requests in a direction that set neither
+    /// `sampling` nor `preset`. Directions without one decode greedily.
+    pub default_presets: HashMap<LanguagePair, SamplingPreset>,
+    /// Terms with a fixed translation, managed through `import_glossary`
+    /// and `export_glossary`. Terms of the input that the model leaves
+    /// untranslated are replaced with their translation in the output.
+    pub glossary: Vec<GlossaryEntry>,
+    /// A small model with the same vocabulary, loaded alongside the model
+    /// for speculative decoding. Set through `set_draft_model`.
+    pub draft_model: Option<PathBuf>,
+    /// Replacements for the built-in system prompt of a direction, set
+    /// through `set_default_system_prompt`
+    pub system_prompts: HashMap<LanguagePair, String>,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        Self {
+            context_size: CONTEXT_SIZE,
+            offline: false,
+            idle_timeout_secs: DEFAULT_IDLE_TIMEOUT_SECS,
+            n_batch: BATCH_SIZE,
+            flash_attention: false,
+            max_input_chars: MAX_INPUT_CHARS,
+            connect_timeout_secs: DOWNLOAD_CONNECT_TIMEOUT_SECS,
+            download_timeout_secs: DOWNLOAD_TIMEOUT_SECS,
+            download_mirrors: vec![HF_BASE_URL.to_string()],
+            output_prefixes: DEFAULT_OUTPUT_PREFIXES.iter().map(|p| p.to_string()).collect(),
+            default_presets: HashMap::new(),
+            glossary: Vec::new(),
+            draft_model: None,
+            system_prompts: HashMap::new(),
+        }
+    }
+}
+
+// Model state holding the loaded model and context
+//
+// The model must always be freed before the backend it was loaded with,
+// which the Drop impl below guarantees.
+pub struct ModelState {
+    backend: LlamaBackend,
+    model: Option<LlamaModel>,
+    is_loaded: bool,
+    // Loading with GPU layers failed and the model was loaded on the CPU
+    cpu_fallback: bool,
+    // Applied to every context created from the model
+    lora: Option<LoraAdapter>,
+    // Guesses tokens for the model to check, see speculative.rs
+    draft: Option<LlamaModel>,
+    // The last prompt of each session, computed with this model and adapter
+    prompts: HashMap<u64, CachedPrompt>,
+}
+
+// A LoRA adapter initialized on the loaded model, with its strength
+//
+// llama.cpp frees adapters together with their model, so this has to go
+// whenever the model does.
+struct LoraAdapter {
+    adapter: LlamaLoraAdapter,
+    scale: f32,
+}
+
+// SAFETY: the adapter is a pointer into weights llama.cpp owns alongside the
+// model, with no thread affinity. It is only reached through ModelState,
+// under the model lock, like the model itself.
+unsafe impl Send for LoraAdapter {}
+
+// A session's prompt as its last translation evaluated it
+//
+// The next prompt of the session starts with this one, so restoring the
+// context state saved after it leaves only the new exchange to evaluate.
+// That works for models with a recurrent state too, as nothing is dropped.
+struct CachedPrompt {
+    prompt: String,
+    tokens: Vec<LlamaToken>,
+    // A state only loads into a context like the one it was saved from
+    context_size: u32,
+    flash_attention: bool,
+    state: Vec<u8>,
+}
+
+impl ModelState {
+    fn unload(&mut self) {
+        self.prompts.clear();
+        self.lora = None;
+        self.draft = None;
+        self.model = None;
+        self.is_loaded = false;
+    }
+
+    // Whether a usable model is loaded. A state that disagrees with itself,
+    // as an interrupted load could leave it, is reset to unloaded.
+    fn check_loaded(&mut self) -> bool {
+        if self.is_loaded != self.model.is_some() {
+            tracing::warn!("Resetting inconsistent model state");
+            self.unload();
+        }
+        self.is_loaded
+    }
+}
+
+impl Drop for ModelState {
+    fn drop(&mut self) {
+        // Fields drop in declaration order, which would free the backend
+        // while the model still uses it
+        self.unload();
+    }
+}
+
+// One exchange remembered by a translation session
+struct SessionTurn {
+    direction: LanguagePair,
+    source: String,
+    translation: String,
+}
+
+// Translations keyed by (text, direction, options serialized to JSON)
+type CacheKey = (String, LanguagePair, String);
+
+// The files of the model in use, replaced together when switching models
+#[derive(Debug, Clone)]
+struct ModelFiles {
+    // The first shard of a split model, which llama.cpp is pointed at
+    file: String,
+    path: PathBuf,
+    // Every file of the model with its cache path, starting with file
+    shards: Vec<(String, PathBuf)>,
+}
+
+impl ModelFiles {
+    fn new(cache_dir: &Path, file: String, shard_files: Vec<String>) -> Self {
+        let shards = shard_files
+            .into_iter()
+            .map(|shard| {
+                let path = cache_dir.join(&shard);
+                (shard, path)
+            })
+            .collect();
+        Self {
+            path: cache_dir.join(&file),
+            file,
+            shards,
+        }
+    }
+}
+
+pub struct TranslationService {
+    model_state: Arc<Mutex<ModelState>>,
+    model_repo: String,
+    cache_dir: PathBuf,
+    // Only changed while holding the model lock, so a load sees one model
+    files: std::sync::RwLock<ModelFiles>,
+    num_threads: i32,
+    gpu_layers: u32,
+    force_cpu: bool,
+    // None when caching is disabled
+    cache: std::sync::Mutex<Option<LruCache<CacheKey, Translation>>>,
+    // Downloads running, or claimed and about to run
+    downloading: Arc<AtomicUsize>,
+    // Progress of the current or last download, 0 total when unknown
+    downloaded_bytes: AtomicU64,
+    total_bytes: AtomicU64,
+    download_cancel: Notify,
+    // Held while the model is fetched, so concurrent callers download once
+    download_lock: Mutex<()>,
+    config: std::sync::RwLock<ServiceConfig>,
+    // When a translation last ran, for the idle unloader
+    last_used: std::sync::Mutex<Instant>,
+    // A copy of the model shipped with the app, installed instead of downloading
+    bundled_model: std::sync::RwLock<Option<PathBuf>>,
+    // Bounds running plus queued translations, each of which holds a context
+    in_flight: Semaphore,
+    max_in_flight: usize,
+    // Read once the model is first loaded, kept across unloads
+    metadata: std::sync::RwLock<Option<ModelMetadata>>,
+    // Deterministic requests currently being translated, for coalescing
+    in_progress: std::sync::Mutex<HashMap<CacheKey, Arc<OnceCell<Translation>>>>,
+    // Kept outside ModelState, so reading it doesn't wait behind a load
+    // holding the model lock
+    phase: watch::Sender<ModelPhase>,
+    // Recent exchanges of each open session, by session id
+    sessions: std::sync::Mutex<HashMap<u64, VecDeque<SessionTurn>>>,
+    next_session_id: AtomicU64,
+    // Where runtime setting changes are saved, None for services built
+    // directly from a builder
+    settings: Option<SettingsStore>,
+}
+
+/// Configures and creates a `TranslationService`
+///
+/// Every setting defaults to what the app uses, so only the ones that differ
+/// need to be set.
+#[derive(Debug, Clone)]
+pub struct TranslationServiceBuilder {
+    model_repo: String,
+    model_file: String,
+    model_shards: Option<Vec<String>>,
+    cache_dir: Option<PathBuf>,
+    num_threads: i32,
+    gpu_layers: u32,
+    context_size: u32,
+    n_batch: u32,
+    flash_attention: bool,
+    max_input_chars: usize,
+    connect_timeout_secs: u64,
+    download_timeout_secs: u64,
+    force_cpu: bool,
+    offline: bool,
+    max_concurrent_translations: usize,
+    system_prompts: HashMap<LanguagePair, String>,
+}
+
+impl Default for TranslationServiceBuilder {
+    fn default() -> Self {
+        Self {
+            model_repo: MODEL_REPO.to_string(),
+            model_file: MODEL_FILE.to_string(),
+            model_shards: None,
+            cache_dir: None,
+            num_threads: NUM_THREADS,
+            gpu_layers: GPU_LAYERS,
+            context_size: CONTEXT_SIZE,
+            n_batch: BATCH_SIZE,
+            flash_attention: false,
+            max_input_chars: MAX_INPUT_CHARS,
+            connect_timeout_secs: DOWNLOAD_CONNECT_TIMEOUT_SECS,
+            download_timeout_secs: DOWNLOAD_TIMEOUT_SECS,
+            force_cpu: false,
+            offline: false,
+            max_concurrent_translations: MAX_CONCURRENT_TRANSLATIONS,
+            system_prompts: HashMap::new(),
+        }
+    }
+}
+
+impl TranslationServiceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// HuggingFace repository the model is downloaded from
+    pub fn model_repo(mut self, model_repo: impl Into<String>) -> Self {
+        self.model_repo = model_repo.into();
+        self
+    }
+
+    /// GGUF file within the repository
+    ///
+    /// For a model split with llama.cpp's naming
+    /// (`name-00001-of-00003.gguf`), pass the first file and the others are
+    /// found from its name.
+    pub fn model_file(mut self, model_file: impl Into<String>) -> Self {
+        self.model_file = model_file.into();
+        self.model_shards = None;
+        self
+    }
+
+    /// Every file of a split model, in order, for shards that don't follow
+    /// llama.cpp's naming. The first one is what gets loaded.
+    pub fn model_shards<I, S>(mut self, shards: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let shards: Vec<String> = shards.into_iter().map(Into::into).collect();
+        if let Some(first) = shards.first() {
+            self.model_file = first.clone();
+        }
+        self.model_shards = Some(shards);
+        self
+    }
+
+    /// Directory the model is cached in, `KONNYAKU_MODEL_DIR` or the platform
+    /// cache dir by default
+    pub fn cache_dir(mut self, cache_dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(cache_dir.into());
+        self
+    }
+
+    /// CPU threads used for inference
+    pub fn num_threads(mut self, num_threads: i32) -> Self {
+        self.num_threads = num_threads;
+        self
+    }
+
+    /// Model layers offloaded to the GPU
+    pub fn gpu_layers(mut self, gpu_layers: u32) -> Self {
+        self.gpu_layers = gpu_layers;
+        self
+    }
+
+    /// Context window in tokens, see `ServiceConfig::context_size`
+    pub fn context_size(mut self, context_size: u32) -> Self {
+        self.context_size = context_size;
+        self
+    }
+
+    /// Prompt tokens processed per decode call, see `ServiceConfig::n_batch`
+    pub fn n_batch(mut self, n_batch: u32) -> Self {
+        self.n_batch = n_batch;
+        self
+    }
+
+    /// Enable flash attention, see `ServiceConfig::flash_attention`
+    pub fn flash_attention(mut self, flash_attention: bool) -> Self {
+        self.flash_attention = flash_attention;
+        self
+    }
+
+    /// Longest text to translate in one go, see
+    /// `ServiceConfig::max_input_chars`
+    pub fn max_input_chars(mut self, max_input_chars: usize) -> Self {
+        self.max_input_chars = max_input_chars;
+        self
+    }
+
+    /// Download connect and total timeouts in seconds, see
+    /// `ServiceConfig::download_timeout_secs`
+    pub fn download_timeouts(mut self, connect_secs: u64, total_secs: u64) -> Self {
+        self.connect_timeout_secs = connect_secs;
+        self.download_timeout_secs = total_secs;
+        self
+    }
+
+    /// Keep everything, including the KV cache, on the CPU regardless of
+    /// `gpu_layers`
+    pub fn force_cpu(mut self, force_cpu: bool) -> Self {
+        self.force_cpu = force_cpu;
+        self
+    }
+
+    /// Only use a model already on disk, see `TranslationService::set_offline`
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// How many translations may be in flight before new ones fail with
+    /// `TranslationError::Busy`
+    pub fn max_concurrent_translations(mut self, max_concurrent_translations: usize) -> Self {
+        self.max_concurrent_translations = max_concurrent_translations;
+        self
+    }
+
+    /// Replace the built-in system prompt for `direction`, see
+    /// `TranslationService::set_default_system_prompt`
+    pub fn system_prompt(
+        mut self,
+        direction: LanguagePair,
+        prompt: impl Into<String>,
+    ) -> Self {
+        self.system_prompts.insert(direction, prompt.into());
+        self
+    }
+
+    /// Check the settings without building anything
+    pub fn validate(&self) -> Result<()> {
+        if self.num_threads <= 0 {
+            anyhow::bail!("Thread count must be positive");
+        }
+        if self.context_size == 0 {
+            anyhow::bail!("Context size must be positive");
+        }
+        if self.n_batch == 0 {
+            anyhow::bail!("Batch size must be positive");
+        }
+        if self.max_input_chars == 0 {
+            anyhow::bail!("Input length limit must be positive");
+        }
+        validate_download_timeouts(self.connect_timeout_secs, self.download_timeout_secs)?;
+        if self.max_concurrent_translations == 0 {
+            anyhow::bail!("Concurrent translation limit must be positive");
+        }
+        for prompt in self.system_prompts.values() {
+            prompts::validate(prompt)?;
+        }
+        if self.model_shards.as_ref().is_some_and(Vec::is_empty) {
+            anyhow::bail!("Model shard list is empty");
+        }
+        Ok(())
+    }
+
+    /// Create the service, initializing the llama backend
+    pub fn build(self) -> Result<TranslationService> {
+        self.validate()?;
+
+        let shard_files = match self.model_shards {
+            Some(shards) => shards,
+            None => shard_files(&self.model_file),
+        };
+
+        let cache_dir = TranslationService::get_cache_dir(self.cache_dir)?;
+        let files = ModelFiles::new(&cache_dir, self.model_file, shard_files);
+
+        // Initialize the LlamaBackend
+        let backend = LlamaBackend::init()
+            .context("Failed to initialize LlamaBackend")?;
+
+        let model_state = ModelState {
+            backend,
+            model: None,
+            is_loaded: false,
+            cpu_fallback: false,
+            lora: None,
+            draft: None,
+            prompts: HashMap::new(),
+        };
+
+        Ok(TranslationService {
+            model_state: Arc::new(Mutex::new(model_state)),
+            model_repo: self.model_repo,
+            cache_dir,
+            files: std::sync::RwLock::new(files),
+            num_threads: self.num_threads,
+            gpu_layers: self.gpu_layers,
+            force_cpu: self.force_cpu,
+            cache: std::sync::Mutex::new(NonZeroUsize::new(DEFAULT_CACHE_SIZE).map(LruCache::new)),
+            downloading: Arc::new(AtomicUsize::new(0)),
+            downloaded_bytes: AtomicU64::new(0),
+            total_bytes: AtomicU64::new(0),
+            download_cancel: Notify::new(),
+            download_lock: Mutex::new(()),
+            config: std::sync::RwLock::new(ServiceConfig {
+                context_size: self.context_size,
+                n_batch: self.n_batch,
+                flash_attention: self.flash_attention,
+                max_input_chars: self.max_input_chars,
+                connect_timeout_secs: self.connect_timeout_secs,
+                download_timeout_secs: self.download_timeout_secs,
+                offline: self.offline,
+                system_prompts: self.system_prompts,
+                ..ServiceConfig::default()
+            }),
+            last_used: std::sync::Mutex::new(Instant::now()),
+            bundled_model: std::sync::RwLock::new(None),
+            in_flight: Semaphore::new(self.max_concurrent_translations),
+            max_in_flight: self.max_concurrent_translations,
+            in_progress: std::sync::Mutex::new(HashMap::new()),
+            phase: watch::channel(ModelPhase::Idle).0,
+            sessions: std::sync::Mutex::new(HashMap::new()),
+            next_session_id: AtomicU64::new(1),
+            settings: None,
+            metadata: std::sync::RwLock::new(None),
+        })
+    }
+}
+
+impl TranslationService {
+    /// Create a new TranslationService instance with the saved settings
+    ///
+    /// Runtime setting changes are saved back, so they apply again on the
+    /// next start. Saved settings the service can't be built with are
+    /// ignored in favor of the defaults, but left in the file to be fixed.
+    pub fn new() -> Result<Self> {
+        let store = SettingsStore::load()?;
+
+        // Overrides saved before they became part of the settings
+        if store.get().service.system_prompts.is_empty() {
+            if let Some(legacy) = prompts::legacy_overrides() {
+                store.update(|saved| saved.service.system_prompts = legacy)?;
+                if let Err(e) = prompts::remove_legacy_overrides() {
+                    tracing::warn!(error = %e, "Failed to remove old system prompt file");
+                }
+            }
+        }
+
+        Self::with_settings(Self::builder(), store)
+    }
+
+    /// Build from `base` with the settings in `store`, which runtime
+    /// changes are then saved to
+    fn with_settings(base: TranslationServiceBuilder, store: SettingsStore) -> Result<Self> {
+        let mut settings = store.get();
+        let mut builder = Self::builder_from(base.clone(), &settings);
+        if let Err(e) = builder.validate() {
+            tracing::warn!(error = %e, "Ignoring invalid settings, using the defaults");
+            settings = Settings::default();
+            builder = Self::builder_from(base, &settings);
+        }
+
+        let mut service = builder.build()?;
+        *service.config.write().unwrap() = settings.service;
+        service.resize_cache(settings.cache_size);
+        service.settings = Some(store);
+        Ok(service)
+    }
+
+    fn builder_from(
+        base: TranslationServiceBuilder,
+        settings: &Settings,
+    ) -> TranslationServiceBuilder {
+        let mut builder = base
+            .context_size(settings.service.context_size)
+            .n_batch(settings.service.n_batch)
+            .flash_attention(settings.service.flash_attention)
+            .max_input_chars(settings.service.max_input_chars)
+            .download_timeouts(
+                settings.service.connect_timeout_secs,
+                settings.service.download_timeout_secs,
+            )
+            .offline(settings.service.offline)
+            .force_cpu(settings.force_cpu);
+        if let Some(num_threads) = settings.num_threads {
+            builder = builder.num_threads(num_threads);
+        }
+        if let Some(gpu_layers) = settings.gpu_layers {
+            builder = builder.gpu_layers(gpu_layers);
+        }
+        if let Some(model_repo) = &settings.model_repo {
+            builder = builder.model_repo(model_repo);
+        }
+        if let Some(model_file) = &settings.model_file {
+            builder = builder.model_file(model_file);
+        }
+        for (direction, prompt) in &settings.service.system_prompts {
+            builder = builder.system_prompt(direction.clone(), prompt);
+        }
+        builder
+    }
+
+    /// Start configuring a customized service
+    pub fn builder() -> TranslationServiceBuilder {
+        TranslationServiceBuilder::default()
+    }
+    
+    /// Get the cache directory for storing models
+    ///
+    /// An explicit directory wins, then `KONNYAKU_MODEL_DIR`, then the
+    /// platform cache dir. The directory is created if needed and must be
+    /// writable, since the model is downloaded into it. An unwritable
+    /// platform cache dir, as on a locked-down home directory, is replaced
+    /// with one under the temp dir; a directory the user chose isn't.
+    fn get_cache_dir(explicit: Option<PathBuf>) -> Result<PathBuf> {
+        if let Some(dir) = explicit.or_else(|| std::env::var_os(MODEL_DIR_ENV).map(PathBuf::from)) {
+            return Self::writable_cache_dir(dir, None);
+        }
+
+        let proj_dirs = ProjectDirs::from("com", "konnyaku", "konnyaku")
+            .context("Failed to determine project directories")?;
+        let fallback = std::env::temp_dir().join("konnyaku").join("models");
+        Self::writable_cache_dir(proj_dirs.cache_dir().join("models"), Some(fallback))
+    }
+
+    /// `dir`, or `fallback` when `dir` can't be created or written to
+    ///
+    /// Fails with `TranslationError::CacheDirUnwritable` for `dir` when
+    /// neither is usable.
+    fn writable_cache_dir(dir: PathBuf, fallback: Option<PathBuf>) -> Result<PathBuf> {
+        let error = match prepare_cache_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) => e,
+        };
+        if let Some(fallback) = fallback {
+            match prepare_cache_dir(&fallback) {
+                Ok(()) => {
+                    tracing::warn!(
+                        cache_dir = %dir.display(),
+                        fallback = %fallback.display(),
+                        error = %error,
+                        "Cache directory is not writable, keeping models in the fallback"
+                    );
+                    return Ok(fallback);
+                }
+                Err(e) => tracing::warn!(
+                    fallback = %fallback.display(),
+                    error = %e,
+                    "Fallback cache directory is not writable either"
+                ),
+            }
+        }
+        Err(TranslationError::CacheDirUnwritable {
+            path: dir.display().to_string(),
+            reason: error.to_string(),
+        }
+        .into())
+    }
+    
+    /// Download the model from HuggingFace if not cached
+    #[tracing::instrument(skip(self), fields(model_path = ?self.files().path))]
+    pub async fn ensure_model_downloaded(&self) -> Result<()> {
+        if self.is_model_downloaded() {
+            tracing::debug!("Model already cached");
+            return Ok(());
+        }
+
+        let _download = self.download_lock.lock().await;
+        // Another caller may have fetched it while we waited
+        if self.is_model_downloaded() {
+            return Ok(());
+        }
+
+        self.set_phase(ModelPhase::Downloading);
+        // Dropping the download future stops it, so remove what it wrote
+        let result = tokio::select! {
+            result = self.download_model() => Some(result),
+            _ = self.download_cancel.notified() => None,
+        };
+        let Some(result) = result else {
+            for (_, path) in &self.files().shards {
+                let _ = tokio::fs::remove_file(part_path(path)).await;
+            }
+            self.set_phase(ModelPhase::Idle);
+            tracing::info!("Model download cancelled");
+            anyhow::bail!("Model download cancelled");
+        };
+
+        self.set_phase(match &result {
+            Ok(()) => ModelPhase::Idle,
+            Err(e) => ModelPhase::Failed { error: e.to_string() },
+        });
+        result
+    }
+
+    /// Whether the model, every shard of it, is on disk ready to be loaded
+    pub fn is_model_downloaded(&self) -> bool {
+        self.files().shards.iter().all(|(_, path)| path.exists())
+    }
+
+    /// How far the running (or last) download has got
+    pub fn download_progress(&self) -> DownloadProgress {
+        let downloaded_bytes = self.downloaded_bytes.load(Ordering::SeqCst);
+        let total_bytes = self.total_bytes.load(Ordering::SeqCst);
+        DownloadProgress {
+            in_progress: self.downloading.load(Ordering::SeqCst) > 0,
+            downloaded_bytes,
+            total_bytes: (total_bytes > 0).then_some(total_bytes),
+            percent: (total_bytes > 0)
+                .then(|| (downloaded_bytes as f64 / total_bytes as f64 * 100.0).min(100.0)),
+            phase: self.model_phase(),
+        }
+    }
+
+    /// Stop the running download, returning whether there was one
+    ///
+    /// The `ensure_model_downloaded` call driving it fails with a
+    /// cancellation error and the partial file is removed.
+    pub fn cancel_download(&self) -> bool {
+        if self.downloading.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+        self.download_cancel.notify_waiters();
+        true
+    }
+
+    /// Count a download as in progress before it is started, None while one
+    /// already is
+    ///
+    /// For downloads run in the background, so a second request made before
+    /// the first one gets going doesn't start another.
+    pub(crate) fn claim_download(&self) -> Option<DownloadGuard> {
+        self.downloading
+            .compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()?;
+        Some(DownloadGuard(Arc::clone(&self.downloading)))
+    }
+
+    /// Install the bundled model or download it, whichever is available
+    async fn download_model(&self) -> Result<()> {
+        let files = self.files();
+        let _downloading = DownloadGuard::new(&self.downloading);
+        self.downloaded_bytes.store(0, Ordering::SeqCst);
+        self.total_bytes.store(0, Ordering::SeqCst);
+
+        // A bundled model is a single file
+        let bundled_model = self.bundled_model.read().unwrap().clone();
+        let bundled_model = bundled_model.filter(|path| files.shards.len() == 1 && path.is_file());
+        if let Some(bundled_model) = bundled_model {
+            tracing::info!(from = ?bundled_model, "Installing bundled model");
+            tokio::fs::create_dir_all(&self.cache_dir)
+                .await
+                .context("Failed to create model directory")?;
+            return write_atomically(&files.path, |part_path| async move {
+                tokio::fs::copy(&bundled_model, &part_path)
+                    .await
+                    .context("Failed to copy bundled model to cache")?;
+                Ok(())
+            })
+            .await;
+        }
+
+        if self.config().offline {
+            return Err(TranslationError::ModelNotDownloaded {
+                path: files.path.display().to_string(),
+            }
+            .into());
+        }
+
+        // Ensure the parent directory exists
+        tokio::fs::create_dir_all(&self.cache_dir)
+            .await
+            .context("Failed to create model directory")?;
+        
+        for (file, path) in &files.shards {
+            if !path.exists() {
+                self.download_shard(file, path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Download one file of the model to `path`, directly or through the
+    /// HuggingFace API
+    async fn download_shard(&self, file: &str, path: &Path) -> Result<()> {
+        let started = Instant::now();
+
+        tracing::info!(repo = %self.model_repo, %file, "Downloading model from HuggingFace");
+        
+        // Where to point users when every automatic download fails
+        let direct_url = mirror_url(HF_BASE_URL, &self.model_repo, file);
+
+        // Try direct downloads from each mirror first as they're often faster
+        let mut auth_error = None;
+        for mirror in self.config().download_mirrors {
+            let url = mirror_url(&mirror, &self.model_repo, file);
+            tracing::info!(%url, "Attempting direct download");
+
+            let downloaded = self.downloaded_bytes.load(Ordering::SeqCst);
+            let total = self.total_bytes.load(Ordering::SeqCst);
+            match self.download_file_direct(&url, path).await {
+                Ok(()) => {
+                    tracing::info!(
+                        %mirror,
+                        elapsed_ms = started.elapsed().as_millis() as u64,
+                        "Model downloaded successfully via direct download"
+                    );
+                    return Ok(());
+                }
+                // Other mirrors and the API would fail the same way (and the
+                // API need twice the space, with its own cache)
+                Err(e) if matches!(
+                    e.downcast_ref::<TranslationError>(),
+                    Some(TranslationError::InsufficientDiskSpace { .. })
+                ) => return Err(e),
+                Err(e) => {
+                    tracing::warn!(%mirror, error = %e, "Direct download failed");
+                    // Progress starts over with the next attempt
+                    self.downloaded_bytes.store(downloaded, Ordering::SeqCst);
+                    self.total_bytes.store(total, Ordering::SeqCst);
+                    if matches!(
+                        e.downcast_ref::<TranslationError>(),
+                        Some(TranslationError::AuthenticationRequired { .. })
+                    ) {
+                        auth_error = Some(e);
+                    }
+                }
+            }
+        }
+
+        // The API uses the same token, it would be refused too
+        if let Some(e) = auth_error {
+            return Err(e);
+        }
+        tracing::info!("Trying HuggingFace API");
+        
+        // Fallback to HuggingFace API, with a fresh time budget. Its client
+        // has no connect timeout of its own.
+        let timeout_secs = self.config().download_timeout_secs;
+        let download_timeout = Duration::from_secs(timeout_secs);
+        
+        let api = hf_api()?;
+        let repo = api.model(self.model_repo.clone());
+        
+        let download_future = async {
+            tracing::info!("Starting HuggingFace API download");
+            let model_file = repo.get(file).await
+                .context("Failed to download model from HuggingFace")?;
+            
+            tracing::debug!(from = ?model_file, "Download complete, copying to cache");
+
+            let size = tokio::fs::metadata(&model_file).await.map(|m| m.len()).unwrap_or(0);
+            self.check_disk_space(size)?;
+            
+            // Copy to cache location
+            write_atomically(path, |part_path| async move {
+                tokio::fs::copy(&model_file, &part_path)
+                    .await
+                    .context("Failed to copy model to cache")?;
+                Ok(())
+            })
+            .await?;
+            
+            Ok::<(), anyhow::Error>(())
+        };
+        
+        match tokio::time::timeout(download_timeout, download_future).await {
+            Ok(Ok(())) => {
+                tracing::info!(
+                    elapsed_ms = started.elapsed().as_millis() as u64,
+                    "Model downloaded successfully via HuggingFace API"
+                );
+                Ok(())
+            }
+            Ok(Err(e)) => {
+                tracing::error!(
+                    error = %e,
+                    manual_url = %direct_url,
+                    "HuggingFace API download failed, try downloading the model manually to model_path"
+                );
+                Err(e)
+            }
+            Err(_) => {
+                let err =
+                    anyhow::anyhow!("Model download timed out after {} seconds", timeout_secs);
+                tracing::error!(
+                    manual_url = %direct_url,
+                    "{}, try downloading the model manually to model_path",
+                    err
+                );
+                Err(err)
+            }
+        }
+    }
+    
+    /// Fail early if the cache directory can't fit `needed` more bytes
+    ///
+    /// If free space can't be determined the download goes ahead anyway.
+    fn check_disk_space(&self, needed: u64) -> Result<()> {
+        match fs4::available_space(&self.cache_dir) {
+            Ok(available) if available < needed + DISK_SPACE_MARGIN => {
+                Err(TranslationError::InsufficientDiskSpace {
+                    needed: needed + DISK_SPACE_MARGIN,
+                    available,
+                }
+                .into())
+            }
+            Ok(_) => Ok(()),
+            Err(e) => {
+                tracing::warn!(error = %e, "Could not determine free disk space");
+                Ok(())
+            }
+        }
+    }
+
+    /// Direct download using reqwest (simpler than HuggingFace API)
+    async fn download_file_direct(&self, url: &str, dest: &Path) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+        
+        let config = self.config();
+        let client = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+            .timeout(Duration::from_secs(config.download_timeout_secs))
+            .build()?;
+        
+        let mut request = client.get(url);
+        if let Some(token) = hf_token() {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request
+            .send()
+            .await
+            .context("Failed to start download")?;
+
+        let status = response.status();
+        if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+            return Err(TranslationError::AuthenticationRequired {
+                status: status.as_u16(),
+            }
+            .into());
+        }
+        
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("HTTP error: {}", response.status()));
+        }
+        
+        let total_size = response
+            .content_length()
+            .unwrap_or(0);
+        
+        tracing::info!(total_bytes = total_size, "Download started");
+
+        if total_size > 0 {
+            self.check_disk_space(total_size)?;
+        }
+        // Shards add up, so the totals cover the whole model
+        self.total_bytes.fetch_add(total_size, Ordering::SeqCst);
+        
+        write_atomically(dest, |part_path| async move {
+            let mut file = tokio::fs::File::create(&part_path)
+                .await
+                .context("Failed to create file")?;
+
+            let mut downloaded = 0u64;
+            let mut next_report = PROGRESS_REPORT_INTERVAL;
+            let mut stream = response.bytes_stream();
+
+            use futures_util::StreamExt;
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk.context("Error while downloading chunk")?;
+                file.write_all(&chunk)
+                    .await
+                    .context("Failed to write to file")?;
+
+                downloaded += chunk.len() as u64;
+                self.downloaded_bytes.fetch_add(chunk.len() as u64, Ordering::SeqCst);
+
+                // Log progress every 10MB
+                if downloaded >= next_report || downloaded == total_size {
+                    next_report = downloaded + PROGRESS_REPORT_INTERVAL;
+                    let progress = if total_size > 0 {
+                        (downloaded as f64 / total_size as f64 * 100.0) as u32
+                    } else {
+                        0
+                    };
+                    tracing::debug!(
+                        downloaded_bytes = downloaded,
+                        total_bytes = total_size,
+                        progress_percent = progress,
+                        "Download progress"
+                    );
+                }
+            }
+
+            if total_size > 0 && downloaded != total_size {
+                anyhow::bail!(
+                    "Download ended early: got {} of {} bytes",
+                    downloaded,
+                    total_size
+                );
+            }
+
+            file.flush().await?;
+            tracing::debug!(downloaded_bytes = downloaded, "Download complete");
+
+            Ok(())
+        })
+        .await
+    }
+    
+    /// Initialize the model if not already loaded
+    ///
+    /// A cached model llama.cpp can't load is checked with `repair_model`,
+    /// and loaded again if that found and replaced a damaged file.
+    #[tracing::instrument(skip(self), fields(model_path = ?self.files().path))]
+    pub async fn ensure_model_loaded(&self) -> Result<()> {
+        // A damaged file fails the same way every time, fetching it again is
+        // the only way out short of deleting it by hand
+        match self.load_if_needed().await {
+            Err(e) if e.downcast_ref::<LlamaModelLoadError>().is_some() && self.can_repair() => {
+                tracing::warn!(error = %e, "Model failed to load, checking it for damage");
+                match self.repair_model().await {
+                    Ok(repair) if !repair.repaired.is_empty() => self.load_if_needed().await,
+                    Ok(_) => Err(e),
+                    Err(repair_error) => {
+                        tracing::warn!(error = %repair_error, "Could not repair the model");
+                        Err(e)
+                    }
+                }
+            }
+            result => result,
+        }
+    }
+
+    async fn load_if_needed(&self) -> Result<()> {
+        let mut state = self.model_state.lock().await;
+        
+        if state.check_loaded() {
+            return Ok(());
+        }
+        
+        // Ensure model is downloaded
+        drop(state); // Release lock temporarily
+        self.ensure_model_downloaded().await?;
+        state = self.model_state.lock().await; // Re-acquire lock
+
+        // Another caller may have loaded it while the lock was released
+        if state.check_loaded() {
+            return Ok(());
+        }
+
+        self.set_phase(ModelPhase::Loading);
+        // The tokio lock doesn't poison, so a panic in the bindings would
+        // otherwise leave the phase stuck at Loading for every later caller
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| self.load_model(&mut state)))
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Loading the model panicked")));
+        if result.is_err() {
+            state.unload();
+        }
+        self.set_phase(match &result {
+            Ok(()) => ModelPhase::Ready,
+            Err(e) => ModelPhase::Failed { error: e.to_string() },
+        });
+        result
+    }
+
+    /// Load the downloaded model into `state`
+    fn load_model(&self, state: &mut ModelState) -> Result<()> {
+        let files = self.files();
+
+        // llama.cpp finds the other shards from the first one's name, and
+        // fails obscurely part way through when one is missing
+        let missing: Vec<&str> = files
+            .shards
+            .iter()
+            .filter(|(_, path)| !path.exists())
+            .map(|(file, _)| file.as_str())
+            .collect();
+        if !missing.is_empty() {
+            anyhow::bail!("Model files missing: {}", missing.join(", "));
+        }
+
+        tracing::info!(shards = files.shards.len(), "Loading model");
+        let started = Instant::now();
+        
+        let gpu_layers = if self.force_cpu { 0 } else { self.gpu_layers };
+        let model_params = LlamaModelParams::default()
+            .with_n_gpu_layers(gpu_layers);
+            
+        
+        // Load the model. llama.cpp only reports a bare null on failure, so
+        // with GPU layers requested that is retried on the CPU; errors from
+        // the path itself would fail the same way and aren't retried.
+        let loaded = LlamaModel::load_from_file(&state.backend, &files.path, &model_params);
+        let (model, cpu_fallback) = match loaded {
+            Err(LlamaModelLoadError::NullResult) if gpu_layers > 0 => {
+                tracing::warn!(
+                    gpu_layers,
+                    "Loading the model on the GPU failed, falling back to CPU"
+                );
+                let cpu_params = LlamaModelParams::default().with_n_gpu_layers(0);
+                let model =
+                    LlamaModel::load_from_file(&state.backend, &files.path, &cpu_params)
+                        .context("Failed to load model")?;
+                (model, true)
+            }
+            loaded => (loaded.context("Failed to load model")?, false),
+        };
+        
+        *self.metadata.write().unwrap() = Some(ModelMetadata::read(&model));
+        state.model = Some(model);
+        state.is_loaded = true;
+        state.cpu_fallback = cpu_fallback;
+        
+        tracing::info!(
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "Model loaded successfully"
+        );
+
+        // Translations work without the draft model, just more slowly
+        if let Some(path) = self.config().draft_model {
+            match self.load_draft(state, &path) {
+                Ok(draft) => state.draft = Some(draft),
+                Err(e) => tracing::warn!(
+                    error = %e,
+                    "Draft model unusable, decoding without speculation"
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the draft model at `path` to go with the loaded model
+    fn load_draft(&self, state: &ModelState, path: &Path) -> Result<LlamaModel> {
+        let model = state.model.as_ref().context("Model not loaded")?;
+        let gpu_layers = if self.force_cpu || state.cpu_fallback { 0 } else { self.gpu_layers };
+        let params = LlamaModelParams::default().with_n_gpu_layers(gpu_layers);
+        let draft = LlamaModel::load_from_file(&state.backend, path, &params)
+            .with_context(|| format!("Failed to load draft model from {}", path.display()))?;
+        speculative::check_compatible(model, &draft)?;
+        for (name, model) in [("model", model), ("draft model", &draft)] {
+            if !speculative::can_rewind(model, &state.backend)? {
+                anyhow::bail!(
+                    "The {} keeps a recurrent state, which speculative decoding can't rewind",
+                    name
+                );
+            }
+        }
+        tracing::info!(draft = %path.display(), "Loaded draft model");
+        Ok(draft)
+    }
+    
+    /// Translate text based on the specified direction
+    pub async fn translate(
+        &self,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<Translation> {
+        // Nothing to translate, don't load the model for it
+        if !self.check_input(text)? {
+            return Ok(Translation::empty());
+        }
+
+        self.touch();
+
+        let options = &self.resolve_sampling(&direction, options)?;
+        options.sampling.validate()?;
+        options.validate_beam_search()?;
+
+        // Fail on a malformed grammar before waiting for the model
+        if let Some(grammar) = &options.grammar {
+            sampling::validate_grammar(grammar)?;
+        }
+
+        // Random sampling without a fixed seed is meant to vary, don't cache it
+        let cache_key = if options.is_deterministic() && !options.collect_timings {
+            Some((
+                text.to_string(),
+                direction.clone(),
+                serde_json::to_string(options).context("Failed to serialize options")?,
+            ))
+        } else {
+            None
+        };
+
+        let Some(key) = cache_key else {
+            return self.translate_fresh(text, direction, options).await;
+        };
+
+        if let Some(cached) = self.cached(&key) {
+            return Ok(cached);
+        }
+
+        // Identical requests arriving while this one runs wait on the same
+        // cell instead of running inference again. A failed attempt leaves
+        // the cell empty, so a waiting duplicate retries rather than sharing
+        // the error.
+        let cell = Arc::clone(self.in_progress.lock().unwrap().entry(key.clone()).or_default());
+        let result = cell
+            .get_or_try_init(|| self.translate_fresh(text, direction, options))
+            .await
+            .cloned();
+
+        {
+            let mut in_progress = self.in_progress.lock().unwrap();
+            if in_progress.get(&key).is_some_and(|current| Arc::ptr_eq(current, &cell)) {
+                in_progress.remove(&key);
+            }
+        }
+
+        let translation = result?;
+        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+            cache.put(key, translation.clone());
+        }
+
+        Ok(translation)
+    }
+
+    /// Run inference for a request the cache couldn't answer
+    async fn translate_fresh(
+        &self,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<Translation> {
+        let _permit = self.in_flight.try_acquire().map_err(|_| TranslationError::Busy {
+            limit: self.max_in_flight,
+        })?;
+
+        let mut translation = self.translate_uncached(text, direction.clone(), options).await?;
+        if options.n_alternatives > 1 && !options.beam_search() {
+            translation.alternatives = self
+                .alternatives(text, direction.clone(), options, &translation.text)
+                .await;
+        }
+        let wants_readings = options.with_romaji || options.with_furigana;
+        if wants_readings && direction.target == Language::Japanese {
+            // Both come from the same readings, generated once
+            let lines = self.readings(&translation.text).await;
+            if options.with_romaji {
+                translation.romaji = Some(romaji_of(&lines));
+            }
+            if options.with_furigana {
+                translation.furigana = Some(furigana_of(&lines));
+            }
+        }
+
+        Ok(translation)
+    }
+
+    /// The lines of `text`, each with the model's kana reading of it where
+    /// it has kanji and the reading fits
+    async fn readings<'a>(&self, text: &'a str) -> Vec<(&'a str, Option<String>)> {
+        // Unnormalized, so the reading follows the text character for character
+        let options = TranslateOptions {
+            system_prompt: Some(FURIGANA_PROMPT.to_string()),
+            normalize_input: false,
+            ..TranslateOptions::default()
+        };
+
+        let mut lines = Vec::new();
+        for line in text.split_inclusive('\n') {
+            let content = line.trim_end_matches(['\r', '\n']);
+            let reading = if furigana::has_kanji(content) {
+                self.line_reading(content, &options).await
+            } else {
+                None
+            };
+            lines.push((line, reading));
+        }
+        lines
+    }
+
+    async fn line_reading(&self, line: &str, options: &TranslateOptions) -> Option<String> {
+        let direction = LanguagePair::JA_EN;
+        let reading = match self.generate(line, direction, options).await {
+            Ok(reading) => reading.text,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to generate a reading");
+                return None;
+            }
+        };
+        if furigana::annotate(line, &reading).is_none() {
+            tracing::debug!(%reading, "Reading doesn't fit the text, line left unread");
+            return None;
+        }
+        Some(reading)
+    }
+
+    /// Sample extra candidates, returning them deduplicated after `primary`
+    ///
+    /// Runs at most `MAX_ALTERNATIVES - 1` more generations. A failed attempt
+    /// ends the search and keeps what was found so far, since the primary
+    /// translation already succeeded.
+    async fn alternatives(
+        &self,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+        primary: &str,
+    ) -> Vec<String> {
+        let wanted = options.n_alternatives.min(MAX_ALTERNATIVES);
+        let mut candidates = vec![primary.to_string()];
+
+        for index in 1..wanted {
+            let alternative_options = TranslateOptions {
+                sampling: options.sampling.alternative(index as u32),
+                n_alternatives: 0,
+                ..options.clone()
+            };
+            match self.translate_uncached(text, direction.clone(), &alternative_options).await {
+                Ok(alternative) => {
+                    if !candidates.contains(&alternative.text) {
+                        candidates.push(alternative.text);
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, index, "Failed to generate alternative translation");
+                    break;
+                }
+            }
+        }
+
+        candidates
+    }
+
+    fn cached(&self, key: &CacheKey) -> Option<Translation> {
+        self.cache.lock().unwrap().as_mut()?.get(key).cloned()
+    }
+
+    /// Drop all cached translations
+    pub fn clear_cache(&self) {
+        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+            cache.clear();
+        }
+    }
+
+    /// Change how many translations are cached, 0 disables the cache
+    pub fn set_cache_size(&self, size: usize) {
+        self.resize_cache(size);
+        self.save_settings();
+    }
+
+    fn resize_cache(&self, size: usize) {
+        let mut cache = self.cache.lock().unwrap();
+        match (NonZeroUsize::new(size), cache.as_mut()) {
+            (Some(size), Some(existing)) => existing.resize(size),
+            (size, _) => *cache = size.map(LruCache::new),
+        }
+    }
+
+    fn cache_size(&self) -> usize {
+        self.cache.lock().unwrap().as_ref().map_or(0, |cache| cache.cap().get())
+    }
+
+    /// The current settings, including the startup-only ones
+    pub fn settings(&self) -> Settings {
+        let mut settings = self.settings.as_ref().map(SettingsStore::get).unwrap_or_default();
+        settings.service = self.config();
+        settings.cache_size = self.cache_size();
+        settings
+    }
+
+    /// Go back to the default settings, saving them for the next start
+    ///
+    /// Runtime settings apply straight away, the others on the next start.
+    pub fn reset_settings(&self) -> Result<Settings> {
+        *self.config.write().unwrap() = ServiceConfig::default();
+        self.resize_cache(DEFAULT_CACHE_SIZE);
+        if let Some(store) = &self.settings {
+            store.reset()?;
+        }
+        Ok(self.settings())
+    }
+
+    // Save the runtime settings after one of them changed
+    fn save_settings(&self) {
+        let Some(store) = &self.settings else {
+            return;
+        };
+        let config = self.config();
+        let cache_size = self.cache_size();
+        let saved = store.update(|settings| {
+            settings.service = config;
+            settings.cache_size = cache_size;
+        });
+        if let Err(e) = saved {
+            tracing::warn!(error = %e, "Failed to save settings");
+        }
+    }
+
+    async fn translate_uncached(
+        &self,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<Translation> {
+        if options.preserve_html {
+            return self.translate_html(text, direction, options).await;
+        }
+        if !options.preserve_markdown {
+            return self.generate_paragraphs(text, direction, options).await;
+        }
+
+        let protected = markdown::protect(text);
+        if protected.is_only_placeholders() {
+            // Nothing but code and links, there is no prose to translate
+            return Ok(Translation {
+                text: text.to_string(),
+                truncated: false,
+                alternatives: Vec::new(),
+                confidence: None,
+                romaji: None,
+                furigana: None,
+                timings: None,
+            });
+        }
+
+        // Placeholders never span a blank line, so code blocks survive the
+        // split into paragraphs
+        let translated = self.generate_paragraphs(&protected.text, direction, options).await?;
+        let restore = |output: &String| markdown::restore(output, &protected.fragments);
+        Ok(Translation {
+            text: restore(&translated.text),
+            truncated: translated.truncated,
+            // Only beam search gets here with alternatives
+            alternatives: translated.alternatives.iter().map(restore).collect(),
+            confidence: translated.confidence,
+            romaji: None,
+            furigana: None,
+            timings: translated.timings,
+        })
+    }
+
+    /// `generate`, a paragraph at a time when `preserve_paragraphs` is set
+    async fn generate_paragraphs(
+        &self,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<Translation> {
+        let paragraphs = chunking::paragraphs(text);
+        if !options.preserve_paragraphs || paragraphs.len() <= 1 {
+            return self.generate(text, direction, options).await;
+        }
+
+        let mut output = String::with_capacity(text.len());
+        let mut truncated = false;
+        let mut timings = None;
+        for paragraph in paragraphs {
+            let core = paragraph.trim();
+            if !markdown::has_prose(core) {
+                output.push_str(paragraph);
+                continue;
+            }
+
+            let translated = self.generate(core, direction.clone(), options).await?;
+            truncated |= translated.truncated;
+            add_timings(&mut timings, translated.timings);
+
+            let leading = paragraph.len() - paragraph.trim_start().len();
+            output.push_str(&paragraph[..leading]);
+            output.push_str(&translated.text);
+            output.push_str(&paragraph[leading + core.len()..]);
+        }
+
+        Ok(Translation {
+            text: output,
+            truncated,
+            alternatives: Vec::new(),
+            confidence: None,
+            romaji: None,
+            furigana: None,
+            timings,
+        })
+    }
+
+    /// Translate each text node of an HTML snippet on its own, so the tags
+    /// around them can't be dropped or reordered
+    async fn translate_html(
+        &self,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<Translation> {
+        let mut output = String::with_capacity(text.len());
+        let mut truncated = false;
+        let mut timings = None;
+
+        for node in html::tokenize(text) {
+            let text_node = match node {
+                html::Node::Markup(markup) => {
+                    output.push_str(markup);
+                    continue;
+                }
+                html::Node::Text(text_node) => text_node,
+            };
+
+            // Whitespace and punctuation between tags pass through as-is
+            let decoded = html::decode_entities(text_node);
+            let core = decoded.trim();
+            if !core.chars().any(char::is_alphanumeric) {
+                output.push_str(text_node);
+                continue;
+            }
+
+            let translated = self.generate(core, direction.clone(), options).await?;
+            truncated |= translated.truncated;
+            add_timings(&mut timings, translated.timings);
+
+            let leading = decoded.len() - decoded.trim_start().len();
+            output.push_str(&decoded[..leading]);
+            output.push_str(&html::escape_text(&translated.text));
+            output.push_str(&decoded[leading + core.len()..]);
+        }
+
+        Ok(Translation {
+            text: output,
+            truncated,
+            alternatives: Vec::new(),
+            confidence: None,
+            romaji: None,
+            furigana: None,
+            timings,
+        })
+    }
+
+    /// Count the prompt tokens a translation of `text` would consume
+    ///
+    /// Builds the exact prompt `translate` would use and tokenizes it, without
+    /// creating a context or running inference.
+    pub async fn count_tokens(&self, text: &str, direction: LanguagePair) -> Result<usize> {
+        self.ensure_model_loaded().await?;
+
+        let state = self.model_state.lock().await;
+        let model = state.model.as_ref().context("Model not loaded")?;
+
+        let options = TranslateOptions::default();
+        let system_prompt = self.system_prompt(&direction, &options)?;
+        let full_prompt = Self::build_prompt(
+            model,
+            &options.prepare_input(text),
+            &system_prompt,
+            &options.context,
+        )?;
+        let tokens = Self::tokenize_prompt(model, &full_prompt)?;
+        Ok(tokens.len())
+    }
+
+    /// Work out the prompt, token count, chunking and sampling translating
+    /// `text` would use, without running inference
+    ///
+    /// The model is loaded if it isn't, as the chat template and tokenizer
+    /// come from it. It describes a single generation over the whole text,
+    /// where `preserve_paragraphs` and `translate_long` run one per piece.
+    pub async fn translate_dry_run(
+        &self,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<DryRun> {
+        let options = self.resolve_sampling(&direction, options)?;
+        let system_prompt = self.system_prompt(&direction, &options)?;
+        self.ensure_model_loaded().await?;
+
+        let state = self.model_state.lock().await;
+        let model = state.model.as_ref().context("Model not loaded")?;
+        let prompt = Self::build_prompt(
+            model,
+            &options.prepare_input(text),
+            &system_prompt,
+            &options.context,
+        )?;
+        let prompt_tokens = Self::tokenize_prompt(model, &prompt)?.len();
+        let max_context_size = Self::effective_context_size(model, self.config().context_size);
+        let max_new_tokens =
+            Self::max_new_tokens(options.max_tokens, prompt_tokens, max_context_size)?;
+
+        Ok(DryRun {
+            prompt,
+            prompt_tokens,
+            prompt_format: PromptFormat::of(model),
+            chunks: chunking::split(text, MAX_CHUNK_CHARS).len(),
+            sampling: options.sampling.clone(),
+            stop_sequences: options.effective_stop_sequences(text),
+            max_new_tokens,
+            context_size: context_bucket(prompt_tokens + max_new_tokens as usize, max_context_size),
+        })
+    }
+
+    /// Format the system and user turns with the model's chat template
+    fn build_prompt(
+        model: &LlamaModel,
+        text: &str,
+        system_prompt: &str,
+        context: &[(String, String)],
+    ) -> Result<String> {
+        // The model's own template, or chatml when it has none llama.cpp can apply
+        let prompt_format = PromptFormat::of(model);
+        tracing::debug!(?prompt_format, "Building prompt");
+        let chat_template = match prompt_format {
+            PromptFormat::ModelTemplate => {
+                model.chat_template(None).context("Failed to read chat template")?
+            }
+            PromptFormat::Chatml => {
+                LlamaChatTemplate::new("chatml").expect("Failed to create chatml template")
+            }
+        };
+
+        // Create chat messages, with any context as earlier exchanges
+        let mut chat = vec![
+            LlamaChatMessage::new("system".to_string(), system_prompt.to_string())
+                .context("Failed to create system message")?,
+        ];
+        for (source, translation) in context {
+            chat.push(
+                LlamaChatMessage::new("user".to_string(), source.clone())
+                    .context("Failed to create context message")?,
+            );
+            chat.push(
+                LlamaChatMessage::new("assistant".to_string(), translation.clone())
+                    .context("Failed to create context message")?,
+            );
+        }
+        chat.push(
+            LlamaChatMessage::new("user".to_string(), text.to_string())
+                .context("Failed to create user message")?,
+        );
+
+        // Apply the chat template
+        model
+            .apply_chat_template(&chat_template, &chat, true)
+            .context("Failed to apply chat template")
+    }
+
+    /// Tokenize a full prompt the same way for counting and generation
+    fn tokenize_prompt(model: &LlamaModel, full_prompt: &str) -> Result<Vec<LlamaToken>> {
+        // AddBos depends on model's expectation
+        // Try with AddBos::Always first as many models expect it
+        model
+            .str_to_token(full_prompt, AddBos::Always)
+            .context("Failed to tokenize prompt")
+    }
+
+    /// A snapshot of the current settings
+    pub fn config(&self) -> ServiceConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Specialize the model with a LoRA adapter, replacing any applied before
+    ///
+    /// `path_or_repo` is a local GGUF file or, if no such file exists, a
+    /// HuggingFace file as `owner/repo/file.gguf`. The adapter must have
+    /// been trained for this base model's architecture, llama.cpp rejects
+    /// others. Unloading or reloading the model removes it.
+    pub async fn apply_lora(&self, path_or_repo: &str, scale: f32) -> Result<()> {
+        if !scale.is_finite() {
+            anyhow::bail!("LoRA scale must be a finite number");
+        }
+
+        let path = self.resolve_lora(path_or_repo).await?;
+        self.ensure_model_loaded().await?;
+
+        let mut state = self.model_state.lock().await;
+        let model = state.model.as_ref().context("Model not loaded")?;
+        let adapter = model
+            .lora_adapter_init(&path)
+            .with_context(|| format!("Failed to load LoRA adapter from {}", path.display()))?;
+        state.lora = Some(LoraAdapter { adapter, scale });
+        state.prompts.clear();
+        drop(state);
+
+        // Cached results came from the model without this adapter
+        self.clear_cache();
+        tracing::info!(adapter = %path.display(), scale, "Applied LoRA adapter");
+        Ok(())
+    }
+
+    /// Remove the LoRA adapter, returning whether one was applied
+    ///
+    /// Its memory is only released when the model is next unloaded. Open
+    /// sessions evaluate their whole prompt again on their next turn.
+    pub async fn clear_lora(&self) -> bool {
+        let mut state = self.model_state.lock().await;
+        let removed = state.lora.take().is_some();
+        // Sessions' saved states were computed with the adapter
+        state.prompts.clear();
+        drop(state);
+        if removed {
+            self.clear_cache();
+        }
+        removed
+    }
+
+    /// Speed up greedy translations with speculative decoding, using the
+    /// file `file` of the HuggingFace repo `repo` as the draft model
+    ///
+    /// The draft must share the model's vocabulary, e.g. a smaller model of
+    /// the same family, and is rejected otherwise. Neither model may keep a
+    /// recurrent state, which rules out hybrids like the default LFM2
+    /// model, since rejected guesses can't be removed from it. The draft
+    /// stays loaded next to the model, costing its weights in memory plus a
+    /// KV cache of its own for every translation. Sampled, grammar-
+    /// constrained and logprob requests decode without it. The choice is
+    /// saved, and a draft that can't be loaded later is skipped with a
+    /// warning. How much faster it is depends on the pair and hasn't been
+    /// measured for any, `benchmark` compares throughput with and without it.
+    pub async fn set_draft_model(&self, repo: &str, file: &str) -> Result<()> {
+        if self.config().offline {
+            anyhow::bail!("Can't download the draft model, offline mode is enabled");
+        }
+        let path = hf_api()?
+            .model(repo.to_string())
+            .get(file)
+            .await
+            .context("Failed to download draft model from HuggingFace")?;
+        self.ensure_model_loaded().await?;
+
+        let mut state = self.model_state.lock().await;
+        let draft = self.load_draft(&state, &path)?;
+        state.draft = Some(draft);
+        drop(state);
+
+        self.config.write().unwrap().draft_model = Some(path);
+        self.save_settings();
+        Ok(())
+    }
+
+    /// Stop speculative decoding and free the draft model, returning
+    /// whether one was set
+    pub async fn clear_draft_model(&self) -> bool {
+        let unloaded = self.model_state.lock().await.draft.take().is_some();
+        let cleared = self.config.write().unwrap().draft_model.take().is_some();
+        if cleared {
+            self.save_settings();
+        }
+        unloaded || cleared
+    }
+
+    /// A local adapter file, or the HuggingFace one `path_or_repo` names
+    async fn resolve_lora(&self, path_or_repo: &str) -> Result<PathBuf> {
+        let path = PathBuf::from(path_or_repo);
+        if path.is_file() {
+            return Ok(path);
+        }
+
+        let parts: Vec<&str> = path_or_repo.splitn(3, '/').collect();
+        let [owner, name, file] = parts[..] else {
+            anyhow::bail!(
+                "No LoRA adapter at {}, expected a file or owner/repo/file.gguf",
+                path_or_repo
+            );
+        };
+        if self.config().offline {
+            anyhow::bail!(
+                "LoRA adapter {} is not a local file and offline mode is enabled",
+                path_or_repo
+            );
+        }
+
+        let api = hf_api()?;
+        api.model(format!("{}/{}", owner, name))
+            .get(file)
+            .await
+            .context("Failed to download LoRA adapter from HuggingFace")
+    }
+
+    /// The context length the loaded model was trained with, the most
+    /// `context_size` can usefully be set to
+    pub async fn max_context_length(&self) -> Result<u32> {
+        match self.model_state.lock().await.model.as_ref() {
+            Some(model) => Ok(model.n_ctx_train()),
+            None => anyhow::bail!("Model is not loaded"),
+        }
+    }
+
+    /// Change the context window used for new translations
+    ///
+    /// Sizes beyond the model's trained context length are rejected once the
+    /// model has been loaded, otherwise they are clamped (with a warning) at
+    /// translation time. Bigger contexts cost more memory per translation.
+    pub async fn set_context_size(&self, context_size: u32) -> Result<()> {
+        if context_size == 0 {
+            anyhow::bail!("Context size must be positive");
+        }
+
+        // Read on every load and kept across unloads, so the limit is still
+        // known after the idle unloader ran
+        let trained = self
+            .metadata
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|metadata| metadata.trained_context_length);
+        if let Some(trained) = trained.filter(|&trained| trained > 0) {
+            if context_size > trained {
+                anyhow::bail!(
+                    "Context size {} exceeds the model's trained context length of {}",
+                    context_size,
+                    trained
+                );
+            }
+        }
+
+        self.config.write().unwrap().context_size = context_size;
+        self.save_settings();
+        Ok(())
+    }
+
+    /// Override the default system prompt for `direction`, `None` restores
+    /// the built-in one
+    pub fn set_default_system_prompt(
+        &self,
+        direction: LanguagePair,
+        prompt: Option<String>,
+    ) -> Result<()> {
+        if let Some(prompt) = &prompt {
+            prompts::validate(prompt)?;
+        }
+        {
+            let mut config = self.config.write().unwrap();
+            match prompt {
+                Some(prompt) => config.system_prompts.insert(direction, prompt),
+                None => config.system_prompts.remove(&direction),
+            };
+        }
+        self.save_settings();
+        // Cached results were produced with the old prompt
+        self.clear_cache();
+        Ok(())
+    }
+
+    /// The system prompt to use: the request's own, then the saved
+    /// override, then the built-in default
+    fn system_prompt(
+        &self,
+        direction: &LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<String> {
+        if let Some(prompt) = &options.system_prompt {
+            prompts::validate(prompt)?;
+            return Ok(prompt.clone());
+        }
+        let saved = self.config.read().unwrap().system_prompts.get(direction).cloned();
+        Ok(saved.unwrap_or_else(|| direction.default_system_prompt().to_string()))
+    }
+
+    /// Change how long the model stays loaded without use, 0 disables
+    /// unloading
+    pub fn set_idle_timeout(&self, idle_timeout_secs: u64) {
+        self.config.write().unwrap().idle_timeout_secs = idle_timeout_secs;
+        self.save_settings();
+    }
+
+    /// Change how many prompt tokens are processed per decode call, for new
+    /// translations
+    pub fn set_batch_size(&self, n_batch: u32) -> Result<()> {
+        if n_batch == 0 {
+            anyhow::bail!("Batch size must be positive");
+        }
+        self.config.write().unwrap().n_batch = n_batch;
+        self.save_settings();
+        Ok(())
+    }
+
+    /// The options with `sampling` filled in: the request's own settings,
+    /// then its preset, then the direction's default preset, then greedy
+    fn resolve_sampling(
+        &self,
+        direction: &LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<TranslateOptions> {
+        let preset = match &options.preset {
+            Some(name) => Some(name.parse::<SamplingPreset>()?),
+            // Beam search picks tokens its own way
+            None if options.beam_search() => None,
+            None => self.config().default_presets.get(direction).copied(),
+        };
+
+        let mut resolved = options.clone();
+        if let Some(preset) = preset.filter(|_| options.sampling == SamplingConfig::default()) {
+            resolved.sampling = preset.config();
+        }
+        // Resolved requests then share cache entries with explicit ones
+        resolved.preset = None;
+        Ok(resolved)
+    }
+
+    /// Pick the sampling preset for `direction`'s requests that don't ask
+    /// for one, `None` goes back to greedy decoding
+    pub fn set_default_preset(
+        &self,
+        direction: LanguagePair,
+        preset: Option<SamplingPreset>,
+    ) {
+        let mut config = self.config.write().unwrap();
+        match preset {
+            Some(preset) => config.default_presets.insert(direction, preset),
+            None => config.default_presets.remove(&direction),
+        };
+        drop(config);
+        self.save_settings();
+    }
+
+    /// Change the longest text `translate` accepts, in characters
+    pub fn set_max_input_chars(&self, max_input_chars: usize) -> Result<()> {
+        if max_input_chars == 0 {
+            anyhow::bail!("Input length limit must be positive");
+        }
+        self.config.write().unwrap().max_input_chars = max_input_chars;
+        self.save_settings();
+        Ok(())
+    }
+
+    /// Replace the mirrors models are downloaded from, tried in order
+    ///
+    /// An empty list leaves only the HuggingFace API.
+    pub fn set_download_mirrors(&self, mirrors: Vec<String>) -> Result<()> {
+        let mirrors: Vec<String> = mirrors
+            .into_iter()
+            .map(|mirror| mirror.trim().trim_end_matches('/').to_string())
+            .collect();
+        if let Some(mirror) = mirrors
+            .iter()
+            .find(|mirror| !mirror.starts_with("https://") && !mirror.starts_with("http://"))
+        {
+            anyhow::bail!("Mirror {} is not an http(s) URL", mirror);
+        }
+        self.config.write().unwrap().download_mirrors = mirrors;
+        self.save_settings();
+        Ok(())
+    }
+
+    /// Replace the labels stripped from the start of the output
+    pub fn set_output_prefixes(&self, prefixes: Vec<String>) {
+        let prefixes = prefixes
+            .into_iter()
+            .map(|prefix| prefix.trim_start().to_string())
+            .filter(|prefix| !prefix.is_empty())
+            .collect();
+        self.config.write().unwrap().output_prefixes = prefixes;
+        self.save_settings();
+    }
+
+    /// The glossary terms, in the order they were added
+    pub fn glossary(&self) -> Vec<GlossaryEntry> {
+        self.config().glossary
+    }
+
+    /// Merge the `source,target` pairs in the CSV or TSV file at `path` into
+    /// the glossary
+    ///
+    /// A `.tsv` or `.tab` file is read as tab-separated. Rows without exactly
+    /// two columns or with an empty source term are skipped and reported,
+    /// the rest are imported.
+    pub async fn import_glossary(&self, path: &Path) -> Result<GlossaryImport> {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let (entries, skipped) = glossary::parse(&contents, glossary::delimiter(path));
+        for row in &skipped {
+            tracing::warn!(row = row.row, reason = %row.reason, "Skipping glossary row");
+        }
+
+        let mut config = self.config.write().unwrap();
+        let mut import = glossary::merge(&mut config.glossary, entries);
+        drop(config);
+        self.save_settings();
+        // Cached translations were cleaned up with the old terms
+        if import.added + import.updated > 0 {
+            self.clear_cache();
+        }
+        import.skipped = skipped;
+        Ok(import)
+    }
+
+    /// Write the glossary to `path` as CSV, or TSV for a `.tsv` or `.tab`
+    /// path, returning how many terms were written
+    pub async fn export_glossary(&self, path: &Path) -> Result<usize> {
+        let entries = self.glossary();
+        let contents = glossary::write(&entries, glossary::delimiter(path))?;
+        tokio::fs::write(path, contents)
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(entries.len())
+    }
+
+    /// Change the download connect and total timeouts, in seconds
+    ///
+    /// Applies from the next download attempt.
+    pub fn set_download_timeouts(&self, connect_secs: u64, total_secs: u64) -> Result<()> {
+        validate_download_timeouts(connect_secs, total_secs)?;
+        let mut config = self.config.write().unwrap();
+        config.connect_timeout_secs = connect_secs;
+        config.download_timeout_secs = total_secs;
+        drop(config);
+        self.save_settings();
+        Ok(())
+    }
+
+    /// Turn flash attention on or off for new translations
+    pub fn set_flash_attention(&self, enabled: bool) {
+        self.config.write().unwrap().flash_attention = enabled;
+        self.save_settings();
+    }
+
+    fn touch(&self) {
+        *self.last_used.lock().unwrap() = Instant::now();
+    }
+
+    /// Unload the model whenever it sits unused for the idle timeout
+    ///
+    /// Runs forever, spawn it once on the async runtime.
+    pub async fn unload_when_idle(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+
+            let idle_timeout_secs = self.config().idle_timeout_secs;
+            if idle_timeout_secs == 0 {
+                continue;
+            }
+
+            // Holding the lock means no translation is running while we check
+            let mut state = self.model_state.lock().await;
+            let idle = self.last_used.lock().unwrap().elapsed();
+            if state.is_loaded && idle >= Duration::from_secs(idle_timeout_secs) {
+                tracing::info!(idle_secs = idle.as_secs(), "Unloading idle model");
+                state.unload();
+                self.set_phase(ModelPhase::Idle);
+            }
+        }
+    }
+
+    /// The HuggingFace repository the model comes from
+    pub fn model_repo(&self) -> &str {
+        &self.model_repo
+    }
+
+    /// The GGUF file name the service loads
+    pub fn model_file(&self) -> String {
+        self.files.read().unwrap().file.clone()
+    }
+
+    /// The quantization of the model in use, if its file name shows one
+    pub fn quantization(&self) -> Option<Quantization> {
+        Quantization::from_file_name(&self.files.read().unwrap().file)
+    }
+
+    /// Switch to another quantization of the model, then download and
+    /// load it
+    ///
+    /// The file is checked to exist in the model repository before the
+    /// current model is unloaded, so a bad choice leaves things as they
+    /// were. Cached translations and any LoRA adapter are dropped, and the
+    /// choice is saved for the next start.
+    ///
+    /// The new file has the current one's name with the other quantization,
+    /// so the current file has to be named `<stem>-<quantization>.gguf`.
+    pub async fn set_quantization(&self, quantization: Quantization) -> Result<()> {
+        let current = self.model_file();
+        let Some((stem, _)) = Quantization::split_file_name(&current) else {
+            anyhow::bail!(
+                "Can't tell the quantization of {}, expected a <name>-<quantization>.gguf file",
+                current
+            );
+        };
+        let file = quantization.file_name(stem);
+        if file != current {
+            let _download = self.lock_for_switch()?;
+            if !self.cache_dir.join(&file).exists() {
+                self.check_repo_file(&file).await?;
+            }
+            let files = ModelFiles::new(&self.cache_dir, file.clone(), shard_files(&file));
+            self.switch_files(files).await;
+
+            if let Some(store) = &self.settings {
+                if let Err(e) = store.update(|settings| settings.model_file = Some(file.clone())) {
+                    tracing::warn!(error = %e, "Failed to save settings");
+                }
+            }
+            tracing::info!(%quantization, %file, "Switched model quantization");
+        }
+
+        self.ensure_model_downloaded().await?;
+        self.ensure_model_loaded().await
+    }
+
+    /// Load the GGUF model at `path` instead of the cached one, without
+    /// touching the network
+    ///
+    /// The file stays where it is and is never deleted by
+    /// `delete_cached_model`. Split models need their other shards next to
+    /// it. The choice lasts until the app restarts.
+    pub async fn load_model_from_path(&self, path: &Path) -> Result<()> {
+        let is_gguf = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("gguf"));
+        if !is_gguf {
+            anyhow::bail!("{} is not a .gguf file", path.display());
+        }
+        let path = tokio::fs::canonicalize(path)
+            .await
+            .with_context(|| format!("Model file {} not found", path.display()))?;
+        check_gguf_header(&path).await?;
+
+        let (Some(dir), Some(file)) = (path.parent(), path.file_name()) else {
+            anyhow::bail!("Invalid model path: {}", path.display());
+        };
+        let file = file.to_string_lossy().into_owned();
+        let files = ModelFiles::new(dir, file.clone(), shard_files(&file));
+        if let Some((missing, _)) = files.shards.iter().find(|(_, shard)| !shard.is_file()) {
+            anyhow::bail!("Model shard {} is missing from {}", missing, dir.display());
+        }
+
+        {
+            // Released before loading, a repair of a file in the cache takes it
+            let _download = self.lock_for_switch()?;
+            self.switch_files(files).await;
+        }
+        tracing::info!(path = %path.display(), "Switched to a local model file");
+        self.ensure_model_loaded().await
+    }
+
+    /// Hold off downloads while the model files are replaced or removed
+    fn lock_for_switch(&self) -> Result<tokio::sync::MutexGuard<'_, ()>> {
+        self.download_lock
+            .try_lock()
+            .map_err(|_| anyhow::anyhow!("Cannot change the model while one is being downloaded"))
+    }
+
+    /// Make `files` the model, unloading the previous one once no
+    /// translation is using it
+    async fn switch_files(&self, files: ModelFiles) {
+        let mut state = self.model_state.lock().await;
+        *self.files.write().unwrap() = files;
+        state.unload();
+        *self.metadata.write().unwrap() = None;
+        self.clear_cache();
+        self.set_phase(ModelPhase::Idle);
+    }
+
+    /// Check every file of the cached model against the repository and
+    /// download again the ones that are missing, the wrong size or fail
+    /// their SHA-256 checksum
+    ///
+    /// Needs the network for the expected sizes and checksums. Files the
+    /// host publishes no checksum for are checked by size only.
+    pub async fn repair_model(&self) -> Result<ModelRepair> {
+        if self.config().offline {
+            anyhow::bail!("Repairing the model needs the network, offline mode is enabled");
+        }
+        let files = self.files();
+        if !files.path.starts_with(&self.cache_dir) {
+            anyhow::bail!(
+                "The model at {} is not in the cache and won't be repaired",
+                files.path.display()
+            );
+        }
+
+        let mut repair = ModelRepair {
+            checked: Vec::new(),
+            repaired: Vec::new(),
+        };
+        {
+            let _download = self.download_lock.lock().await;
+            let mut damaged = Vec::new();
+            for (file, path) in &files.shards {
+                repair.checked.push(file.clone());
+                if let Some(problem) = self.shard_problem(file, path).await? {
+                    tracing::warn!(%file, %problem, "Cached model file is damaged");
+                    damaged.push((file.clone(), path.clone()));
+                }
+            }
+            if damaged.is_empty() {
+                tracing::info!("Cached model verified");
+                return Ok(repair);
+            }
+
+            // Unloaded first, a mapped file can't be deleted everywhere
+            self.switch_files(files).await;
+            for (file, path) in damaged {
+                match tokio::fs::remove_file(&path).await {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e).context("Failed to delete damaged model file"),
+                }
+                repair.repaired.push(file);
+            }
+        }
+
+        self.ensure_model_downloaded().await?;
+        tracing::info!(files = ?repair.repaired, "Model repaired");
+        Ok(repair)
+    }
+
+    /// Remove cached model files that don't even start like a GGUF file, so
+    /// they are downloaded again instead of failing to load
+    ///
+    /// A cheap check for startup, `repair_model` verifies checksums.
+    pub async fn check_cached_model(&self) {
+        let files = self.files();
+        if !files.path.starts_with(&self.cache_dir) {
+            return;
+        }
+
+        for (file, path) in &files.shards {
+            let Ok(metadata) = tokio::fs::metadata(path).await else {
+                continue;
+            };
+            let valid =
+                metadata.len() >= GGUF_MIN_SIZE && check_gguf_header(path).await.is_ok();
+            if valid {
+                continue;
+            }
+            tracing::warn!(%file, size = metadata.len(), "Removing damaged cached model file");
+            if let Err(e) = tokio::fs::remove_file(path).await {
+                tracing::warn!(%file, error = %e, "Failed to remove damaged model file");
+            }
+        }
+    }
+
+    /// Whether `repair_model` could run for the current model
+    fn can_repair(&self) -> bool {
+        !self.config().offline && self.files().path.starts_with(&self.cache_dir)
+    }
+
+    /// What is wrong with the cached `file` at `path`, or None if it matches
+    /// the repository
+    async fn shard_problem(&self, file: &str, path: &Path) -> Result<Option<String>> {
+        let Ok(metadata) = tokio::fs::metadata(path).await else {
+            return Ok(Some("missing".to_string()));
+        };
+        let remote = self.remote_file(file).await?;
+
+        if let Some(size) = remote.size.filter(|&size| size != metadata.len()) {
+            return Ok(Some(format!("{} bytes, expected {}", metadata.len(), size)));
+        }
+        if let Some(expected) = remote.sha256 {
+            let actual = file_sha256(path).await?;
+            if actual != expected {
+                return Ok(Some(format!("SHA-256 {}, expected {}", actual, expected)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The published size and checksum of `file`, from the first mirror
+    /// that answers
+    async fn remote_file(&self, file: &str) -> Result<RemoteFile> {
+        let config = self.config();
+        // The redirect of an LFS file carries its details, not the file
+        let client = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+            .timeout(Duration::from_secs(config.connect_timeout_secs))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+
+        let mut mirrors = config.download_mirrors;
+        if mirrors.is_empty() {
+            mirrors.push(HF_BASE_URL.to_string());
+        }
+        let mut last_error = None;
+        for mirror in mirrors {
+            let mut request = client.head(mirror_url(&mirror, &self.model_repo, file));
+            if let Some(token) = hf_token() {
+                request = request.bearer_auth(token);
+            }
+            match request.send().await {
+                Ok(response)
+                    if response.status().is_success() || response.status().is_redirection() =>
+                {
+                    return Ok(RemoteFile::from_headers(response.headers()));
+                }
+                Ok(response) => {
+                    last_error = Some(anyhow::anyhow!("HTTP error: {}", response.status()))
+                }
+                Err(e) => last_error = Some(e.into()),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| anyhow::anyhow!("No mirror to ask"))
+            .context(format!("Failed to look up {} in {}", file, self.model_repo)))
+    }
+
+    /// Fail unless `file` is in the model repository
+    async fn check_repo_file(&self, file: &str) -> Result<()> {
+        if self.config().offline {
+            return Err(TranslationError::ModelNotDownloaded {
+                path: self.cache_dir.join(file).display().to_string(),
+            }
+            .into());
+        }
+
+        let info = hf_api()?
+            .model(self.model_repo.clone())
+            .info()
+            .await
+            .with_context(|| format!("Failed to list the files of {}", self.model_repo))?;
+        if !info.siblings.iter().any(|sibling| sibling.rfilename == file) {
+            anyhow::bail!("{} has no file {}", self.model_repo, file);
+        }
+        Ok(())
+    }
+
+    fn files(&self) -> ModelFiles {
+        self.files.read().unwrap().clone()
+    }
+
+    /// Use the model at `path` (e.g. an app resource) to populate the cache
+    /// instead of downloading it, when it exists
+    pub fn set_bundled_model(&self, path: PathBuf) {
+        *self.bundled_model.write().unwrap() = Some(path);
+    }
+
+    /// Turn offline mode on or off
+    ///
+    /// While offline no HuggingFace or direct HTTP requests are made: a
+    /// missing model is reported as `TranslationError::ModelNotDownloaded`
+    /// instead of being downloaded.
+    pub fn set_offline(&self, offline: bool) {
+        self.config.write().unwrap().offline = offline;
+        self.save_settings();
+    }
+
+    /// The configured context size, limited to what the model was trained for
+    fn effective_context_size(model: &LlamaModel, configured: u32) -> u32 {
+        let trained = model.n_ctx_train();
+        if trained > 0 && configured > trained {
+            tracing::warn!(
+                configured,
+                trained,
+                "Context size exceeds the model's trained length, clamping"
+            );
+            return trained;
+        }
+        configured
+    }
+
+    /// Number of tokens to generate, clamped to the room left in the context
+    fn max_new_tokens(
+        requested: Option<i32>,
+        prompt_tokens: usize,
+        context_size: u32,
+    ) -> Result<i32> {
+        let requested = requested.unwrap_or(MAX_TOKENS);
+        if requested <= 0 {
+            anyhow::bail!("max_tokens must be positive, got {}", requested);
+        }
+
+        let available = context_size as i32 - prompt_tokens as i32;
+        if available <= 0 {
+            anyhow::bail!(
+                "Input is too long: the prompt uses {} of {} context tokens",
+                prompt_tokens,
+                context_size
+            );
+        }
+
+        Ok(requested.min(available))
+    }
+
+    /// Run the model over the text and return the raw translation
+    async fn generate(
+        &self,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<Translation> {
+        Ok(self.generate_timed(text, direction, options).await?.0)
+    }
+
+    /// `generate`, also reporting how long each phase took
+    #[tracing::instrument(skip(self, text, options), fields(text_len = text.len()))]
+    async fn generate_timed(
+        &self,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<(Translation, GenerationTimings)> {
+        // Ensure model is loaded
+        self.ensure_model_loaded().await?;
+        
+        let mut state = self.model_state.lock().await;
+        self.generate_with(&mut state, text, direction, options, &mut |_| true)
+    }
+
+    /// Run generation on an already locked model, passing each new piece of
+    /// output to `on_text` as it is produced. Output is held back while it
+    /// could still turn out to be a stop sequence, so `on_text` never sees
+    /// one. Generation is abandoned if `on_text` returns false.
+    fn generate_with(
+        &self,
+        state: &mut ModelState,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+        on_text: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<(Translation, GenerationTimings)> {
+        let ModelState { backend, model, lora, draft, prompts, .. } = state;
+        let model = model.as_ref()
+            .context("Model not loaded")?;
+        let started = Instant::now();
+        
+        let system_prompt = self.system_prompt(&direction, options)?;
+        let full_prompt = Self::build_prompt(
+            model,
+            &options.prepare_input(text),
+            &system_prompt,
+            &options.context,
+        )?;
+
+        // The deadline is checked between tokens rather than with
+        // tokio::time::timeout: the loop below never awaits (the llama context
+        // isn't Send, so it can't be held across an await point), so a timer
+        // future would never get the chance to fire.
+        let deadline = options
+            .timeout_ms
+            .map(|ms| (ms, started + Duration::from_millis(ms)));
+
+        let config = self.config();
+        let max_context_size = Self::effective_context_size(model, config.context_size);
+
+        let tokenize_started = Instant::now();
+        // A session's prompt grows by one exchange a turn, only that needs
+        // tokenizing and evaluating when its last prompt was kept
+        let cached = options
+            .session
+            .filter(|_| !options.beam_search())
+            .and_then(|id| prompts.remove(&id))
+            .filter(|cached| {
+                full_prompt.len() > cached.prompt.len() && full_prompt.starts_with(&cached.prompt)
+            });
+        let tokens_list = match &cached {
+            Some(cached) => {
+                let mut tokens = cached.tokens.clone();
+                tokens.extend(
+                    model
+                        .str_to_token(&full_prompt[cached.prompt.len()..], AddBos::Never)
+                        .context("Failed to tokenize prompt")?,
+                );
+                tokens
+            }
+            None => Self::tokenize_prompt(model, &full_prompt)?,
+        };
+        let tokenize = tokenize_started.elapsed();
+        if tokens_list.is_empty() {
+            anyhow::bail!("The prompt produced no tokens");
+        }
+        let max_new_tokens =
+            Self::max_new_tokens(options.max_tokens, tokens_list.len(), max_context_size)?;
+        // Only as large as this translation can use, the KV cache of a full
+        // size context is mostly wasted on a short phrase. A session keeps
+        // its size while it fits, so the saved state can be restored.
+        let needed = tokens_list.len() + max_new_tokens as usize;
+        let context_size = match &cached {
+            Some(cached)
+                if (needed..=max_context_size as usize)
+                    .contains(&(cached.context_size as usize)) =>
+            {
+                cached.context_size
+            }
+            _ => context_bucket(needed, max_context_size),
+        };
+
+        // Create context parameters
+        let ctx_params = LlamaContextParams::default()
+            .with_n_ctx(NonZeroU32::new(context_size))
+            .with_n_batch(config.n_batch)
+            .with_flash_attention(config.flash_attention)
+            .with_n_threads(self.num_threads)
+            .with_offload_kqv(!self.force_cpu);
+        // Beam search keeps two sequences per beam, each needing the room a
+        // single generation would get
+        let ctx_params = if options.beam_search() {
+            let sequences = 2 * options.beam_width as u32;
+            let n_ctx = beam::context_size(model, backend, context_size, sequences)?;
+            ctx_params
+                .with_n_ctx(NonZeroU32::new(n_ctx))
+                .with_n_batch(config.n_batch.max(sequences))
+                .with_n_seq_max(sequences)
+        } else {
+            ctx_params
+        };
+
+        // Create a new context for this translation
+        let context_started = Instant::now();
+        let mut ctx = model
+            .new_context(backend, ctx_params)
+            .context("Failed to create context")?;
+        if let Some(lora) = lora {
+            ctx.lora_adapter_set(&mut lora.adapter, lora.scale)
+                .context("Failed to apply LoRA adapter")?;
+        }
+        let reused = match cached {
+            Some(cached)
+                if cached.context_size == context_size
+                    && cached.flash_attention == config.flash_attention =>
+            {
+                // SAFETY: the state was saved from a context with the same
+                // model, adapter and parameters
+                let read = unsafe { ctx.set_state_data(&cached.state) };
+                if read == cached.state.len() {
+                    cached.tokens.len()
+                } else {
+                    tracing::warn!("Failed to restore the session's prompt, evaluating it again");
+                    ctx.clear_kv_cache();
+                    0
+                }
+            }
+            _ => 0,
+        };
+        let context_setup = context_started.elapsed();
+        let prompt_tokens = tokens_list.len();
+        let timings_for = |prompt_eval: Duration,
+                           first_token: Duration,
+                           generated_tokens: usize,
+                           generation: Duration| {
+            GenerationTimings {
+                prompt_tokens,
+                generated_tokens,
+                tokenize_ms: tokenize.as_secs_f64() * 1000.0,
+                context_size,
+                context_ms: context_setup.as_secs_f64() * 1000.0,
+                prompt_eval_ms: prompt_eval.as_secs_f64() * 1000.0,
+                time_to_first_token_ms: first_token.as_secs_f64() * 1000.0,
+                generation_ms: generation.as_secs_f64() * 1000.0,
+                tokens_per_sec: generated_tokens as f64
+                    / generation.as_secs_f64().max(f64::EPSILON),
+                draft_tokens: 0,
+                accepted_draft_tokens: 0,
+            }
+        };
+
+        let stop_sequences = options.effective_stop_sequences(text);
+        
+        // Create a batch for processing
+        let prompt_started = Instant::now();
+        let n_batch = (config.n_batch as usize).min(tokens_list.len());
+        // Room for a token and the draft model's guesses after it
+        let mut batch = LlamaBatch::new(n_batch.max(speculative::DRAFT_TOKENS + 1), 1);
+        
+        // Process the prompt in batches of at most n_batch tokens, a single
+        // decode call can't take more. Only the very last token needs logits.
+        let last_index = tokens_list.len() - 1;
+        for range in prompt_batches(tokens_list.len() - reused, n_batch) {
+            let range = range.start + reused..range.end + reused;
+            batch.clear();
+            for i in range {
+                batch.add(tokens_list[i], i as i32, &[0], i == last_index)?;
+            }
+            ctx.decode(&mut batch)
+                .context("Failed to decode prompt")?;
+        }
+        tracing::debug!(prompt_tokens = tokens_list.len(), reused, "Prompt evaluated");
+
+        // Keep the evaluated prompt for the session's next turn
+        if let Some(id) = options.session.filter(|_| !options.beam_search()) {
+            let mut state = vec![0; ctx.get_state_size()];
+            // SAFETY: get_state_size is the most a state can take
+            let written = unsafe { ctx.copy_state_data(state.as_mut_ptr()) };
+            state.truncate(written);
+            prompts.insert(
+                id,
+                CachedPrompt {
+                    prompt: full_prompt.clone(),
+                    tokens: tokens_list.clone(),
+                    context_size,
+                    flash_attention: config.flash_attention,
+                    state,
+                },
+            );
+            let sessions = self.sessions.lock().unwrap();
+            prompts.retain(|id, _| sessions.contains_key(id));
+        }
+
+        // Beam search replaces the token loop below
+        if options.beam_search() {
+            let prompt_eval = prompt_started.elapsed();
+            let generation_started = Instant::now();
+            let logits_index = batch.n_tokens() - 1;
+            let search = beam::Search {
+                width: options.beam_width,
+                max_new_tokens: max_new_tokens as usize,
+                stop_at: &|output: &str| {
+                    find_stop_sequence(output, output.len(), &stop_sequences)
+                        .filter(|i| !output[..*i].trim().is_empty())
+                },
+                deadline: deadline.map(|(_, deadline)| deadline),
+            };
+            let outcome = search.run(&mut ctx, &mut batch, tokens_list.len(), logits_index)?;
+            let generation = generation_started.elapsed();
+            self.touch();
+
+            let (best, truncated) = match outcome.beams.first() {
+                Some(best) => (best.text.as_str(), best.truncated),
+                None => ("", true),
+            };
+            if let Some((timeout_ms, _)) = deadline.filter(|_| outcome.timed_out) {
+                tracing::warn!(timeout_ms, "Translation timed out");
+                return Err(TranslationError::Timeout {
+                    timeout_ms,
+                    partial: strip_prompt_echo(best, &system_prompt).trim().to_string(),
+                }
+                .into());
+            }
+            emit_new(best.trim_end(), &mut 0, on_text);
+
+            let timings =
+                timings_for(prompt_eval, started.elapsed(), outcome.decoded_tokens, generation);
+            tracing::debug!(
+                beams = outcome.beams.len(),
+                decoded_tokens = outcome.decoded_tokens,
+                truncated,
+                elapsed_ms = started.elapsed().as_millis() as u64,
+                "Beam search finished"
+            );
+
+            let mut candidates = Vec::new();
+            for beam in &outcome.beams {
+                let candidate = self.clean_output(&beam.text, text, &system_prompt, options);
+                if !candidates.contains(&candidate) {
+                    candidates.push(candidate);
+                }
+            }
+            let translation = candidates.first().cloned().unwrap_or_default();
+            candidates.truncate(options.n_alternatives.min(options.beam_width));
+            let translation = Translation {
+                text: translation,
+                truncated,
+                alternatives: if options.n_alternatives > 1 { candidates } else { Vec::new() },
+                confidence: None,
+                romaji: None,
+                furigana: None,
+                timings: options.collect_timings.then(|| timings.clone()),
+            };
+            return Ok((translation, timings));
+        }
+
+        // Speculation only reproduces greedy picks from the full vocabulary
+        let speculate = options.sampling.is_greedy()
+            && options.grammar.is_none()
+            && !options.include_logprobs
+            && config.n_batch > 1;
+        let mut speculator = match draft.as_ref().filter(|_| speculate) {
+            Some(draft) => {
+                let draft_params = LlamaContextParams::default()
+                    .with_n_ctx(NonZeroU32::new(context_size))
+                    .with_n_batch(config.n_batch)
+                    .with_flash_attention(config.flash_attention)
+                    .with_n_threads(self.num_threads)
+                    .with_offload_kqv(!self.force_cpu);
+                let draft_tokens = speculative::DRAFT_TOKENS.min(config.n_batch as usize - 1);
+                Speculator::new(draft, backend, draft_params, &tokens_list, n_batch, draft_tokens)
+                    .inspect_err(|e| tracing::warn!(error = %e, "Decoding without the draft model"))
+                    .ok()
+            }
+            None => None,
+        };
+        let prompt_eval = prompt_started.elapsed();
+        let generation_started = Instant::now();
+        
+        // Initialize generation position
+        let mut n_cur = tokens_list.len() as i32;
+        
+        // Initialize the decoder for UTF-8 output
+        let mut decoder = encoding_rs::UTF_8.new_decoder();
+        
+        // Create a sampler for token generation
+        // Greedy by default for deterministic output (best for translation)
+        let mut sampler = options.sampling.build_sampler();
+        if let Some(grammar) = &options.grammar {
+            // Grammar first so the other samplers only see allowed tokens
+            let grammar = sampling::grammar_sampler(model, grammar)?;
+            sampler = LlamaSampler::chain_simple([grammar, sampler]);
+        }
+        
+        // Generate the translation
+        let mut translation = String::new();
+        let n_len = n_cur + max_new_tokens;
+        // Stays set unless the model ends the output itself
+        let mut truncated = true;
+        let mut logprobs = options.include_logprobs.then(Vec::new);
+        let mut hit_stop_sequence = false;
+        // Bytes of `translation` already passed to `on_text`
+        let mut emitted = 0;
+        // Where in the batch the logits for the next token are
+        // Key: Use batch.n_tokens() - 1, not n_cur - 1!
+        let mut logits_index = batch.n_tokens() - 1;
+        // Draft model guesses the model agreed with, decoded but not output
+        let mut guessed = VecDeque::new();
+        // Since `started`, when the first token was picked
+        let mut first_token = None;
+        
+        while n_cur < n_len {
+            // Sample the next token, unless the draft model guessed it
+            let (token, decoded) = match guessed.pop_front() {
+                Some(token) => (token, true),
+                None => (sampler.sample(&ctx, logits_index), false),
+            };
+            sampler.accept(token);
+            first_token.get_or_insert_with(|| started.elapsed());
+            let logprob = match logprobs {
+                Some(_) => sampling::token_logprob(ctx.get_logits_ith(logits_index), token),
+                None => None,
+            };
+            
+            // Check for end of generation (EOS or end-of-turn)
+            if model.is_eog_token(token) {
+                truncated = false;
+                break;
+            }
+            
+            // Convert token to text
+            let output_bytes = model
+                .token_to_bytes(token, Special::Tokenize)
+                .context("Failed to convert token to bytes")?;
+            
+            // Decode bytes to string, holding back a character split
+            // across tokens until its remaining bytes arrive
+            let output_string = decode_bytes(&mut decoder, &output_bytes, false);
+            
+            // Add to translation
+            translation.push_str(&output_string);
+            if let (Some(logprobs), Some(logprob)) = (logprobs.as_mut(), logprob) {
+                logprobs.push(TokenLogprob {
+                    text: output_string.clone(),
+                    logprob,
+                });
+            }
+
+            // Stop on (and drop) any stop sequence the new text completed,
+            // ignoring leading whitespace before the translation has started
+            let stop_at =
+                find_stop_sequence(&translation, output_string.len(), &stop_sequences);
+            if let Some(stop_at) = stop_at.filter(|i| !translation[..*i].trim().is_empty()) {
+                translation.truncate(stop_at);
+                truncated = false;
+                hit_stop_sequence = true;
+                break;
+            }
+
+            let ready = translation.len() - partial_stop_len(&translation, &stop_sequences);
+            if !emit_new(&translation[..ready], &mut emitted, on_text) {
+                anyhow::bail!("Translation cancelled");
+            }
+            
+            if let Some((timeout_ms, deadline)) = deadline {
+                if Instant::now() >= deadline {
+                    tracing::warn!(timeout_ms, "Translation timed out");
+                    return Err(TranslationError::Timeout {
+                        timeout_ms,
+                        partial: strip_prompt_echo(&translation, &system_prompt)
+                            .trim()
+                            .to_string(),
+                    }
+                    .into());
+                }
+            }
+            
+            // Process the new token, along with guesses of the next ones
+            // when speculating
+            let limit = (n_len - n_cur - 1) as usize;
+            match speculator.as_mut().filter(|_| limit > 0) {
+                _ if decoded => {}
+                Some(speculator) => {
+                    let (agreed, index) =
+                        speculator.step(&mut ctx, &mut batch, token, n_cur, limit)?;
+                    guessed.extend(agreed);
+                    logits_index = index;
+                }
+                None => {
+                    batch.clear();
+                    batch.add(token, n_cur, &[0], true)?;
+                    ctx.decode(&mut batch)
+                        .context("Failed to decode next token")?;
+                    logits_index = batch.n_tokens() - 1;
+                }
+            }
+            
+            n_cur += 1;
+        }
+        let (drafted, accepted) = speculator.as_ref().map_or((0, 0), Speculator::acceptance);
+        if speculator.is_some() {
+            tracing::debug!(drafted, accepted, "Speculative decoding");
+        }
+        
+        // Flush whatever the last token left in the decoder, so a trailing
+        // multibyte character isn't silently dropped. After a stop sequence
+        // those bytes belong to the discarded text.
+        if !hit_stop_sequence {
+            translation.push_str(&decode_bytes(&mut decoder, &[], true));
+        }
+        emit_new(translation.trim_end(), &mut emitted, on_text);
+        
+        let generation = generation_started.elapsed();
+
+        // Still holding the model lock, so the idle unloader can't slip in
+        // between the end of generation and this update
+        self.touch();
+
+        let generated_tokens = (n_cur - tokens_list.len() as i32) as usize;
+        let first_token = first_token.unwrap_or_else(|| started.elapsed());
+        let timings = GenerationTimings {
+            draft_tokens: drafted,
+            accepted_draft_tokens: accepted,
+            ..timings_for(prompt_eval, first_token, generated_tokens, generation)
+        };
+
+        tracing::debug!(
+            prompt_tokens = timings.prompt_tokens,
+            generated_tokens,
+            truncated,
+            prompt_eval_ms = timings.prompt_eval_ms,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "Translation generated"
+        );
+
+        let translation = Translation {
+            text: self.clean_output(&translation, text, &system_prompt, options),
+            truncated,
+            alternatives: Vec::new(),
+            confidence: logprobs.map(Confidence::new),
+            romaji: None,
+            furigana: None,
+            timings: options.collect_timings.then(|| timings.clone()),
+        };
+        Ok((translation, timings))
+    }
+
+    /// Generated `output` for `text` without any echoed prompt, labels and
+    /// extra whitespace
+    fn clean_output(
+        &self,
+        output: &str,
+        text: &str,
+        system_prompt: &str,
+        options: &TranslateOptions,
+    ) -> String {
+        let output = strip_prompt_echo(output, system_prompt);
+        let config = self.config();
+        let output = strip_output_prefix(output, text, &config.output_prefixes).trim();
+        let output = glossary::apply(output, text, &config.glossary);
+        if options.trim_output {
+            output
+        } else {
+            let leading = &text[..text.len() - text.trim_start().len()];
+            let trailing = &text[text.trim_end().len()..];
+            format!("{}{}{}", leading, output, trailing)
+        }
+    }
+
+    /// Time `iterations` translations of a fixed English text
+    ///
+    /// One untimed run comes first, so model loading and cold caches don't
+    /// skew the numbers. Runs bypass the translation cache. With a draft
+    /// model set, the runs are repeated with the draft put aside, so the
+    /// two throughputs can be compared.
+    pub async fn benchmark(&self, iterations: usize) -> Result<Benchmark> {
+        if !(1..=MAX_BENCHMARK_ITERATIONS).contains(&iterations) {
+            anyhow::bail!(
+                "Iterations must be between 1 and {}, got {}",
+                MAX_BENCHMARK_ITERATIONS,
+                iterations
+            );
+        }
+
+        let _permit = self.in_flight.try_acquire().map_err(|_| TranslationError::Busy {
+            limit: self.max_in_flight,
+        })?;
+
+        let direction = LanguagePair::EN_JA;
+        let options = TranslateOptions::default();
+        self.generate(BENCHMARK_TEXT, direction.clone(), &options).await?;
+
+        let mut runs = Vec::with_capacity(iterations);
+        for _ in 0..iterations {
+            let (_, timings) = self
+                .generate_timed(BENCHMARK_TEXT, direction.clone(), &options)
+                .await?;
+            runs.push(timings);
+        }
+
+        // Under a single lock, so nothing else translates while the draft
+        // is put aside
+        self.ensure_model_loaded().await?;
+        let mut state = self.model_state.lock().await;
+        let without_draft = match state.draft.take() {
+            Some(draft) => {
+                let runs = (0..iterations)
+                    .map(|_| {
+                        self.generate_with(
+                            &mut state,
+                            BENCHMARK_TEXT,
+                            direction.clone(),
+                            &options,
+                            &mut |_| true,
+                        )
+                        .map(|(_, timings)| timings.tokens_per_sec)
+                    })
+                    .collect::<Result<Vec<_>>>();
+                state.draft = Some(draft);
+                Some(runs?.iter().sum::<f64>() / iterations as f64)
+            }
+            None => None,
+        };
+        drop(state);
+
+        let mean = |value: fn(&GenerationTimings) -> f64| {
+            runs.iter().map(value).sum::<f64>() / runs.len() as f64
+        };
+        let drafted: usize = runs.iter().map(|run| run.draft_tokens).sum();
+        let accepted: usize = runs.iter().map(|run| run.accepted_draft_tokens).sum();
+        Ok(Benchmark {
+            mean_tokens_per_sec_without_draft: without_draft,
+            draft_acceptance: (drafted > 0).then(|| accepted as f64 / drafted as f64),
+            mean_tokens_per_sec: mean(|run| run.tokens_per_sec),
+            mean_context_ms: mean(|run| run.context_ms),
+            mean_prompt_eval_ms: mean(|run| run.prompt_eval_ms),
+            mean_time_to_first_token_ms: mean(|run| run.time_to_first_token_ms),
+            mean_generation_ms: mean(|run| run.generation_ms),
+            runs,
+        })
+    }
+
+    /// Whether `text` has anything to translate
+    ///
+    /// Fails with `TranslationError::InputTooLong` for text over the
+    /// `max_input_chars` limit, so huge pastes never reach the tokenizer.
+    fn check_input(&self, text: &str) -> Result<bool> {
+        if text.trim().is_empty() {
+            return Ok(false);
+        }
+
+        let limit = self.config().max_input_chars;
+        let chars = text.chars().count();
+        if chars > limit {
+            return Err(TranslationError::InputTooLong { chars, limit }.into());
+        }
+        Ok(true)
+    }
+
+    /// Translate `text`, passing each piece of output to `on_token` as the
+    /// model produces it
+    ///
+    /// For embedders that want progress without Tauri events. The pieces
+    /// join up to the output as generated, stop sequences excluded, and the
+    /// returned translation is that output cleaned up as by `translate`.
+    /// Generation is one pass over `text` that bypasses the cache, so the
+    /// options that split the text or add alternatives don't apply.
+    ///
+    /// `on_token` is called on a blocking thread of the tokio pool, not in
+    /// async context: it may block, e.g. on `blocking_send`, but not call
+    /// `block_on`. Generation waits while it runs, so keep it quick. It
+    /// isn't called at all for whitespace-only text.
+    pub async fn translate_with_progress(
+        self: &Arc<Self>,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+        mut on_token: impl FnMut(&str) + Send + 'static,
+    ) -> Result<Translation> {
+        if !self.check_input(text)? {
+            return Ok(Translation::empty());
+        }
+
+        self.generate_with_progress(text, direction, options, move |piece| {
+            on_token(piece);
+            true
+        })
+        .await
+    }
+
+    /// Generate on a blocking thread, where `on_text` runs for each piece
+    /// and stops generation by returning false
+    async fn generate_with_progress(
+        self: &Arc<Self>,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+        mut on_text: impl FnMut(&str) -> bool + Send + 'static,
+    ) -> Result<Translation> {
+        let options = self.resolve_sampling(&direction, options)?;
+        options.sampling.validate()?;
+        options.validate_beam_search()?;
+        let _permit = self.in_flight.try_acquire().map_err(|_| TranslationError::Busy {
+            limit: self.max_in_flight,
+        })?;
+        self.ensure_model_loaded().await?;
+        let mut state = Arc::clone(&self.model_state).lock_owned().await;
+
+        // The llama context isn't Send, so it is driven from a blocking
+        // thread, where the callback is also free to block
+        let worker = Arc::clone(self);
+        let text = text.to_string();
+        let (translation, _) = tokio::task::spawn_blocking(move || {
+            worker.generate_with(&mut state, &text, direction, &options, &mut on_text)
+        })
+        .await
+        .context("Generation task failed")??;
+        Ok(translation)
+    }
+
+    /// Translate `text`, yielding the output piece by piece as the model
+    /// produces it
+    ///
+    /// Items arrive in generation order and join up to the translation as
+    /// generated: stop sequences are never yielded, but unlike `translate`
+    /// an echoed prompt isn't removed and nothing is cached. A full channel
+    /// pauses generation until the consumer catches up. Dropping the stream
+    /// cancels generation at the next token and frees the model for other
+    /// requests. An error, including a timeout, ends the stream.
+    ///
+    /// Input that is too long fails here, before anything is generated.
+    /// Whitespace-only input gives an empty stream without loading the
+    /// model.
+    pub fn translate_stream(
+        self: &Arc<Self>,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<impl Stream<Item = Result<String>>> {
+        let (sender, receiver) = mpsc::channel(TOKEN_CHANNEL_CAPACITY);
+        if !self.check_input(text)? {
+            return Ok(stream_from(receiver));
+        }
+
+        let service = Arc::clone(self);
+        let (text, options) = (text.to_string(), options.clone());
+        tokio::spawn(async move {
+            let tokens = sender.clone();
+            let result = service
+                .generate_with_progress(&text, direction, &options, move |piece| {
+                    tokens.blocking_send(Ok(piece.to_string())).is_ok()
+                })
+                .await;
+
+            if let Err(e) = result {
+                let _ = sender.send(Err(e)).await;
+            }
+        });
+
+        Ok(stream_from(receiver))
+    }
+
+    /// Open a session whose translations see the exchanges before them
+    ///
+    /// Useful for conversations, where earlier lines help with pronouns and
+    /// tone. Each session remembers up to `MAX_SESSION_TURNS` of its latest
+    /// exchanges, which are part of every prompt in it. The context state
+    /// after a session's last prompt is kept with the model, so the next
+    /// translation only evaluates the new exchange. When the history is full,
+    /// its older half is forgotten and the next prompt is evaluated in full.
+    /// Each open session holds the KV cache of its prompt, up to a few MB
+    /// for a long history, until it is closed or the model is unloaded, so
+    /// close sessions when done with them.
+    pub fn create_session(&self) -> Result<u64> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= MAX_SESSIONS {
+            anyhow::bail!("Too many open sessions (limit {}), close one first", MAX_SESSIONS);
+        }
+
+        let id = self.next_session_id.fetch_add(1, Ordering::Relaxed);
+        sessions.insert(id, VecDeque::new());
+        Ok(id)
+    }
+
+    /// Translate `text` with the session's earlier exchanges in the same
+    /// direction as context, then remember this one
+    pub async fn translate_in_session(
+        &self,
+        session_id: u64,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<Translation> {
+        let context = match self.sessions.lock().unwrap().get(&session_id) {
+            Some(turns) => turns
+                .iter()
+                .filter(|turn| turn.direction == direction)
+                .map(|turn| (turn.source.clone(), turn.translation.clone()))
+                .collect(),
+            None => anyhow::bail!("Unknown session {}", session_id),
+        };
+
+        let options = TranslateOptions {
+            context,
+            session: Some(session_id),
+            ..options.clone()
+        };
+        let translation = self.translate(text, direction.clone(), &options).await?;
+
+        // The session may have been closed while translating
+        if let Some(turns) = self.sessions.lock().unwrap().get_mut(&session_id) {
+            if !translation.text.is_empty() {
+                turns.push_back(SessionTurn {
+                    direction,
+                    source: text.to_string(),
+                    translation: translation.text.clone(),
+                });
+            }
+            // Dropping the older half at once, rather than one exchange a
+            // turn, keeps most prompts continuing the one before
+            if turns.len() > MAX_SESSION_TURNS {
+                turns.drain(..turns.len() - MAX_SESSION_TURNS / 2);
+            }
+        }
+
+        Ok(translation)
+    }
+
+    /// Forget a session's history, returns false if it wasn't open
+    pub fn close_session(&self, session_id: u64) -> bool {
+        let closed = self.sessions.lock().unwrap().remove(&session_id).is_some();
+        // Otherwise the next session translation drops its prompt
+        if let Ok(mut state) = self.model_state.try_lock() {
+            state.prompts.remove(&session_id);
+        }
+        closed
+    }
+
+    /// Translate text of any length piece by piece
+    ///
+    /// The text is split at paragraph, line or sentence boundaries into
+    /// pieces the model can handle in one go. The whitespace around each
+    /// piece, including the blank lines between paragraphs, is kept as-is.
+    /// With `overlap_sentences`, each piece is translated with the end of
+    /// the one before it as context.
+    pub async fn translate_long(
+        &self,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<String> {
+        let mut output = String::with_capacity(text.len());
+        let mut piece_options = options.clone();
+        for piece in chunking::split(text, MAX_CHUNK_CHARS) {
+            let core = piece.trim();
+            if core.is_empty() {
+                output.push_str(piece);
+                continue;
+            }
+
+            let mut translated = self
+                .translate(core, direction.clone(), &piece_options)
+                .await?
+                .text;
+            if let Some((_, previous)) = piece_options.context.last() {
+                // Drop the context's translation if the model repeated it
+                if let Some(rest) = translated.strip_prefix(previous.as_str()) {
+                    if !rest.trim().is_empty() {
+                        translated = rest.trim_start().to_string();
+                    }
+                }
+            }
+
+            let leading = piece.len() - piece.trim_start().len();
+            output.push_str(&piece[..leading]);
+            output.push_str(&translated);
+            output.push_str(&piece[leading + core.len()..]);
+
+            if options.overlap_sentences > 0 {
+                piece_options.context = vec![(
+                    chunking::last_sentences(core, options.overlap_sentences).to_string(),
+                    chunking::last_sentences(&translated, options.overlap_sentences).to_string(),
+                )];
+            }
+        }
+        Ok(output)
+    }
+
+    /// Translate a UTF-8 text file, writing the result to `output` or
+    /// returning it when there is no output path
+    ///
+    /// The file is read a paragraph at a time, so large files are never held
+    /// in memory or sent to the model whole. An output file only appears once
+    /// the whole input has been translated.
+    pub async fn translate_file(
+        &self,
+        input: &Path,
+        output: Option<&Path>,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<Option<String>> {
+        let file = tokio::fs::File::open(input)
+            .await
+            .with_context(|| format!("Failed to open {}", input.display()))?;
+        let reader = tokio::io::BufReader::new(file);
+
+        match output {
+            Some(output) => {
+                write_atomically(output, |part_path| async move {
+                    let mut writer = tokio::fs::File::create(&part_path)
+                        .await
+                        .with_context(|| format!("Failed to create {}", output.display()))?;
+                    self.translate_reader(input, reader, &mut writer, direction, options)
+                        .await
+                })
+                .await?;
+                Ok(None)
+            }
+            None => {
+                let mut buffer = Vec::new();
+                self.translate_reader(input, reader, &mut buffer, direction, options)
+                    .await?;
+                Ok(Some(String::from_utf8(buffer).context("Translation is not valid UTF-8")?))
+            }
+        }
+    }
+
+    async fn translate_reader<R, W>(
+        &self,
+        input: &Path,
+        mut reader: R,
+        writer: &mut W,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<()>
+    where
+        R: tokio::io::AsyncBufRead + Unpin,
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+        let mut pending = String::new();
+        let mut line = String::new();
+        let mut after_blank = false;
+
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line).await.map_err(|e| {
+                if e.kind() == std::io::ErrorKind::InvalidData {
+                    anyhow::anyhow!("{} is not valid UTF-8 text", input.display())
+                } else {
+                    anyhow::Error::new(e).context(format!("Failed to read {}", input.display()))
+                }
+            })?;
+
+            // Translate what has been collected at the start of a new
+            // paragraph, or once a single paragraph has grown too large
+            let blank = line.trim().is_empty();
+            let new_paragraph = !blank && after_blank;
+            if read == 0 || new_paragraph || pending.len() >= FILE_BUFFER_BYTES {
+                let translated = self.translate_long(&pending, direction.clone(), options).await?;
+                writer
+                    .write_all(translated.as_bytes())
+                    .await
+                    .context("Failed to write translation")?;
+                pending.clear();
+            }
+            if read == 0 {
+                break;
+            }
+
+            pending.push_str(&line);
+            after_blank = blank;
+        }
+
+        writer.flush().await.context("Failed to write translation")?;
+        Ok(())
+    }
+
+    /// Translate the cue text of SRT subtitles, keeping indices and timings
+    ///
+    /// Each cue's lines are translated together so sentences split across
+    /// lines keep their meaning. Blank lines are dropped from the output,
+    /// since they would end the cue early.
+    pub async fn translate_srt(
+        &self,
+        content: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<String> {
+        let mut cues = subtitles::parse(content)?;
+        for cue in &mut cues {
+            let translated = self.translate(&cue.text, direction.clone(), options).await?;
+            cue.text = translated
+                .text
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
+        Ok(subtitles::render(&cues))
+    }
+
+    /// Translate the string values of a JSON document, keeping its
+    /// structure, keys and every other value
+    ///
+    /// `keys` limits translation to the strings under those keys, see
+    /// `json::strings`. Invalid JSON is an error rather than being
+    /// translated as text. The output is pretty-printed if the input spans
+    /// several lines.
+    pub async fn translate_json(
+        &self,
+        content: &str,
+        direction: LanguagePair,
+        keys: Option<&[String]>,
+        options: &TranslateOptions,
+    ) -> Result<String> {
+        let mut document: serde_json::Value =
+            serde_json::from_str(content).context("Invalid JSON")?;
+
+        for string in json::strings(&document, keys) {
+            let translated = self.translate(&string.text, direction.clone(), options).await?;
+            if let Some(value) = document.pointer_mut(&string.pointer) {
+                *value = serde_json::Value::String(translated.text);
+            }
+        }
+
+        if content.trim().contains('\n') {
+            serde_json::to_string_pretty(&document).context("Failed to serialize JSON")
+        } else {
+            serde_json::to_string(&document).context("Failed to serialize JSON")
+        }
+    }
+
+    /// Translate the cells of some CSV columns, leaving the others as they
+    /// were
+    ///
+    /// `columns` are 0-based. A header row is kept untranslated, and blank
+    /// cells stay blank. Fields are quoted in the output where CSV requires
+    /// it, which may differ from the input's quoting. Rows with a different
+    /// number of fields than the first are an error.
+    pub async fn translate_csv(
+        &self,
+        content: &str,
+        columns: &[usize],
+        direction: LanguagePair,
+        has_header: bool,
+        options: &TranslateOptions,
+    ) -> Result<String> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(false)
+            .from_reader(content.as_bytes());
+        let mut writer = csv::WriterBuilder::new().from_writer(Vec::new());
+
+        for (row, record) in reader.records().enumerate() {
+            let record = record.context("Invalid CSV")?;
+            if let Some(&column) = columns.iter().find(|&&column| column >= record.len()) {
+                anyhow::bail!(
+                    "Column {} is out of range, rows have {} columns",
+                    column,
+                    record.len()
+                );
+            }
+
+            let mut fields = Vec::with_capacity(record.len());
+            for (column, field) in record.iter().enumerate() {
+                let translate = !(has_header && row == 0)
+                    && columns.contains(&column)
+                    && !field.trim().is_empty();
+                if translate {
+                    fields.push(self.translate(field, direction.clone(), options).await?.text);
+                } else {
+                    fields.push(field.to_string());
+                }
+            }
+            writer.write_record(&fields).context("Failed to write CSV")?;
+        }
+
+        let output = writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .context("Failed to write CSV")?;
+        String::from_utf8(output).context("Translated CSV is not valid UTF-8")
+    }
+
+    /// Translate `text` and then back again, scoring how much survived
+    ///
+    /// A low similarity hints that meaning was lost along the way, though
+    /// valid paraphrases also score below 1, so it's best used to compare
+    /// runs on the same inputs.
+    pub async fn round_trip(
+        &self,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<RoundTrip> {
+        let reverse = direction.reverse();
+        let translation = self.translate(text, direction, options).await?.text;
+        let round_tripped = self.translate(&translation, reverse, options).await?.text;
+
+        Ok(RoundTrip {
+            similarity: quality::similarity(text, &round_tripped),
+            translation,
+            round_tripped,
+        })
+    }
+    
+    /// Report the backend, offloading and threading actually in use
+    pub async fn backend_info(&self) -> BackendInfo {
+        let state = self.model_state.lock().await;
+        let gpu_support = state.backend.supports_gpu_offload();
+        let requested_layers = if self.force_cpu || !gpu_support || state.cpu_fallback {
+            0
+        } else {
+            self.gpu_layers
+        };
+
+        let (gpu_layers, context_size) = match state.model.as_ref() {
+            Some(model) => (
+                Some(requested_layers.min(model.n_layer())),
+                Self::effective_context_size(model, self.config().context_size),
+            ),
+            None => (None, self.config().context_size),
+        };
+
+        let backend = if gpu_layers.unwrap_or(requested_layers) == 0 {
+            "CPU"
+        } else if cfg!(target_os = "macos") {
+            "Metal"
+        } else {
+            "GPU"
+        };
+
+        BackendInfo {
+            backend: backend.to_string(),
+            gpu_support,
+            gpu_layers,
+            cpu_fallback: state.cpu_fallback,
+            num_threads: self.num_threads,
+            context_size,
+        }
+    }
+
+    /// Report process and model memory use, loaded or not
+    pub async fn memory_stats(&self) -> MemoryStats {
+        let resident_bytes = sysinfo::get_current_pid().ok().and_then(|pid| {
+            let mut system = sysinfo::System::new();
+            system.refresh_process(pid);
+            system.process(pid).map(|process| process.memory())
+        });
+
+        let backend = self.backend_info().await;
+        MemoryStats {
+            resident_bytes,
+            model_bytes: self.model_cache_info().await.size_bytes,
+            model_loaded: self.is_model_loaded().await,
+            gpu_in_use: backend.gpu_layers.is_some_and(|layers| layers > 0),
+        }
+    }
+
+    /// Estimate the memory `model_file` from the cache dir needs once loaded
+    ///
+    /// Weights count at their file size, so the quantization is accounted
+    /// for. The KV cache is sized as if every layer used attention, which
+    /// overestimates hybrid models. Only the model header is read. The
+    /// comparison is against system RAM, the VRAM of a discrete GPU can't
+    /// be queried.
+    pub async fn estimate_model_memory(&self, model_file: &str) -> Result<MemoryEstimate> {
+        if Path::new(model_file).file_name() != Some(std::ffi::OsStr::new(model_file)) {
+            anyhow::bail!("Invalid model file name: {}", model_file);
+        }
+        let dir = &self.cache_dir;
+
+        let mut weight_bytes = 0;
+        for file in shard_files(model_file) {
+            let metadata = tokio::fs::metadata(dir.join(&file))
+                .await
+                .with_context(|| format!("Model file {} is not in the cache", file))?;
+            weight_bytes += metadata.len();
+        }
+
+        let configured = self.config().context_size;
+        // The header-only model is freed before the lock on the backend
+        let estimated_bytes = {
+            let state = self.model_state.lock().await;
+            let params = LlamaModelParams::default().with_vocab_only(true);
+            let model = LlamaModel::load_from_file(&state.backend, dir.join(model_file), &params)
+                .context("Failed to read model header")?;
+            let trained = model.n_ctx_train();
+            let context_size = if trained > 0 { configured.min(trained) } else { configured };
+            estimate_memory(
+                weight_bytes,
+                model.n_layer(),
+                model.n_embd().max(0) as u32,
+                model.n_head(),
+                model.n_head_kv(),
+                context_size,
+            )
+        };
+
+        let mut system = sysinfo::System::new();
+        system.refresh_memory();
+        let available_bytes = system.available_memory();
+
+        Ok(MemoryEstimate {
+            estimated_bytes,
+            available_bytes,
+            fits: estimated_bytes <= available_bytes,
+        })
+    }
+
+    /// Check if the model is currently loaded
+    pub async fn is_model_loaded(&self) -> bool {
+        self.model_state.lock().await.check_loaded()
+    }
+
+    /// Run every diagnostic check, reporting failures instead of stopping
+    /// at the first one
+    ///
+    /// Loads the model for the test translation if it is downloaded, but
+    /// never downloads it.
+    pub async fn health_check(&self) -> HealthReport {
+        let mut errors = Vec::new();
+        let cache_dir = &self.cache_dir;
+
+        let probe = cache_dir.join(".konnyaku-write-test");
+        let cache_dir_writable = match tokio::fs::write(&probe, b"").await {
+            Ok(()) => {
+                let _ = tokio::fs::remove_file(&probe).await;
+                true
+            }
+            Err(e) => {
+                errors.push(format!("cache_dir_writable: {}", e));
+                false
+            }
+        };
+
+        let free_disk_bytes = match fs4::available_space(cache_dir) {
+            Ok(available) => Some(available),
+            Err(e) => {
+                errors.push(format!("free_disk_bytes: {}", e));
+                None
+            }
+        };
+
+        let model_downloaded = self.is_model_downloaded();
+        let test_translation = if model_downloaded {
+            let options = TranslateOptions {
+                timeout_ms: Some(HEALTH_CHECK_TIMEOUT_MS),
+                ..TranslateOptions::default()
+            };
+            let direction = LanguagePair::EN_JA;
+            match self.translate_fresh(HEALTH_CHECK_TEXT, direction, &options).await {
+                Ok(translation) => Some(translation.text),
+                Err(e) => {
+                    errors.push(format!("test_translation: {}", e));
+                    None
+                }
+            }
+        } else {
+            errors.push("test_translation: skipped, the model is not downloaded".to_string());
+            None
+        };
+
+        let backend = self.backend_info().await;
+        HealthReport {
+            model_downloaded,
+            model_loaded: self.is_model_loaded().await,
+            cache_dir: cache_dir.display().to_string(),
+            cache_dir_writable,
+            free_disk_bytes,
+            backend: backend.backend,
+            num_threads: self.num_threads,
+            test_translation,
+            errors,
+        }
+    }
+
+    /// Release the loaded model, it is reloaded on the next translation
+    pub async fn unload_model(&self) {
+        self.model_state.lock().await.unload();
+        self.set_phase(ModelPhase::Idle);
+    }
+
+    /// Whether the model is ready to translate, with the reason if not
+    pub fn readiness(&self) -> Readiness {
+        Readiness::new(self.model_phase(), self.is_model_downloaded())
+    }
+
+    /// Where the model is in downloading and loading
+    pub fn model_phase(&self) -> ModelPhase {
+        self.phase.borrow().clone()
+    }
+
+    /// Watch the model phase, to be told about each change
+    pub fn subscribe_model_phase(&self) -> watch::Receiver<ModelPhase> {
+        self.phase.subscribe()
+    }
+
+    fn set_phase(&self, phase: ModelPhase) {
+        self.phase.send_if_modified(|current| {
+            let changed = *current != phase;
+            *current = phase;
+            changed
+        });
+    }
+
+    /// The loaded model's GGUF metadata, loading the model if it never was
+    pub async fn model_metadata(&self) -> Result<ModelMetadata> {
+        if let Some(metadata) = self.metadata.read().unwrap().clone() {
+            return Ok(metadata);
+        }
+
+        self.ensure_model_loaded().await?;
+        self.metadata
+            .read()
+            .unwrap()
+            .clone()
+            .context("Model metadata unavailable")
+    }
+
+    /// Report the cached model's location and size, over all its shards
+    pub async fn model_cache_info(&self) -> ModelCacheInfo {
+        let files = self.files();
+        let mut size_bytes = 0;
+        let mut exists = true;
+        for (_, path) in &files.shards {
+            match tokio::fs::metadata(path).await {
+                Ok(metadata) if metadata.is_file() => size_bytes += metadata.len(),
+                _ => exists = false,
+            }
+        }
+        ModelCacheInfo {
+            path: files.path.display().to_string(),
+            size_bytes,
+            exists,
+        }
+    }
+
+    /// Every GGUF file in the cache directory, largest first
+    ///
+    /// A cache directory that doesn't exist yet just has no models.
+    pub async fn list_cached_models(&self) -> Result<Vec<CachedModel>> {
+        let mut entries = match tokio::fs::read_dir(&self.cache_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context("Failed to read model cache directory"),
+        };
+
+        let loaded = self.is_model_loaded().await;
+        let files = self.files();
+        let mut models = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .context("Failed to read model cache directory")?
+        {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let is_gguf = Path::new(&file_name)
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("gguf"));
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            if !is_gguf || !metadata.is_file() {
+                continue;
+            }
+
+            let path = entry.path();
+            let active = files.shards.iter().any(|(_, shard)| *shard == path);
+            models.push(CachedModel {
+                file_name,
+                size_bytes: metadata.len(),
+                active,
+                loaded: active && loaded,
+            });
+        }
+
+        models.sort_by_key(|model| std::cmp::Reverse(model.size_bytes));
+        Ok(models)
+    }
+
+    /// Unload the model and remove the cached file so it is downloaded fresh
+    ///
+    /// Fails while the model is being downloaded, installed or repaired.
+    pub async fn delete_cached_model(&self) -> Result<()> {
+        let _download = self.lock_for_switch()?;
+        let files = self.files();
+        if !files.path.starts_with(&self.cache_dir) {
+            anyhow::bail!(
+                "The model at {} is not in the cache and won't be deleted",
+                files.path.display()
+            );
+        }
+
+        // Held until the files are gone, so no translation loads them again
+        let mut state = self.model_state.lock().await;
+        state.unload();
+        *self.metadata.write().unwrap() = None;
+        self.set_phase(ModelPhase::Idle);
+
+        for (_, path) in &files.shards {
+            match tokio::fs::remove_file(path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).context("Failed to delete cached model"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Byte offset of the earliest stop sequence that ends within the last
+/// `appended` bytes of `buffer`
+///
+/// Only the tail that could contain a newly completed match is scanned, so
+/// the cost per token doesn't grow with the length of the output.
+fn find_stop_sequence(buffer: &str, appended: usize, stops: &[String]) -> Option<usize> {
+    let longest = stops.iter().map(String::len).max()?;
+    let mut tail_start = buffer.len().saturating_sub(appended + longest.saturating_sub(1));
+    while !buffer.is_char_boundary(tail_start) {
+        tail_start -= 1;
+    }
+
+    let tail = &buffer[tail_start..];
+    stops
+        .iter()
+        .filter_map(|stop| tail.find(stop.as_str()))
+        .min()
+        .map(|offset| tail_start + offset)
+}
+
+/// Split `n_tokens` prompt positions into consecutive ranges of at most
+/// `n_batch`
+fn prompt_batches(n_tokens: usize, n_batch: usize) -> impl Iterator<Item = std::ops::Range<usize>> {
+    let n_batch = n_batch.max(1);
+    (0..n_tokens)
+        .step_by(n_batch)
+        .map(move |start| start..(start + n_batch).min(n_tokens))
+}
+
+/// Bytes a model needs loaded: its weights, an f16 KV cache for
+/// `context_size` tokens and the compute buffers
+fn estimate_memory(
+    weight_bytes: u64,
+    n_layer: u32,
+    n_embd: u32,
+    n_head: u32,
+    n_head_kv: u32,
+    context_size: u32,
+) -> u64 {
+    // Grouped-query attention stores fewer keys and values than heads
+    let n_embd_kv = match n_head {
+        0 => n_embd as u64,
+        _ => n_embd as u64 / n_head as u64 * n_head_kv as u64,
+    };
+    let kv_bytes = 2 * n_layer as u64 * n_embd_kv * context_size as u64 * 2;
+    weight_bytes + kv_bytes + COMPUTE_BUFFER_BYTES
+}
+
+/// Romaji for lines read by `readings`, lines left unread keep their kanji
+fn romaji_of(lines: &[(&str, Option<String>)]) -> String {
+    let kana: String = lines
+        .iter()
+        .map(|(line, reading)| match reading {
+            Some(reading) => {
+                let content = line.trim_end_matches(['\r', '\n']);
+                format!("{}{}", reading, &line[content.len()..])
+            }
+            None => line.to_string(),
+        })
+        .collect();
+    romaji::to_romaji(&kana)
+}
+
+/// HTML of lines read by `readings`, with the kanji of each read line
+/// annotated
+fn furigana_of(lines: &[(&str, Option<String>)]) -> String {
+    let mut output = String::new();
+    for (line, reading) in lines {
+        let content = line.trim_end_matches(['\r', '\n']);
+        let annotated = reading
+            .as_ref()
+            .and_then(|reading| furigana::annotate(content, reading));
+        output.push_str(&annotated.unwrap_or_else(|| html::escape_text(content)));
+        output.push_str(&line[content.len()..]);
+    }
+    output
+}
+
+/// The context size for `needed` tokens: the next power of two from
+/// `MIN_CONTEXT_SIZE`, at most `max`
+fn context_bucket(needed: usize, max: u32) -> u32 {
+    let needed = u32::try_from(needed).unwrap_or(u32::MAX);
+    needed
+        .max(MIN_CONTEXT_SIZE)
+        .checked_next_power_of_two()
+        .unwrap_or(u32::MAX)
+        .min(max)
+}
+
+/// Fold the timings of one more generation into a running total
+fn add_timings(total: &mut Option<GenerationTimings>, timings: Option<GenerationTimings>) {
+    match (total.as_mut(), timings) {
+        (Some(total), Some(timings)) => total.add(&timings),
+        (None, timings) => *total = timings,
+        (Some(_), None) => {}
+    }
+}
+
+/// Length of the longest end of `buffer` that is the start of a stop
+/// sequence, and so can't be shown yet
+fn partial_stop_len(buffer: &str, stops: &[String]) -> usize {
+    stops
+        .iter()
+        .flat_map(|stop| {
+            stop.char_indices()
+                .skip(1)
+                .map(|(i, _)| &stop[..i])
+                .filter(|prefix| buffer.ends_with(prefix))
+                .map(str::len)
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Pass the part of `ready` after `emitted` to `on_text`, skipping leading
+/// whitespace before any output has been shown
+fn emit_new(ready: &str, emitted: &mut usize, on_text: &mut dyn FnMut(&str) -> bool) -> bool {
+    if *emitted == 0 {
+        *emitted = ready.len() - ready.trim_start().len();
+    }
+    if ready.len() <= *emitted {
+        return true;
+    }
+
+    let new = &ready[*emitted..];
+    *emitted = ready.len();
+    on_text(new)
+}
+
+/// The items sent to `receiver`, ending once every sender is dropped
+fn stream_from<T>(receiver: mpsc::Receiver<T>) -> impl Stream<Item = T> {
+    futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|item| (item, receiver))
+    })
+}
+
+/// Decode the next chunk of UTF-8 output, `last` flushes any incomplete
+/// character
+///
+/// Invalid byte sequences become U+FFFD rather than vanishing, and are
+/// logged, so malformed model output shows up as such.
+fn decode_bytes(decoder: &mut encoding_rs::Decoder, bytes: &[u8], last: bool) -> String {
+    let capacity = decoder
+        .max_utf8_buffer_length(bytes.len())
+        .unwrap_or(bytes.len() + 4);
+    let mut output = String::with_capacity(capacity);
+
+    let mut rest = bytes;
+    let mut replaced = false;
+    loop {
+        let (result, read, had_replacements) = decoder.decode_to_string(rest, &mut output, last);
+        replaced |= had_replacements;
+        rest = &rest[read..];
+        match result {
+            encoding_rs::CoderResult::InputEmpty => break,
+            encoding_rs::CoderResult::OutputFull => output.reserve(rest.len() + 4),
+        }
+    }
+
+    if replaced {
+        tracing::warn!(
+            bytes = bytes.len(),
+            last,
+            "Model output was not valid UTF-8, replaced with U+FFFD"
+        );
+    }
+    output
+}
+
+/// Fail with a clear error unless `path` can be read and starts like a
+/// GGUF file
+async fn check_gguf_header(path: &Path) -> Result<()> {
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Cannot read model file {}", path.display()))?;
+    let mut magic = [0; 4];
+    let read = file.read_exact(&mut magic).await;
+    if read.is_err() || &magic != b"GGUF" {
+        anyhow::bail!("{} is not a GGUF model file", path.display());
+    }
+    Ok(())
+}
+
+/// Create `dir` if needed and check files can be written to it, up front
+/// rather than at the end of a long download
+fn prepare_cache_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let probe = dir.join(".write-test");
+    std::fs::write(&probe, b"")?;
+    let _ = std::fs::remove_file(&probe);
+    Ok(())
+}
+
+/// Lowercase hex SHA-256 of the file at `path`
+async fn file_sha256(path: &Path) -> Result<String> {
+    use sha2::{Digest, Sha256};
+
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&path)
+            .with_context(|| format!("Cannot read model file {}", path.display()))?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher)
+            .with_context(|| format!("Cannot read model file {}", path.display()))?;
+        Ok(hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect())
+    })
+    .await
+    .context("Checksum task panicked")?
+}
+
+/// A HuggingFace API client, authenticated when a token is set
+fn hf_api() -> Result<Api> {
+    match hf_token() {
+        Some(token) => ApiBuilder::new().with_token(Some(token)).build(),
+        None => Api::new(),
+    }
+    .context("Failed to create HuggingFace API")
+}
+
+/// The HuggingFace token from the environment, if any
+fn hf_token() -> Option<String> {
+    HF_TOKEN_ENVS
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|token| !token.trim().is_empty())
+}
+
+/// Write `dest` through a `.part` file that is renamed into place only once
+/// `write` succeeds
+///
+/// On failure the partial file is removed, so an interrupted download never
+/// leaves a truncated model behind to fail at load time.
+async fn write_atomically<F, Fut>(dest: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(PathBuf) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let part_path = part_path(dest);
+
+    let result = match write(part_path.clone()).await {
+        Ok(()) => tokio::fs::rename(&part_path, dest)
+            .await
+            .context("Failed to move download into place"),
+        Err(e) => Err(e),
+    };
+
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&part_path).await;
+    }
+    result
+}
+
+/// The files of a model split with llama.cpp's `name-00001-of-00003.gguf`
+/// naming, found from the first one's name, or just `first` otherwise
+fn shard_files(first: &str) -> Vec<String> {
+    let split = first.strip_suffix(".gguf").and_then(|stem| {
+        let (stem, count) = stem.rsplit_once("-of-")?;
+        let (base, index) = stem.rsplit_once('-')?;
+        let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        if !is_number(index) || !is_number(count) || index.parse::<u32>().ok()? != 1 {
+            return None;
+        }
+        Some((base, count.len(), count.parse::<u32>().ok()?))
+    });
+
+    match split {
+        Some((base, width, count)) if count > 1 => (1..=count)
+            .map(|i| format!("{base}-{i:0width$}-of-{count:0width$}.gguf"))
+            .collect(),
+        _ => vec![first.to_string()],
+    }
+}
+
+/// Where `mirror` serves `file` of `repo`
+fn mirror_url(mirror: &str, repo: &str, file: &str) -> String {
+    format!("{}/{}/resolve/main/{}", mirror.trim_end_matches('/'), repo, file)
+}
+
+fn validate_download_timeouts(connect_secs: u64, total_secs: u64) -> Result<()> {
+    if connect_secs == 0 || total_secs == 0 {
+        anyhow::bail!("Download timeouts must be positive");
+    }
+    if connect_secs > total_secs {
+        anyhow::bail!("Connect timeout can't be longer than the total download timeout");
+    }
+    Ok(())
+}
+
+/// The temporary file `write_atomically` writes `dest` through
+fn part_path(dest: &Path) -> PathBuf {
+    let mut part_name = dest.file_name().unwrap_or_default().to_os_string();
+    part_name.push(".part");
+    dest.with_file_name(part_name)
+}
+
+/// Drop a leading echo of the system prompt or chat-template markers
+///
+/// The prompt match ignores case, whitespace and punctuation, so
+/// "translate to japanese:" still counts as an echo of "Translate to Japanese."
+fn strip_prompt_echo<'a>(output: &'a str, system_prompt: &str) -> &'a str {
+    let mut rest = output;
+    loop {
+        let trimmed = rest.trim_start();
+        let stripped = DEFAULT_STOP_SEQUENCES
+            .iter()
+            .find_map(|marker| trimmed.strip_prefix(marker))
+            .or_else(|| strip_role_line(trimmed))
+            .or_else(|| strip_loose_prefix(trimmed, system_prompt));
+        match stripped {
+            Some(next) => rest = next,
+            None => return trimmed,
+        }
+    }
+}
+
+/// Drop a label like "Translation:" from the start of `output`
+///
+/// Nothing is stripped when `input` starts with one of `prefixes` itself,
+/// since the translation may rightly start with a label then.
+fn strip_output_prefix<'a>(output: &'a str, input: &str, prefixes: &[String]) -> &'a str {
+    let starts_with = |text: &str, prefix: &str| {
+        !prefix.is_empty()
+            && text
+                .get(..prefix.len())
+                .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+    };
+
+    let input = input.trim_start();
+    if prefixes.iter().any(|prefix| starts_with(input, prefix)) {
+        return output;
+    }
+
+    let output = output.trim_start();
+    prefixes
+        .iter()
+        .find(|prefix| starts_with(output, prefix))
+        .map_or(output, |prefix| output[prefix.len()..].trim_start())
+}
+
+/// Strip a line holding nothing but a role name, like `assistant` or `User:`
+fn strip_role_line(text: &str) -> Option<&str> {
+    let (line, rest) = text.split_once('\n')?;
+    let role = line.trim().trim_end_matches(':').trim_end();
+    ["system", "user", "assistant"]
+        .iter()
+        .any(|name| role.eq_ignore_ascii_case(name))
+        .then_some(rest)
+}
+
+/// Strip `prefix` from the start of `text`, comparing only alphanumeric
+/// characters case-insensitively, along with the whitespace, full stop or
+/// colon right after it
+///
+/// That separator has to hold a line break or a colon, as an echoed prompt
+/// is set apart from the translation, and something has to follow it.
+/// Otherwise the text may just start with the same words, like a sentence
+/// that reads "Translate to English." itself.
+fn strip_loose_prefix<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    let mut expected = prefix.chars().filter(|c| c.is_alphanumeric()).peekable();
+    expected.peek()?;
+
+    let mut chars = text.char_indices();
+    while expected.peek().is_some() {
+        let (_, c) = chars.next()?;
+        if !c.is_alphanumeric() {
+            continue;
+        }
+        let want = expected.next()?;
+        if !c.to_lowercase().eq(want.to_lowercase()) {
+            return None;
+        }
+    }
+
+    let mut separated = false;
+    for (i, c) in chars {
+        if !c.is_whitespace() && !matches!(c, '.' | '。' | ':' | '：') {
+            return separated.then(|| &text[i..]);
+        }
+        separated |= matches!(c, '\n' | ':' | '：');
+    }
+    None
+}
+
+// TranslationService is Send and Sync without any unsafe code of its own:
+// llama-cpp-2 declares LlamaModel Send + Sync, LlamaBackend holds no data,
+// and every model access goes through the Mutex. LlamaContext is neither,
+// which is why contexts only ever live inside `generate` and are never
+// stored. This stops compiling if a field breaks that.
+const _: () = {
+    fn assert_send_sync<T: Send + Sync>() {}
+    let _ = assert_send_sync::<TranslationService>;
+};
+
+// Implementation notes:
+// 1. Using LlamaBackend::init() to initialize the backend once
+// 2. Loading model with LlamaModel::load_from_file()
+// 3. Creating a new context for each translation to ensure clean state
+// 4. Using greedy sampling for deterministic translations
 // 5. Processing tokens in batches using LlamaBatch
 // 6. Properly handling UTF-8 decoding for Japanese text
-// 7. Using Metal acceleration on macOS when available
\ No newline at end of file
+// 7. Using Metal acceleration on macOS when available
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An empty directory of its own under the temp dir
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("konnyaku-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // llama.cpp's backend can only be initialized once at a time
+    static BACKEND: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    // A service without a model, holding the backend until it's dropped
+    struct TestService {
+        service: Arc<TranslationService>,
+        _backend: tokio::sync::MutexGuard<'static, ()>,
+    }
+
+    impl std::ops::Deref for TestService {
+        type Target = Arc<TranslationService>;
+
+        fn deref(&self) -> &Arc<TranslationService> {
+            &self.service
+        }
+    }
+
+    // A service with the settings saved in `dir`, as the app starts it
+    async fn restarted_service(dir: &Path) -> TestService {
+        let backend = BACKEND.lock().await;
+        let store = SettingsStore::load_from(dir.join("settings.json")).unwrap();
+        let base = TranslationService::builder().cache_dir(dir.join("models"));
+        TestService {
+            service: Arc::new(TranslationService::with_settings(base, store).unwrap()),
+            _backend: backend,
+        }
+    }
+
+    async fn test_service(name: &str, builder: TranslationServiceBuilder) -> TestService {
+        let backend = BACKEND.lock().await;
+        let service = builder
+            .cache_dir(scratch_dir(name))
+            .offline(true)
+            .build()
+            .unwrap();
+        TestService {
+            service: Arc::new(service),
+            _backend: backend,
+        }
+    }
+
+    #[test]
+    fn prompt_echoes_are_stripped() {
+        let prompt = SYSTEM_PROMPT_EN_TO_JA;
+        assert_eq!(strip_prompt_echo("Translate to Japanese.\nこんにちは", prompt), "こんにちは");
+        assert_eq!(strip_prompt_echo("translate to japanese: こんにちは", prompt), "こんにちは");
+        assert_eq!(
+            strip_prompt_echo("<|im_start|>assistant\nTranslate to Japanese:\n「はい」", prompt),
+            "「はい」"
+        );
+        assert_eq!(strip_prompt_echo("  こんにちは", prompt), "こんにちは");
+    }
+
+    #[test]
+    fn output_that_only_starts_like_the_prompt_is_kept() {
+        let prompt = SYSTEM_PROMPT_JA_TO_EN;
+        assert_eq!(
+            strip_prompt_echo("Translate to English. Then send it.", prompt),
+            "Translate to English. Then send it."
+        );
+        assert_eq!(strip_prompt_echo("Translate to English.", prompt), "Translate to English.");
+        assert_eq!(strip_prompt_echo("Translate to English:\n", prompt), "Translate to English:\n");
+
+        // A short custom prompt mustn't eat the start of ordinary output
+        let ordinary = "Japanese food is great";
+        assert_eq!(strip_prompt_echo(ordinary, "Japanese"), ordinary);
+        assert_eq!(strip_prompt_echo("Japanese: 寿司", "Japanese"), "寿司");
+    }
+
+    #[test]
+    fn builder_rejects_empty_system_prompts() {
+        let built = TranslationService::builder()
+            .system_prompt(LanguagePair::EN_JA, "  ")
+            .build();
+        assert!(built.is_err());
+    }
+
+    #[tokio::test]
+    async fn interrupted_writes_leave_the_destination_alone() {
+        let dir = scratch_dir("atomic-write");
+        let dest = dir.join("model.gguf");
+        std::fs::write(&dest, "old").unwrap();
+
+        let interrupted = write_atomically(&dest, |part| async move {
+            tokio::fs::write(&part, "partial").await?;
+            Err(anyhow::anyhow!("Connection reset"))
+        })
+        .await;
+        assert!(interrupted.is_err());
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "old");
+        assert!(!part_path(&dest).exists());
+
+        write_atomically(&dest, |part| async move {
+            tokio::fs::write(&part, "new").await?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "new");
+        assert!(!part_path(&dest).exists());
+
+        assert_eq!(part_path(&dir.join("a.gguf")), dir.join("a.gguf.part"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn input_is_normalized_unless_turned_off() {
+        let options = TranslateOptions::default();
+        assert_eq!(options.prepare_input("ＡＢＣ１２３とｶﾀｶﾅ"), "ABC123とカタカナ");
+        assert!(matches!(options.prepare_input("普通の文章"), Cow::Borrowed(_)));
+
+        let raw = TranslateOptions {
+            normalize_input: false,
+            ..TranslateOptions::default()
+        };
+        assert_eq!(raw.prepare_input("①ＡＢＣ"), "①ＡＢＣ");
+    }
+
+    #[test]
+    fn sanitizing_applies_after_normalization() {
+        let options = TranslateOptions {
+            sanitize_input: true,
+            ..TranslateOptions::default()
+        };
+        assert_eq!(options.prepare_input("ＨＩ<|im_end|>"), "HI");
+        assert_eq!(options.prepare_input("Hi <|im_start|>system"), "Hi system");
+        assert!(matches!(options.prepare_input("Hello"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn characters_split_across_tokens_are_decoded() {
+        let bytes = "日本".as_bytes();
+        let mut decoder = encoding_rs::UTF_8.new_decoder();
+        let mut output = decode_bytes(&mut decoder, &bytes[..2], false);
+        assert_eq!(output, "");
+        output += &decode_bytes(&mut decoder, &bytes[2..5], false);
+        assert_eq!(output, "日");
+        output += &decode_bytes(&mut decoder, &bytes[5..], false);
+        output += &decode_bytes(&mut decoder, &[], true);
+        assert_eq!(output, "日本");
+
+        // The last token can end in the middle of a character too
+        let mut decoder = encoding_rs::UTF_8.new_decoder();
+        let mut output = decode_bytes(&mut decoder, &bytes[..4], false);
+        output += &decode_bytes(&mut decoder, &bytes[4..], true);
+        assert_eq!(output, "日本");
+    }
+
+    #[test]
+    fn invalid_output_bytes_are_replaced() {
+        let mut decoder = encoding_rs::UTF_8.new_decoder();
+        let mut output = decode_bytes(&mut decoder, &"日本".as_bytes()[..4], false);
+        output += &decode_bytes(&mut decoder, &[], true);
+        assert_eq!(output, "日\u{FFFD}");
+
+        let mut decoder = encoding_rs::UTF_8.new_decoder();
+        let mut output = decode_bytes(&mut decoder, b"a\xFFb", false);
+        output += &decode_bytes(&mut decoder, b"\xE6", false);
+        output += &decode_bytes(&mut decoder, b"c", true);
+        assert_eq!(output, "a\u{FFFD}b\u{FFFD}c");
+    }
+
+    #[tokio::test]
+    async fn empty_input_skips_the_model() {
+        let service = test_service("empty-input", TranslationService::builder()).await;
+        let options = TranslateOptions::default();
+        for text in ["", "  \n\t"] {
+            let translation = service
+                .translate(text, LanguagePair::EN_JA, &options)
+                .await
+                .unwrap();
+            assert_eq!(translation.text, "");
+            assert!(!translation.truncated);
+        }
+        assert!(!service.is_model_loaded().await);
+    }
+
+    #[test]
+    fn new_tokens_fit_in_the_context() {
+        let max_new_tokens = TranslationService::max_new_tokens;
+        assert_eq!(max_new_tokens(None, 1, 4096).unwrap(), MAX_TOKENS);
+        assert_eq!(max_new_tokens(Some(100), 4000, 4096).unwrap(), 96);
+        assert_eq!(max_new_tokens(None, 4095, 4096).unwrap(), 1);
+        assert!(max_new_tokens(None, 4096, 4096).is_err());
+        assert!(max_new_tokens(Some(0), 1, 4096).is_err());
+        assert!(max_new_tokens(Some(-5), 1, 4096).is_err());
+    }
+
+    #[test]
+    fn pairs_parse_from_their_codes() {
+        for pair in SUPPORTED_PAIRS {
+            assert_eq!(pair.to_string().parse::<LanguagePair>().unwrap(), pair);
+            assert_eq!(LanguagePair::new(pair.source, pair.target).unwrap(), pair);
+        }
+        assert_eq!(
+            " EN-JA ".parse::<LanguagePair>().unwrap(),
+            LanguagePair::EN_JA
+        );
+        assert_eq!(
+            "Ja-En".parse::<LanguagePair>().unwrap(),
+            LanguagePair::JA_EN
+        );
+
+        for invalid in ["", "en", "en-fr", "en_ja", "ja-ja", "auto"] {
+            assert!(matches!(
+                invalid.parse::<LanguagePair>(),
+                Err(TranslationError::InvalidDirection { direction }) if direction == invalid
+            ));
+        }
+    }
+
+    #[test]
+    fn pairs_serialize_as_codes_and_read_old_settings() {
+        let prompts = HashMap::from([(LanguagePair::JA_EN, "Into English.".to_string())]);
+        let json = serde_json::to_string(&prompts).unwrap();
+        assert_eq!(json, r#"{"ja-en":"Into English."}"#);
+
+        let saved = r#"{"EnglishToJapanese":"a","JapaneseToEnglish":"b"}"#;
+        let prompts: HashMap<LanguagePair, String> = serde_json::from_str(saved).unwrap();
+        assert_eq!(prompts[&LanguagePair::EN_JA], "a");
+        assert_eq!(prompts[&LanguagePair::JA_EN], "b");
+        assert!(serde_json::from_str::<LanguagePair>(r#""en-fr""#).is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_check_their_input_first() {
+        use futures_util::StreamExt;
+
+        let builder = TranslationService::builder().max_input_chars(5);
+        let service = test_service("stream-input", builder).await;
+        let direction = LanguagePair::EN_JA;
+        let options = TranslateOptions::default();
+
+        let too_long = service.translate_stream("Hello!", direction.clone(), &options);
+        assert!(matches!(
+            too_long.err().map(|e| e.downcast::<TranslationError>()),
+            Some(Ok(TranslationError::InputTooLong { chars: 6, limit: 5 }))
+        ));
+
+        let empty = service.translate_stream(" \n", direction, &options);
+        assert_eq!(empty.unwrap().count().await, 0);
+        assert!(!service.is_model_loaded().await);
+    }
+
+    #[test]
+    fn long_prompts_are_split_into_batches() {
+        let batches: Vec<_> = prompt_batches(1000, 512).collect();
+        assert_eq!(batches, vec![0..512, 512..1000]);
+        assert_eq!(prompt_batches(1024, 512).count(), 2);
+        assert_eq!(prompt_batches(3, 512).collect::<Vec<_>>(), vec![0..3]);
+        assert_eq!(prompt_batches(0, 512).count(), 0);
+
+        // Every position lands in exactly one batch, in order
+        let positions: Vec<_> = prompt_batches(1000, 7).flatten().collect();
+        assert_eq!(positions, (0..1000).collect::<Vec<_>>());
+        assert!(prompt_batches(1000, 7).all(|batch| batch.len() <= 7));
+        assert_eq!(prompt_batches(2, 0).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn runtime_settings_survive_a_restart() {
+        let dir = scratch_dir("restart");
+        let service = restarted_service(&dir).await;
+        service.set_idle_timeout(42);
+        service.set_flash_attention(true);
+        drop(service);
+
+        let service = restarted_service(&dir).await;
+        assert_eq!(service.config().idle_timeout_secs, 42);
+        assert!(service.config().flash_attention);
+    }
+
+    #[tokio::test]
+    async fn invalid_saved_settings_are_ignored_but_kept() {
+        let dir = scratch_dir("invalid-settings");
+        let saved = r#"{"num_threads": 0, "idle_timeout_secs": 42}"#;
+        std::fs::write(dir.join("settings.json"), saved).unwrap();
+
+        let config = restarted_service(&dir).await.config();
+        assert_eq!(config.idle_timeout_secs, DEFAULT_IDLE_TIMEOUT_SECS);
+
+        // Left for the user to fix
+        let file = std::fs::read_to_string(dir.join("settings.json")).unwrap();
+        assert_eq!(file, saved);
+    }
+
+    #[tokio::test]
+    async fn inconsistent_model_state_is_reset() {
+        let service = test_service("inconsistent-state", TranslationService::builder()).await;
+        let mut state = service.model_state.lock().await;
+        state.is_loaded = true;
+        assert!(!state.check_loaded());
+        assert!(!state.is_loaded);
+        assert!(!state.check_loaded());
+    }
+
+    #[tokio::test]
+    async fn a_failed_load_can_be_retried() {
+        let service = test_service("failed-load", TranslationService::builder()).await;
+        std::fs::write(&service.files().path, "not a model").unwrap();
+
+        for _ in 0..2 {
+            assert!(service.ensure_model_loaded().await.is_err());
+            assert!(matches!(service.model_phase(), ModelPhase::Failed { .. }));
+            assert!(!service.is_model_loaded().await);
+        }
+    }
+
+    #[tokio::test]
+    async fn default_presets_survive_a_restart() {
+        let dir = scratch_dir("restart-presets");
+        let service = restarted_service(&dir).await;
+        let direction = LanguagePair::JA_EN;
+        service.set_default_preset(direction.clone(), Some(SamplingPreset::Natural));
+        drop(service);
+
+        let service = restarted_service(&dir).await;
+        let presets = service.config().default_presets;
+        assert_eq!(presets.get(&direction), Some(&SamplingPreset::Natural));
+        assert_eq!(presets.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn output_prefixes_survive_a_restart() {
+        let dir = scratch_dir("restart-prefixes");
+        let service = restarted_service(&dir).await;
+        service.set_output_prefixes(vec!["Answer:".to_string(), "  訳：".to_string()]);
+        drop(service);
+
+        let config = restarted_service(&dir).await.config();
+        assert_eq!(config.output_prefixes, vec!["Answer:", "訳："]);
+    }
+
+    #[tokio::test]
+    async fn download_mirrors_survive_a_restart() {
+        let dir = scratch_dir("restart-mirrors");
+        let service = restarted_service(&dir).await;
+        let mirror = "https://mirror.example";
+        let mirrors = vec![format!("{}/", mirror), HF_BASE_URL.to_string()];
+        service.set_download_mirrors(mirrors).unwrap();
+        let ftp = vec!["ftp://mirror.example".to_string()];
+        assert!(service.set_download_mirrors(ftp).is_err());
+        drop(service);
+
+        let config = restarted_service(&dir).await.config();
+        assert_eq!(config.download_mirrors, vec![mirror, HF_BASE_URL]);
+    }
+
+    #[tokio::test]
+    async fn glossary_survives_import_restart_and_export() {
+        let dir = scratch_dir("restart-glossary");
+        let import_path = dir.join("terms.tsv");
+        std::fs::write(&import_path, "source\ttarget\n蒟蒻\tKonnyaku\nbroken\n").unwrap();
+        let service = restarted_service(&dir).await;
+        let import = service.import_glossary(&import_path).await.unwrap();
+        assert_eq!(import.added, 1);
+        assert_eq!(import.skipped.len(), 1);
+        drop(service);
+
+        let service = restarted_service(&dir).await;
+        let export_path = dir.join("terms.csv");
+        assert_eq!(service.export_glossary(&export_path).await.unwrap(), 1);
+        let exported = std::fs::read_to_string(&export_path).unwrap();
+        assert_eq!(exported, "source,target\n蒟蒻,Konnyaku\n");
+    }
+
+    #[tokio::test]
+    async fn clean_output_applies_the_glossary() {
+        let service = test_service("clean-glossary", TranslationService::builder()).await;
+        service.config.write().unwrap().glossary = vec![GlossaryEntry {
+            source: "蒟蒻".to_string(),
+            target: "Konnyaku".to_string(),
+        }];
+        let options = TranslateOptions::default();
+
+        let output = service.clean_output("I like 蒟蒻.", "蒟蒻が好き。", "", &options);
+        assert_eq!(output, "I like Konnyaku.");
+        let output = service.clean_output("No 蒟蒻 here.", "こんにちは。", "", &options);
+        assert_eq!(output, "No 蒟蒻 here.");
+    }
+
+    #[tokio::test]
+    async fn progress_translations_check_their_input_first() {
+        let builder = TranslationService::builder().max_input_chars(5);
+        let service = test_service("progress-input", builder).await;
+        let direction = LanguagePair::JA_EN;
+        let options = TranslateOptions::default();
+        let no_progress = |_: &str| panic!("nothing should be generated");
+
+        let too_long = service
+            .translate_with_progress("こんにちは。", direction.clone(), &options, no_progress)
+            .await;
+        assert!(matches!(
+            too_long.err().map(|e| e.downcast::<TranslationError>()),
+            Some(Ok(TranslationError::InputTooLong { chars: 6, limit: 5 }))
+        ));
+
+        let empty = service
+            .translate_with_progress("\n\n", direction, &options, no_progress)
+            .await
+            .unwrap();
+        assert_eq!(empty.text, "");
+        assert!(!service.is_model_loaded().await);
+    }
+
+    #[test]
+    fn context_bucket_rounds_up_within_bounds() {
+        assert_eq!(context_bucket(20, 4096), MIN_CONTEXT_SIZE);
+        assert_eq!(context_bucket(513, 4096), 1024);
+        assert_eq!(context_bucket(3000, 2048), 2048);
+        assert_eq!(context_bucket(usize::MAX, 4096), 4096);
+    }
+
+    // A path that can't be created even by root, as a file is in the way
+    fn blocked_dir(name: &str) -> PathBuf {
+        let file = scratch_dir(name).join("file");
+        std::fs::write(&file, b"").unwrap();
+        file.join("models")
+    }
+
+    #[test]
+    fn writable_cache_dir_creates_the_directory() {
+        let dir = scratch_dir("cache-writable").join("models");
+        let found = TranslationService::writable_cache_dir(dir.clone(), None).unwrap();
+        assert_eq!(found, dir);
+        assert!(dir.is_dir());
+        assert!(!dir.join(".write-test").exists());
+    }
+
+    #[test]
+    fn writable_cache_dir_falls_back_when_unwritable() {
+        let dir = blocked_dir("cache-blocked");
+        let fallback = scratch_dir("cache-fallback").join("models");
+        let found = TranslationService::writable_cache_dir(dir, Some(fallback.clone())).unwrap();
+        assert_eq!(found, fallback);
+        assert!(fallback.is_dir());
+    }
+
+    #[test]
+    fn writable_cache_dir_reports_the_preferred_directory() {
+        let dir = blocked_dir("cache-blocked-both");
+        let fallback = blocked_dir("cache-blocked-fallback");
+        for fallback in [Some(fallback), None] {
+            let error = TranslationService::writable_cache_dir(dir.clone(), fallback).unwrap_err();
+            match error.downcast::<TranslationError>() {
+                Ok(TranslationError::CacheDirUnwritable { path, .. }) => {
+                    assert_eq!(path, dir.display().to_string())
+                }
+                other => panic!("expected CacheDirUnwritable, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn damaged_models_loaded_from_the_cache_dir_fail_without_hanging() {
+        let service = test_service("damaged-local", TranslationService::builder()).await;
+        {
+            // Let the failed load try a repair, which can't reach the mirror
+            let mut config = service.config.write().unwrap();
+            config.offline = false;
+            config.download_mirrors = vec!["http://127.0.0.1:9".to_string()];
+        }
+        let path = service.cache_dir.join("damaged-q4_k_m.gguf");
+        std::fs::write(&path, b"GGUF, but not much else").unwrap();
+
+        let load = service.load_model_from_path(&path);
+        let result = tokio::time::timeout(Duration::from_secs(30), load).await;
+        let error = result.expect("loading hung").unwrap_err();
+        assert!(error.downcast_ref::<LlamaModelLoadError>().is_some());
+        assert!(!service.is_model_loaded().await);
+    }
+
+    #[tokio::test]
+    async fn session_turns_after_clear_lora_evaluate_the_prompt_again() {
+        let service = test_service("clear-lora-session", TranslationService::builder()).await;
+        let session = service.create_session().unwrap();
+        let saved = CachedPrompt {
+            prompt: "Translate to English.\nこんにちは".to_string(),
+            tokens: vec![LlamaToken(1), LlamaToken(2)],
+            context_size: 512,
+            flash_attention: false,
+            state: vec![0; 8],
+        };
+        let mut state = service.model_state.lock().await;
+        state.prompts.insert(session, saved);
+        drop(state);
+
+        service.clear_lora().await;
+        let state = service.model_state.lock().await;
+        assert!(!state.prompts.contains_key(&session));
+    }
+
+    #[test]
+    fn romaji_reads_kanji_through_their_reading() {
+        let lines = [
+            ("毎日走る\n", Some("まいにちはしる".to_string())),
+            ("雨", None),
+        ];
+        assert_eq!(romaji_of(&lines), "mainichihashiru\n雨");
+        assert_eq!(
+            furigana_of(&lines),
+            "<ruby>毎日走<rt>まいにちはし</rt></ruby>る\n雨"
+        );
+    }
+
+    #[tokio::test]
+    async fn cached_models_are_not_deleted_during_a_download() {
+        let service = test_service("delete-model", TranslationService::builder()).await;
+        let path = service.files().path;
+        std::fs::write(&path, b"GGUF").unwrap();
+
+        // As held by a download, the bundled model install and repairs
+        let download = service.download_lock.lock().await;
+        assert!(service.delete_cached_model().await.is_err());
+        assert!(path.exists());
+        drop(download);
+
+        service.delete_cached_model().await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn claimed_downloads_count_as_in_progress() {
+        let service = test_service("claim-download", TranslationService::builder()).await;
+        let claim = service.claim_download().unwrap();
+        assert!(service.claim_download().is_none());
+        assert!(service.download_progress().in_progress);
+        assert!(service.cancel_download());
+        drop(claim);
+        assert!(!service.download_progress().in_progress);
+        assert!(!service.cancel_download());
+    }
+
+    #[tokio::test]
+    async fn bundled_models_install_offline() {
+        let service = test_service("bundled-install", TranslationService::builder()).await;
+        let bundled = scratch_dir("bundled-source").join("model.gguf");
+        std::fs::write(&bundled, b"GGUF").unwrap();
+        service.set_bundled_model(bundled);
+
+        service.ensure_model_downloaded().await.unwrap();
+        assert!(service.is_model_downloaded());
+        assert!(!service.download_progress().in_progress);
+    }
+}
@@ -0,0 +1,149 @@
+// The translation operations the commands need, behind a trait so they can
+// run against something other than a real model.
+
+use crate::quantization::Quantization;
+use crate::translation::{
+    BackendInfo, LanguagePair, ModelPhase, TranslateOptions, Translation, TranslationService,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// A source of translations
+///
+/// Implemented by `TranslationService`. Tests can substitute a stand-in that
+/// returns canned output, so command handlers run without downloading or
+/// loading a model.
+#[async_trait]
+pub trait Translator: Send + Sync {
+    async fn translate(
+        &self,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<Translation>;
+
+    /// Count the prompt tokens a translation of `text` would consume
+    async fn count_tokens(&self, text: &str, direction: LanguagePair) -> Result<usize>;
+
+    async fn is_model_loaded(&self) -> bool;
+
+    async fn ensure_model_downloaded(&self) -> Result<()>;
+
+    async fn ensure_model_loaded(&self) -> Result<()>;
+
+    async fn backend_info(&self) -> BackendInfo;
+
+    fn model_phase(&self) -> ModelPhase;
+
+    fn quantization(&self) -> Option<Quantization>;
+}
+
+#[async_trait]
+impl Translator for TranslationService {
+    async fn translate(
+        &self,
+        text: &str,
+        direction: LanguagePair,
+        options: &TranslateOptions,
+    ) -> Result<Translation> {
+        TranslationService::translate(self, text, direction, options).await
+    }
+
+    async fn count_tokens(&self, text: &str, direction: LanguagePair) -> Result<usize> {
+        TranslationService::count_tokens(self, text, direction).await
+    }
+
+    async fn is_model_loaded(&self) -> bool {
+        TranslationService::is_model_loaded(self).await
+    }
+
+    async fn ensure_model_downloaded(&self) -> Result<()> {
+        TranslationService::ensure_model_downloaded(self).await
+    }
+
+    async fn ensure_model_loaded(&self) -> Result<()> {
+        TranslationService::ensure_model_loaded(self).await
+    }
+
+    async fn backend_info(&self) -> BackendInfo {
+        TranslationService::backend_info(self).await
+    }
+
+    fn model_phase(&self) -> ModelPhase {
+        TranslationService::model_phase(self)
+    }
+
+    fn quantization(&self) -> Option<Quantization> {
+        TranslationService::quantization(self)
+    }
+}
+
+/// A stand-in that answers every translation with `respond`, for tests
+#[cfg(test)]
+pub struct MockTranslator {
+    pub respond: fn(&str) -> Result<Translation>,
+}
+
+#[cfg(test)]
+impl MockTranslator {
+    /// A successful translation to `text`
+    pub fn translation(text: &str) -> Translation {
+        Translation {
+            text: text.to_string(),
+            truncated: false,
+            alternatives: Vec::new(),
+            confidence: None,
+            romaji: None,
+            furigana: None,
+            timings: None,
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Translator for MockTranslator {
+    async fn translate(
+        &self,
+        text: &str,
+        _direction: LanguagePair,
+        _options: &TranslateOptions,
+    ) -> Result<Translation> {
+        (self.respond)(text)
+    }
+
+    async fn count_tokens(&self, text: &str, _direction: LanguagePair) -> Result<usize> {
+        Ok(text.split_whitespace().count())
+    }
+
+    async fn is_model_loaded(&self) -> bool {
+        true
+    }
+
+    async fn ensure_model_downloaded(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn ensure_model_loaded(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn backend_info(&self) -> BackendInfo {
+        BackendInfo {
+            backend: "CPU".to_string(),
+            gpu_support: false,
+            gpu_layers: None,
+            cpu_fallback: false,
+            num_threads: 1,
+            context_size: 4096,
+        }
+    }
+
+    fn model_phase(&self) -> ModelPhase {
+        ModelPhase::Ready
+    }
+
+    fn quantization(&self) -> Option<Quantization> {
+        None
+    }
+}
@@ -0,0 +1,169 @@
+//! End-to-end tests against a real model: download, load, tokenize, generate
+//!
+//! Skipped unless `KONNYAKU_MODEL_TESTS` is set, since the first run downloads
+//! the model (~230MB) into the usual cache dir. Later runs reuse it.
+//! `KONNYAKU_TEST_MODEL_REPO` and `KONNYAKU_TEST_MODEL_FILE` point the tests at
+//! a different GGUF.
+//!
+//! ```sh
+//! KONNYAKU_MODEL_TESTS=1 cargo test --test model
+//! ```
+
+use konnyaku_lib::translation::{LanguagePair, TranslateOptions, TranslationService};
+
+const ENABLE_ENV: &str = "KONNYAKU_MODEL_TESTS";
+const REPO_ENV: &str = "KONNYAKU_TEST_MODEL_REPO";
+const FILE_ENV: &str = "KONNYAKU_TEST_MODEL_FILE";
+
+// Tests run in parallel, only one of them should download the model
+static DOWNLOAD: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// A service with the model on disk, or None if the tests are disabled or the
+/// model can't be fetched
+async fn service() -> Option<TranslationService> {
+    if std::env::var_os(ENABLE_ENV).is_none() {
+        eprintln!("{} is not set, skipping", ENABLE_ENV);
+        return None;
+    }
+
+    let mut builder = TranslationService::builder();
+    if let Ok(repo) = std::env::var(REPO_ENV) {
+        builder = builder.model_repo(repo);
+    }
+    if let Ok(file) = std::env::var(FILE_ENV) {
+        builder = builder.model_file(file);
+    }
+    let service = builder.build().expect("Failed to build translation service");
+
+    let _download = DOWNLOAD.lock().await;
+    if let Err(e) = service.ensure_model_downloaded().await {
+        eprintln!("Model could not be downloaded, skipping: {:#}", e);
+        return None;
+    }
+    Some(service)
+}
+
+#[tokio::test]
+async fn translates_both_directions() {
+    let Some(service) = service().await else {
+        return;
+    };
+    let options = TranslateOptions::default();
+
+    let japanese = service
+        .translate("Good morning.", LanguagePair::EN_JA, &options)
+        .await
+        .expect("English to Japanese failed");
+    assert!(!japanese.text.trim().is_empty());
+
+    let english = service
+        .translate("おはようございます。", LanguagePair::JA_EN, &options)
+        .await
+        .expect("Japanese to English failed");
+    assert!(!english.text.trim().is_empty());
+}
+
+#[tokio::test]
+async fn token_counts_are_consistent() {
+    let Some(service) = service().await else {
+        return;
+    };
+    let direction = LanguagePair::EN_JA;
+
+    let short = service.count_tokens("Hello.", direction.clone()).await.unwrap();
+    let again = service.count_tokens("Hello.", direction.clone()).await.unwrap();
+    let long = service
+        .count_tokens("Hello. It is a fine day for a walk in the park.", direction)
+        .await
+        .unwrap();
+
+    assert!(short > 0);
+    assert_eq!(short, again);
+    assert!(long > short);
+}
+
+#[tokio::test]
+async fn dry_run_matches_token_count() {
+    let Some(service) = service().await else {
+        return;
+    };
+    let direction = LanguagePair::JA_EN;
+
+    let dry_run = service
+        .translate_dry_run("こんにちは。", direction.clone(), &TranslateOptions::default())
+        .await
+        .unwrap();
+    let tokens = service.count_tokens("こんにちは。", direction).await.unwrap();
+
+    assert!(dry_run.prompt.contains("こんにちは。"));
+    assert_eq!(dry_run.prompt_tokens, tokens);
+    assert_eq!(dry_run.chunks, 1);
+}
+
+#[tokio::test]
+async fn speculation_keeps_greedy_output() {
+    let Some(service) = service().await else {
+        return;
+    };
+    let direction = LanguagePair::EN_JA;
+    let options = TranslateOptions::default();
+    let text = "The train to Kyoto leaves at nine in the morning.";
+
+    let plain = service.translate(text, direction.clone(), &options).await.unwrap();
+    // The model shares its vocabulary with itself, but recurrent models such
+    // as the default LFM2 can't speculate at all
+    let repo = service.model_repo().to_string();
+    if let Err(e) = service.set_draft_model(&repo, &service.model_file()).await {
+        eprintln!("Model can't be its own draft, skipping: {:#}", e);
+        return;
+    }
+    service.clear_cache();
+    let speculative = service.translate(text, direction, &options).await.unwrap();
+    service.clear_draft_model().await;
+
+    assert_eq!(speculative.text, plain.text);
+}
+
+#[tokio::test]
+async fn benchmark_compares_decoding_with_and_without_the_draft() {
+    let Some(service) = service().await else {
+        return;
+    };
+    let repo = service.model_repo().to_string();
+    if let Err(e) = service.set_draft_model(&repo, &service.model_file()).await {
+        eprintln!("Model can't be its own draft, skipping: {:#}", e);
+        return;
+    }
+    let benchmark = service.benchmark(3).await.unwrap();
+    service.clear_draft_model().await;
+
+    let without_draft = benchmark.mean_tokens_per_sec_without_draft.unwrap();
+    let acceptance = benchmark.draft_acceptance.unwrap();
+    eprintln!(
+        "{:.1} tokens/s with the draft, {:.1} without, {:.0}% of guesses kept",
+        benchmark.mean_tokens_per_sec,
+        without_draft,
+        acceptance * 100.0
+    );
+    assert!((0.0..=1.0).contains(&acceptance));
+}
+
+#[tokio::test]
+async fn beam_search_returns_distinct_candidates() {
+    let Some(service) = service().await else {
+        return;
+    };
+    let mut options = TranslateOptions::default();
+    options.beam_width = 3;
+    options.n_alternatives = 3;
+    let translation = service
+        .translate("Good morning.", LanguagePair::EN_JA, &options)
+        .await
+        .unwrap();
+
+    assert!(!translation.text.is_empty());
+    assert!(translation.alternatives.len() <= 3);
+    if let Some(first) = translation.alternatives.first() {
+        assert_eq!(first, &translation.text);
+    }
+}